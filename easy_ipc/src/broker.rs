@@ -0,0 +1,427 @@
+use {
+    crate::{
+        client::Client,
+        connection::Connection,
+        error::ConnectionError,
+        sender::{Overflow, Sender},
+        server::Server,
+    },
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::{BTreeMap, VecDeque},
+        io,
+        sync::{Arc, Mutex, MutexGuard, PoisonError},
+        thread,
+    },
+};
+
+/// What a [`BrokerClient`] asks of the [`Broker`], the client message of a broker model
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum BrokerRequest<P> {
+    /// Register under a name, answered with [`BrokerEvent::Registered`] or
+    /// [`BrokerEvent::NameTaken`]
+    Register(String),
+    /// Send a message to the peer registered under `to`
+    Send {
+        /// Name of the peer
+        to: String,
+        /// The message
+        message: P,
+    },
+}
+
+/// What the [`Broker`] tells a [`BrokerClient`], the server message of a broker model
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum BrokerEvent<P> {
+    /// The name was registered, contains the names of the other peers online
+    Registered(Vec<String>),
+    /// Another peer is registered under the name
+    NameTaken,
+    /// A message from another peer
+    Message {
+        /// Name of the peer that sent it
+        from: String,
+        /// The message
+        message: P,
+    },
+    /// A peer registered
+    Joined(String),
+    /// A peer disconnected
+    Left(String),
+    /// A message sent by this peer was thrown away, because its receiver is offline and already
+    /// has as many messages waiting as [`Broker::offline_limit`] allows, because as many offline
+    /// peers as [`Broker::offline_peers`] allows have messages waiting, or because its receiver
+    /// isn't reading and its queue is full.
+    Undeliverable {
+        /// Name of the peer the message was for
+        to: String,
+    },
+}
+
+/// Routes messages between processes that register with it under a unique name.
+///
+/// A [`Server`] only lets clients talk to the server, with a broker in between they can talk to
+/// each other. Every client registers with [`BrokerClient::register`] and then sends messages to
+/// other peers by name. Peers are told when others join or leave. Messages to a peer that isn't
+/// online are kept until it registers, up to [`Broker::offline_limit`] per peer and for up to
+/// [`Broker::offline_peers`] peers.
+///
+/// The broker serves every peer on a thread of its own. Messages are passed on from a queue of
+/// every peer, see [`Broker::queue_capacity`], so a peer that doesn't read holds up nobody else.
+///
+/// ```no_run
+/// use easy_ipc::{
+///     broker::{Broker, BrokerClient, BrokerEvent, BrokerRequest},
+///     prelude::*,
+/// };
+///
+/// struct Plugins;
+///
+/// impl IpcModel for Plugins {
+///     type ClientMsg = BrokerRequest<String>;
+///     type ServerMsg = BrokerEvent<String>;
+///
+///     fn model() -> Result<ClientServerModel<Self::ClientMsg, Self::ServerMsg>, InitError> {
+///         Ok(ClientServerOptions::new("plugins.sock").create())
+///     }
+/// }
+///
+/// // The hub
+/// # fn hub() {
+/// Broker::new(Plugins::server().unwrap()).run().unwrap();
+/// # }
+///
+/// // A plugin
+/// # fn plugin() {
+/// let mut indexer = BrokerClient::register(Plugins::client().unwrap(), "indexer").unwrap();
+/// indexer.send("search", "reindex".to_string()).unwrap();
+/// while let Ok(event) = indexer.receive() {
+///     if let BrokerEvent::Message { from, message } = event {
+///         println!("{from}: {message}");
+///     }
+/// }
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Broker<P>
+where
+    P: Serialize + for<'de> Deserialize<'de>,
+{
+    server: Server<BrokerEvent<P>, BrokerRequest<P>>,
+    peers: Peers<P>,
+}
+
+/// The peers of a broker, shared with the threads serving them
+#[derive(Debug)]
+struct Peers<P>
+where
+    P: Serialize,
+{
+    state: Arc<Mutex<PeersState<P>>>,
+    offline_limit: usize,
+    offline_names: usize,
+    queue_capacity: usize,
+}
+
+impl<P> Clone for Peers<P>
+where
+    P: Serialize,
+{
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+            offline_limit: self.offline_limit,
+            offline_names: self.offline_names,
+            queue_capacity: self.queue_capacity,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PeersState<P>
+where
+    P: Serialize,
+{
+    online: BTreeMap<String, Sender<BrokerEvent<P>>>,
+    /// Messages for peers that aren't online, with the name of the sender
+    offline: BTreeMap<String, VecDeque<(String, P)>>,
+}
+
+impl<P> Broker<P>
+where
+    P: Serialize + for<'de> Deserialize<'de> + Send + 'static,
+{
+    /// Route messages between the clients of `server`.
+    ///
+    /// By default 64 messages are kept for each of up to 1024 peers that are offline, and the
+    /// queue of every peer holds 1024 messages.
+    pub fn new(server: Server<BrokerEvent<P>, BrokerRequest<P>>) -> Self {
+        Self {
+            server,
+            peers: Peers {
+                state: Arc::new(Mutex::new(PeersState {
+                    online: BTreeMap::new(),
+                    offline: BTreeMap::new(),
+                })),
+                offline_limit: 64,
+                offline_names: 1024,
+                queue_capacity: 1024,
+            },
+        }
+    }
+
+    /// Keep up to `messages` messages for every peer that is offline, `0` throws them away.
+    ///
+    /// Once a peer has that many waiting, further messages to it are thrown away and their
+    /// senders get [`BrokerEvent::Undeliverable`].
+    #[must_use]
+    pub const fn offline_limit(mut self, messages: usize) -> Self {
+        self.peers.offline_limit = messages;
+        self
+    }
+
+    /// Keep messages for up to `peers` peers that are offline, any name can be sent to so this
+    /// bounds what the broker holds on to.
+    ///
+    /// Once that many have messages waiting, messages to other offline peers are thrown away and
+    /// their senders get [`BrokerEvent::Undeliverable`].
+    #[must_use]
+    pub const fn offline_peers(mut self, peers: usize) -> Self {
+        self.peers.offline_names = peers;
+        self
+    }
+
+    /// Number of messages that can be waiting to be written to a peer, once its queue is full
+    /// further messages to it are thrown away and their senders get
+    /// [`BrokerEvent::Undeliverable`].
+    #[must_use]
+    pub const fn queue_capacity(mut self, messages: usize) -> Self {
+        self.peers.queue_capacity = messages;
+        self
+    }
+
+    /// Serve peers until accepting them or starting a thread for one fails.
+    ///
+    /// Clients that fail to set up are skipped, the server counts them as rejected.
+    ///
+    /// # Errors
+    ///
+    /// Fails if accepting on the listening socket fails, like when the process runs out of file
+    /// descriptors, or if a thread can't be started.
+    pub fn run(&self) -> Result<(), io::Error> {
+        loop {
+            let Some(connection) = self.server.accept()? else {
+                continue;
+            };
+            let peers = self.peers.clone();
+            thread::Builder::new()
+                .name("easy_ipc broker peer".to_string())
+                .spawn(move || peers.serve(connection))?;
+        }
+    }
+
+    /// Names of the peers that are online
+    #[must_use]
+    pub fn peers(&self) -> Vec<String> {
+        self.peers.lock().online.keys().cloned().collect()
+    }
+
+    /// The server the peers connect to, to look at its metrics
+    pub const fn server(&self) -> &Server<BrokerEvent<P>, BrokerRequest<P>> {
+        &self.server
+    }
+}
+
+impl<P> Peers<P>
+where
+    P: Serialize + for<'de> Deserialize<'de>,
+{
+    fn lock(&self) -> MutexGuard<'_, PeersState<P>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Register the peer on `connection` and route its messages until it disconnects
+    fn serve(&self, mut connection: Connection<BrokerEvent<P>, BrokerRequest<P>>) {
+        let Some(name) = self.register(&mut connection) else {
+            return;
+        };
+        while let Ok(request) = connection.receive() {
+            match request {
+                BrokerRequest::Send { to, message } => self.route(&name, to, message),
+                // Registering twice makes no sense, the client doesn't do it
+                BrokerRequest::Register(_) => break,
+            }
+        }
+        let mut state = self.lock();
+        state.online.remove(&name);
+        for sender in state.online.values() {
+            sender.send(BrokerEvent::Left(name.clone())).ok();
+        }
+    }
+
+    /// Wait for the peer to register under a name that is free, `None` if it disconnects first
+    fn register(
+        &self,
+        connection: &mut Connection<BrokerEvent<P>, BrokerRequest<P>>,
+    ) -> Option<String> {
+        loop {
+            let BrokerRequest::Register(name) = connection.receive().ok()? else {
+                return None;
+            };
+            let mut state = self.lock();
+            if state.online.contains_key(&name) {
+                drop(state);
+                connection.send(BrokerEvent::NameTaken).ok()?;
+                continue;
+            }
+            let sender = connection
+                .buffered_sender(self.queue_capacity, Overflow::Error)
+                .ok()?;
+            let others = state.online.keys().cloned().collect();
+            sender.send(BrokerEvent::Registered(others)).ok()?;
+            for (from, message) in state.offline.remove(&name).unwrap_or_default() {
+                deliver(&state, &sender, &name, &from, message);
+            }
+            for other in state.online.values() {
+                other.send(BrokerEvent::Joined(name.clone())).ok();
+            }
+            state.online.insert(name.clone(), sender);
+            drop(state);
+            return Some(name);
+        }
+    }
+
+    /// Pass a message on to `to`, or keep it until `to` registers
+    fn route(&self, from: &str, to: String, message: P) {
+        let mut state = self.lock();
+        if let Some(sender) = state.online.get(&to) {
+            deliver(&state, sender, &to, from, message);
+            return;
+        }
+        // A name that has nothing waiting yet needs room for one more name
+        let room = state.offline.get(&to).map_or_else(
+            || self.offline_limit > 0 && state.offline.len() < self.offline_names,
+            |waiting| waiting.len() < self.offline_limit,
+        );
+        if room {
+            let waiting = state.offline.entry(to).or_default();
+            waiting.push_back((from.to_string(), message));
+            return;
+        }
+        undeliverable(&state, from, to);
+        drop(state);
+    }
+}
+
+/// Write a message to an online peer, telling the sender if its queue is full
+fn deliver<P>(
+    state: &PeersState<P>,
+    sender: &Sender<BrokerEvent<P>>,
+    to: &str,
+    from: &str,
+    message: P,
+) where
+    P: Serialize,
+{
+    let event = BrokerEvent::Message {
+        from: from.to_string(),
+        message,
+    };
+    if matches!(sender.send(event), Err(ConnectionError::QueueFull)) {
+        undeliverable(state, from, to.to_string());
+    }
+}
+
+/// Tell `from` that its message to `to` was thrown away, if it is still online
+fn undeliverable<P>(state: &PeersState<P>, from: &str, to: String)
+where
+    P: Serialize,
+{
+    if let Some(sender) = state.online.get(from) {
+        sender.send(BrokerEvent::Undeliverable { to }).ok();
+    }
+}
+
+/// A process registered with a [`Broker`] under a name, see [`BrokerClient::register`]
+#[derive(Debug)]
+pub struct BrokerClient<P>
+where
+    P: Serialize + for<'de> Deserialize<'de>,
+{
+    client: Client<BrokerRequest<P>, BrokerEvent<P>>,
+    name: String,
+    /// Kept up to date as join and leave events are received
+    peers: Vec<String>,
+}
+
+impl<P> BrokerClient<P>
+where
+    P: Serialize + for<'de> Deserialize<'de>,
+{
+    /// Register with the broker `client` is connected to under `name`.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`ConnectionError::NameTaken`] if another peer has that name, otherwise like
+    /// [`Client::send`] and [`Client::receive`].
+    pub fn register(
+        mut client: Client<BrokerRequest<P>, BrokerEvent<P>>,
+        name: impl Into<String>,
+    ) -> Result<Self, ConnectionError> {
+        let name = name.into();
+        client.send(BrokerRequest::Register(name.clone()))?;
+        match client.receive()? {
+            BrokerEvent::Registered(peers) => Ok(Self {
+                client,
+                name,
+                peers,
+            }),
+            BrokerEvent::NameTaken => Err(ConnectionError::NameTaken(name)),
+            _ => Err(ConnectionError::UnexpectedFrame),
+        }
+    }
+
+    /// Send a message to the peer registered under `to`.
+    ///
+    /// If it is offline the broker keeps the message until it registers. If the message is
+    /// thrown away instead, [`BrokerEvent::Undeliverable`] is received later on.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`Client::send`], an undeliverable message isn't an error.
+    pub fn send(&mut self, to: &str, message: P) -> Result<(), ConnectionError> {
+        self.client.send(BrokerRequest::Send {
+            to: to.to_string(),
+            message,
+        })
+    }
+
+    /// Receive the next message from another peer, or news about the peers
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`Client::receive`].
+    pub fn receive(&mut self) -> Result<BrokerEvent<P>, ConnectionError> {
+        let event = self.client.receive()?;
+        match &event {
+            BrokerEvent::Joined(name) => self.peers.push(name.clone()),
+            BrokerEvent::Left(name) => self.peers.retain(|peer| peer != name),
+            _ => (),
+        }
+        Ok(event)
+    }
+
+    /// The name we are registered under
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Names of the other peers online, as far as the events received so far tell
+    #[must_use]
+    pub fn peers(&self) -> &[String] {
+        &self.peers
+    }
+}
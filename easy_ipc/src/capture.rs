@@ -0,0 +1,460 @@
+use {
+    crate::{
+        client::Client,
+        connection::{Connection, Frame, FrameKind, RawConnection, Side},
+        error::ConnectionError,
+        trace,
+    },
+    serde::{Deserialize, Serialize},
+    std::{
+        fs::{File, OpenOptions},
+        io::{self, ErrorKind, Read, Seek, Write},
+        path::{Path, PathBuf},
+        sync::{
+            Arc, Mutex, PoisonError,
+            atomic::{AtomicU32, Ordering},
+        },
+        time::{Duration, SystemTime},
+    },
+};
+
+/// Start of every capture file, followed by the records
+const FILE_MAGIC: &[u8] = b"easy_ipc capture 1\n";
+/// Bytes of a record before the payload: time, connection, direction, kind, channel and length
+const RECORD_HEADER_LEN: usize = 8 + 8 + 1 + 1 + 4 + 8;
+
+/// Connections captured by this process so far, part of the connection ID
+static NEXT_CONNECTION: AtomicU32 = AtomicU32::new(0);
+
+/// Which way a captured frame went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Sent by the client
+    ToServer,
+    /// Sent by the server
+    ToClient,
+}
+
+/// A frame read from a capture file, see [`Recording`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedFrame {
+    /// When the frame was sent or received by the process that captured it
+    pub time: SystemTime,
+    /// The connection the frame went over, unique across processes writing to the same file
+    pub connection: u64,
+    /// Which way the frame went
+    pub direction: Direction,
+    kind: FrameKind,
+    channel: u32,
+    data: Vec<u8>,
+}
+
+impl CapturedFrame {
+    /// Decode the message carried by the frame, `None` for frames without a message on the main
+    /// channel, like the end of a stream or a cancellation, and for archived messages.
+    pub fn message<M>(&self) -> Option<Result<M, ConnectionError>>
+    where
+        M: for<'de> Deserialize<'de>,
+    {
+        let data = match self.kind {
+            FrameKind::Message | FrameKind::StreamItem => &self.data[..],
+            FrameKind::DeadlineMessage => self.data.get(8..)?,
+            _ => return None,
+        };
+        Some(bitcode::deserialize(data).map_err(ConnectionError::DeserilizationFailed))
+    }
+
+    /// Kind of the frame, like `message` or `stream end`
+    #[must_use]
+    pub const fn kind(&self) -> &'static str {
+        self.kind.name()
+    }
+
+    /// Channel the frame was sent on, 0 unless it was sent over a [`crate::mux`] channel
+    #[must_use]
+    pub const fn channel(&self) -> u32 {
+        self.channel
+    }
+
+    /// Payload of the frame
+    #[must_use]
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn new(connection: u64, direction: Direction, frame: Frame) -> Self {
+        Self {
+            time: SystemTime::now(),
+            connection,
+            direction,
+            kind: frame.kind,
+            channel: frame.channel,
+            data: frame.data,
+        }
+    }
+
+    /// Same frame, ignoring when and on which connection it was seen
+    fn same_frame(&self, other: &Self) -> bool {
+        self.direction == other.direction
+            && self.kind == other.kind
+            && self.channel == other.channel
+            && self.data == other.data
+    }
+}
+
+/// A frame of the replay that didn't match the capture, see [`Recording::replay_client`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    /// Position of the frame among the frames of the replayed connection
+    pub index: usize,
+    /// The frame that was captured
+    pub expected: CapturedFrame,
+    /// The frame that was received instead
+    pub actual: CapturedFrame,
+}
+
+/// Frames captured with
+/// [`ClientServerOptions::capture`](crate::model::ClientServerOptions::capture), read back from
+/// the capture file.
+///
+/// Every record of the file is a frame in the layout of the wire, without the magic bytes and
+/// checksums and after decryption and decompression, prefixed with when and where it was seen:
+///
+/// ```text
+/// time: u64 | connection: u64 | direction: u8 | kind: u8 | channel: u32 | len: u64 | payload
+/// ```
+///
+/// Numbers are little endian, the time is in microseconds since the unix epoch.
+#[derive(Debug, Clone, Default)]
+pub struct Recording {
+    frames: Vec<CapturedFrame>,
+}
+
+impl Recording {
+    /// Read a capture file
+    ///
+    /// # Errors
+    ///
+    /// Fails if the file can't be read, and with [`io::ErrorKind::InvalidData`] if it isn't a
+    /// capture file or a record is broken, like the last one while it is still being written.
+    pub fn read<P>(path: P) -> Result<Self, io::Error>
+    where
+        P: AsRef<Path>,
+    {
+        let bytes = std::fs::read(path)?;
+        let Some(mut records) = bytes.strip_prefix(FILE_MAGIC) else {
+            return Err(invalid("not a capture file"));
+        };
+        let mut frames = Vec::new();
+        while !records.is_empty() {
+            let (frame, rest) = parse_record(records)?;
+            frames.push(frame);
+            records = rest;
+        }
+        Ok(Self { frames })
+    }
+
+    /// Every captured frame, in the order they were captured
+    #[must_use]
+    pub fn frames(&self) -> &[CapturedFrame] {
+        &self.frames
+    }
+
+    /// IDs of the captured connections, in the order they were first seen
+    #[must_use]
+    pub fn connections(&self) -> Vec<u64> {
+        let mut connections = Vec::new();
+        for frame in &self.frames {
+            if !connections.contains(&frame.connection) {
+                connections.push(frame.connection);
+            }
+        }
+        connections
+    }
+
+    /// Play the client side of a captured connection against a running server.
+    ///
+    /// Frames the client sent are sent again in order, whenever the capture has the server
+    /// answering, a frame is read from the server and compared with the captured one. Returns the
+    /// frames that didn't match, so a regression test can check that a server still answers the
+    /// same way. The timing of the capture isn't kept, frames are sent as fast as possible.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the connection breaks, a mismatch alone isn't an error.
+    pub fn replay_client<C, S>(
+        &self,
+        connection: u64,
+        client: Client<C, S>,
+    ) -> Result<Vec<Mismatch>, ConnectionError>
+    where
+        C: Serialize + for<'de> Deserialize<'de>,
+        S: Serialize + for<'de> Deserialize<'de>,
+    {
+        let raw = client.into_connection().into_raw();
+        self.replay(connection, &raw, Direction::ToServer)
+    }
+
+    /// Play the server side of a captured connection to a client, the counterpart of
+    /// [`Recording::replay_client`].
+    ///
+    /// Use it with a connection of [`Server::connections`](crate::server::Server::connections) to
+    /// check that a client still sends the same messages when it gets the captured answers.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`Recording::replay_client`].
+    pub fn replay_server<S, C>(
+        &self,
+        connection: u64,
+        conn: Connection<S, C>,
+    ) -> Result<Vec<Mismatch>, ConnectionError>
+    where
+        S: Serialize,
+        C: for<'de> Deserialize<'de>,
+    {
+        self.replay(connection, &conn.into_raw(), Direction::ToClient)
+    }
+
+    /// Send the frames going in `direction` and compare the others with what we receive
+    fn replay(
+        &self,
+        connection: u64,
+        raw: &RawConnection,
+        direction: Direction,
+    ) -> Result<Vec<Mismatch>, ConnectionError> {
+        let frames = self
+            .frames
+            .iter()
+            .filter(|frame| frame.connection == connection);
+        let mut mismatches = Vec::new();
+        for (index, expected) in frames.enumerate() {
+            if expected.direction == direction {
+                raw.write_channel_frame(expected.kind, expected.channel, &expected.data)?;
+                continue;
+            }
+            let actual = CapturedFrame::new(connection, expected.direction, raw.read_frame()?);
+            if !actual.same_frame(expected) {
+                mismatches.push(Mismatch {
+                    index,
+                    expected: expected.clone(),
+                    actual,
+                });
+            }
+        }
+        Ok(mismatches)
+    }
+}
+
+/// Parse the first record of `bytes`, returns it along with the rest
+fn parse_record(bytes: &[u8]) -> Result<(CapturedFrame, &[u8]), io::Error> {
+    let Some((header, rest)) = bytes.split_first_chunk::<RECORD_HEADER_LEN>() else {
+        return Err(invalid("truncated record"));
+    };
+    let (time, header) = take_u64(header);
+    let (connection, header) = take_u64(header);
+    let &[direction, kind, ref header @ ..] = header else {
+        return Err(invalid("truncated record"));
+    };
+    let Some((channel, len)) = header.split_first_chunk::<4>() else {
+        return Err(invalid("truncated record"));
+    };
+    let (len, _) = take_u64(len);
+    let direction = match direction {
+        0 => Direction::ToServer,
+        1 => Direction::ToClient,
+        _ => return Err(invalid("unknown direction")),
+    };
+    let kind = FrameKind::from_byte(kind).ok_or_else(|| invalid("unknown frame kind"))?;
+    let len = usize::try_from(len).map_err(invalid)?;
+    if rest.len() < len {
+        return Err(invalid("truncated record"));
+    }
+    let (data, rest) = rest.split_at(len);
+    let frame = CapturedFrame {
+        time: SystemTime::UNIX_EPOCH + Duration::from_micros(time),
+        connection,
+        direction,
+        kind,
+        channel: u32::from_le_bytes(*channel),
+        data: data.to_vec(),
+    };
+    Ok((frame, rest))
+}
+
+/// Read a little endian u64 from the start of `bytes`, zero if there aren't enough
+const fn take_u64(bytes: &[u8]) -> (u64, &[u8]) {
+    match bytes.split_first_chunk::<8>() {
+        Some((value, rest)) => (u64::from_le_bytes(*value), rest),
+        None => (0, bytes),
+    }
+}
+
+fn invalid<E>(error: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(ErrorKind::InvalidData, error)
+}
+
+/// The file frames are captured to, shared by every connection of a model
+#[derive(Debug)]
+pub(crate) struct CaptureFile {
+    path: PathBuf,
+    file: Mutex<FileState>,
+}
+
+#[derive(Debug)]
+enum FileState {
+    /// Opened on the first frame
+    Closed,
+    Open(File),
+    /// Opening or writing failed once, we already warned about it
+    Failed,
+}
+
+impl CaptureFile {
+    pub(crate) const fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            file: Mutex::new(FileState::Closed),
+        }
+    }
+
+    /// Start capturing a new connection
+    pub(crate) fn connection(self: &Arc<Self>, side: Side) -> CaptureConnection {
+        // Unique across processes appending to the same file
+        let id = (u64::from(std::process::id()) << 32)
+            | u64::from(NEXT_CONNECTION.fetch_add(1, Ordering::Relaxed));
+        CaptureConnection {
+            file: self.clone(),
+            id,
+            side,
+        }
+    }
+
+    fn write(&self, record: &[u8]) {
+        let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        if matches!(*file, FileState::Closed) {
+            *file = match self.open() {
+                Ok(opened) => FileState::Open(opened),
+                Err(e) => {
+                    self.warn(&e);
+                    FileState::Failed
+                }
+            }
+        }
+        if let FileState::Open(opened) = &mut *file
+            && let Err(e) = opened.write_all(record)
+        {
+            self.warn(&e);
+            *file = FileState::Failed;
+        }
+    }
+
+    /// Open the file for appending, so that several processes can capture to the same file.
+    ///
+    /// Only the process that creates the file writes the magic bytes, the others wait for them
+    /// before appending. On unix only the owning user can read a new file, it holds the frames
+    /// of encrypted connections in plain.
+    fn open(&self) -> Result<File, io::Error> {
+        let mut options = OpenOptions::new();
+        options.append(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        match options.open(&self.path) {
+            Ok(mut file) => {
+                file.write_all(FILE_MAGIC)?;
+                return Ok(file);
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => (),
+            Err(e) => return Err(e),
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&self.path)?;
+        for _ in 0..100 {
+            let mut prefix = Vec::new();
+            file.rewind()?;
+            (&mut file)
+                .take(FILE_MAGIC.len() as u64)
+                .read_to_end(&mut prefix)?;
+            if prefix == FILE_MAGIC {
+                return Ok(file);
+            }
+            if !FILE_MAGIC.starts_with(&prefix) {
+                return Err(invalid("not a capture file"));
+            }
+            // Another process just created the file and is writing the magic bytes
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        Err(invalid("the capture file has no magic bytes"))
+    }
+
+    fn warn(&self, error: &io::Error) {
+        trace::warn(&format!(
+            "Couldn't capture to {}, stopped capturing: {error}",
+            self.path.display()
+        ));
+    }
+}
+
+/// Captures the frames of a single connection
+#[derive(Debug)]
+pub(crate) struct CaptureConnection {
+    file: Arc<CaptureFile>,
+    id: u64,
+    side: Side,
+}
+
+impl CaptureConnection {
+    /// Capture a frame we sent
+    pub(crate) fn sent(&self, kind: FrameKind, channel: u32, data: &[u8]) {
+        let direction = match self.side {
+            Side::Client => Direction::ToServer,
+            Side::Server => Direction::ToClient,
+        };
+        self.capture(direction, kind, channel, data);
+    }
+
+    /// Capture a frame we received
+    pub(crate) fn received(&self, kind: FrameKind, channel: u32, data: &[u8]) {
+        let direction = match self.side {
+            Side::Client => Direction::ToClient,
+            Side::Server => Direction::ToServer,
+        };
+        self.capture(direction, kind, channel, data);
+    }
+
+    fn capture(&self, direction: Direction, kind: FrameKind, channel: u32, data: &[u8]) {
+        // Frames the connection uses for itself are of no interest, and tokens are secret
+        if matches!(
+            kind,
+            FrameKind::Heartbeat
+                | FrameKind::Capabilities
+                | FrameKind::Handshake
+                | FrameKind::Token
+                | FrameKind::Ping
+                | FrameKind::Pong
+        ) {
+            return;
+        }
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros();
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN + data.len());
+        record.extend_from_slice(&u64::try_from(time).unwrap_or(u64::MAX).to_le_bytes());
+        record.extend_from_slice(&self.id.to_le_bytes());
+        record.push(match direction {
+            Direction::ToServer => 0,
+            Direction::ToClient => 1,
+        });
+        record.push(kind as u8);
+        record.extend_from_slice(&channel.to_le_bytes());
+        record.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        record.extend_from_slice(data);
+        self.file.write(&record);
+    }
+}
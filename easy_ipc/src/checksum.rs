@@ -0,0 +1,44 @@
+/// Checksum used to detect corrupted frames, see
+/// [`ClientServerOptions::checksum`](crate::model::ClientServerOptions::checksum).
+///
+/// The algorithms are behind the cargo features of the same name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum Checksum {
+    /// Don't check frames
+    #[default]
+    None,
+    /// CRC32C, hardware accelerated on most CPUs
+    #[cfg(feature = "crc32c")]
+    Crc32c,
+    /// 32 bit xxHash, fast on any CPU
+    #[cfg(feature = "xxhash")]
+    XxHash,
+}
+
+impl Checksum {
+    /// Number of bytes the checksums take in the header of a frame, one for the header itself
+    /// and one for the payload.
+    pub(crate) const fn header_length(self) -> usize {
+        match self {
+            Self::None => 0,
+            #[cfg(any(feature = "crc32c", feature = "xxhash"))]
+            _ => 2 * size_of::<u32>(),
+        }
+    }
+
+    /// Compute the checksum of `data`
+    #[cfg_attr(
+        not(any(feature = "crc32c", feature = "xxhash")),
+        allow(unused_variables, clippy::missing_const_for_fn)
+    )]
+    pub(crate) fn compute(self, data: &[u8]) -> u32 {
+        match self {
+            Self::None => 0,
+            #[cfg(feature = "crc32c")]
+            Self::Crc32c => crc32c::crc32c(data),
+            #[cfg(feature = "xxhash")]
+            Self::XxHash => xxhash_rust::xxh32::xxh32(data, 0),
+        }
+    }
+}
@@ -1,10 +1,26 @@
 use {
-    crate::{connection::Connection, error::ConnectionError, model::OptionsRaw},
+    crate::{
+        connection::{Connection, PeerInfo, Received, Side},
+        error::{ConnectionError, InitError},
+        model::OptionsRaw,
+        mux::{ClientEnd, Multiplexer},
+        request::CancelHandle,
+        sender::{Overflow, Sender},
+        stream::ResponseStream,
+        token,
+    },
     interprocess::local_socket::Stream,
     serde::{Deserialize, Serialize},
-    std::{marker::PhantomData, sync::Arc},
+    std::{
+        marker::PhantomData,
+        sync::Arc,
+        time::{Duration, SystemTime},
+    },
 };
 
+#[cfg(unix)]
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
+
 /// Client that is able to connect to a server and send/receive messages
 #[derive(Debug)]
 pub struct Client<T, R>
@@ -23,22 +39,199 @@ where
     R: Serialize + for<'de> Deserialize<'de>,
 {
     /// Create a new client given a connection
-    pub(crate) fn new(opts: OptionsRaw, stream: Stream) -> Self {
+    pub(crate) fn new(opts: OptionsRaw, stream: Stream) -> Result<Self, InitError> {
         let opts = Arc::new(opts);
-        let connection = Connection::new(stream, opts);
-        Self {
+        let token = match &opts.token_file {
+            Some(path) => Some(token::read(path).map_err(InitError::TokenFile)?),
+            None => None,
+        };
+        let connection = Connection::new(stream, opts, Side::Client, None, token.as_deref())
+            .map_err(InitError::FailedConnectingToSocket)?;
+        Ok(Self {
             connection,
             _tx: PhantomData,
             _rx: PhantomData,
-        }
+        })
     }
     /// Send a message to the server
     pub fn send(&mut self, msg: T) -> Result<(), ConnectionError> {
         self.connection.send(msg)
     }
 
+    /// Send several messages to the server with as few writes as possible, see
+    /// [`Connection::send_batch`].
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`Connection::send_batch`].
+    pub fn send_batch(&mut self, messages: &[T]) -> Result<(), ConnectionError> {
+        self.connection.send_batch(messages)
+    }
+
+    /// Hold back messages to the server until [`Client::uncork`], see [`Connection::cork`]
+    pub fn cork(&mut self) {
+        self.connection.cork();
+    }
+
+    /// Write the messages held back since [`Client::cork`] in one go, see
+    /// [`Connection::uncork`].
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`Connection::uncork`].
+    pub fn uncork(&mut self) -> Result<(), ConnectionError> {
+        self.connection.uncork()
+    }
+
+    /// Send a message to the server along with a deadline for it to respond by, see
+    /// [`Connection::send_with_deadline`].
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`Connection::send_with_deadline`].
+    pub fn send_with_deadline(
+        &mut self,
+        msg: T,
+        deadline: SystemTime,
+    ) -> Result<(), ConnectionError> {
+        self.connection.send_with_deadline(msg, deadline)
+    }
+
+    /// Send a message to the server serialized with rkyv, see [`Connection::send_archived`]
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`Connection::send_archived`].
+    #[cfg(feature = "rkyv")]
+    pub fn send_archived<M>(&mut self, message: &M) -> Result<(), ConnectionError>
+    where
+        M: for<'a> rkyv::Serialize<crate::connection::ArchiveSerializer<'a>>,
+    {
+        self.connection.send_archived(message)
+    }
+
+    /// Get a [`Sender`] that queues messages to the server, see
+    /// [`Connection::buffered_sender`].
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`Connection::buffered_sender`].
+    pub fn buffered_sender(
+        &self,
+        capacity: usize,
+        overflow: Overflow,
+    ) -> Result<Sender<T>, std::io::Error> {
+        self.connection.buffered_sender(capacity, overflow)
+    }
+
+    /// Get a handle that can cancel the current request from another thread, see
+    /// [`CancelHandle`].
+    #[must_use]
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.connection.cancel_handle()
+    }
+
+    /// Cancel the current request when the process is asked to terminate, for instance when the
+    /// user presses Ctrl-C.
+    ///
+    /// The signal is handled by sending a cancel to the server and then terminating the process as
+    /// the signal would have by default. Only supported on unix, on other platforms this does
+    /// nothing.
+    pub fn cancel_on_interrupt(&self) {
+        crate::handlers::cancel_on_signals(self.cancel_handle());
+    }
+
+    /// Make receiving from the server, and pinging it, fail with
+    /// [`ConnectionError::ReadFailed`] once nothing arrived for `timeout`, or wait forever if
+    /// `None`, which is the default.
+    ///
+    /// Only supported on unix, on other platforms reads keep waiting. A read that timed out may
+    /// have left part of a frame behind, the connection is best dropped afterwards.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the socket refuses the timeout.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), std::io::Error> {
+        self.connection.set_read_timeout(timeout)
+    }
+
+    /// Ask the server to describe itself, see [`Connection::ping`]
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`Connection::ping`].
+    pub fn ping(&mut self) -> Result<PeerInfo, ConnectionError> {
+        self.connection.ping()
+    }
+
     /// Receive a message from the server
     pub fn receive(&mut self) -> Result<R, ConnectionError> {
         self.connection.receive()
     }
+
+    /// Receive a message from the server without deserializing it yet, see
+    /// [`Connection::receive_borrowed`].
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`Connection::receive_borrowed`].
+    pub fn receive_borrowed(&mut self) -> Result<Received<'_, R>, ConnectionError> {
+        self.connection.receive_borrowed()
+    }
+
+    /// Receive a message from the server if one has fully arrived, see
+    /// [`Connection::try_receive`].
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`Connection::try_receive`].
+    pub fn try_receive(&mut self) -> Result<Option<R>, ConnectionError> {
+        self.connection.try_receive()
+    }
+
+    /// Receive a streamed response from the server, see [`Connection::receive_stream`].
+    pub const fn receive_stream(&mut self) -> ResponseStream<'_, T, R> {
+        self.connection.receive_stream()
+    }
+
+    /// Turn this client into a [`Multiplexer`] to run several logical channels over its
+    /// connection.
+    ///
+    /// The server needs to do the same with [`Multiplexer::server`] on its end.
+    #[must_use]
+    pub fn into_multiplexer(self) -> Multiplexer<ClientEnd> {
+        Multiplexer::client(self)
+    }
+
+    /// Send a message that was already serialized
+    pub(crate) fn send_encoded(&self, bytes: &[u8]) -> Result<(), ConnectionError> {
+        self.connection.send_encoded(bytes)
+    }
+
+    pub(crate) fn into_connection(self) -> Connection<T, R> {
+        self.connection
+    }
+}
+
+/// The socket of the connection, see the implementation for [`Connection`]
+#[cfg(unix)]
+impl<T, R> AsFd for Client<T, R>
+where
+    T: Serialize + for<'de> Deserialize<'de>,
+    R: Serialize + for<'de> Deserialize<'de>,
+{
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.connection.as_fd()
+    }
+}
+
+#[cfg(unix)]
+impl<T, R> AsRawFd for Client<T, R>
+where
+    T: Serialize + for<'de> Deserialize<'de>,
+    R: Serialize + for<'de> Deserialize<'de>,
+{
+    fn as_raw_fd(&self) -> RawFd {
+        self.connection.as_raw_fd()
+    }
 }
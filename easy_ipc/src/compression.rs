@@ -0,0 +1,129 @@
+use std::io;
+
+/// Algorithm used to compress messages, see
+/// [`ClientServerOptions::compression`](crate::model::ClientServerOptions::compression).
+///
+/// The algorithms are behind the cargo features of the same name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum Compression {
+    /// Send messages as they are
+    #[default]
+    None,
+    /// Compress with zstd at the given level, 1 to 22 with 3 being a good default
+    #[cfg(feature = "zstd")]
+    Zstd {
+        /// Compression level, higher is smaller but slower
+        level: i32,
+    },
+    /// Compress with lz4, which is very fast but doesn't compress as well as zstd
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+/// Identifies the algorithm at the start of a compressed payload
+#[cfg(feature = "zstd")]
+const ZSTD: u8 = 1;
+#[cfg(feature = "lz4")]
+const LZ4: u8 = 2;
+
+impl Compression {
+    /// Bit of the algorithm in the capabilities we advertise, 0 for no compression
+    pub(crate) const fn bit(self) -> u8 {
+        match self {
+            Self::None => 0,
+            #[cfg(feature = "zstd")]
+            Self::Zstd { .. } => 1 << ZSTD,
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => 1 << LZ4,
+        }
+    }
+
+    /// Compress `data`, the result starts with a byte telling which algorithm was used
+    #[cfg_attr(
+        not(any(feature = "zstd", feature = "lz4")),
+        allow(clippy::unnecessary_wraps)
+    )]
+    pub(crate) fn compress(self, data: &[u8]) -> Result<Vec<u8>, io::Error> {
+        match self {
+            Self::None => Ok(data.to_vec()),
+            #[cfg(feature = "zstd")]
+            Self::Zstd { level } => {
+                let mut out = vec![ZSTD];
+                zstd::stream::copy_encode(data, &mut out, level)?;
+                Ok(out)
+            }
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => {
+                let mut out = vec![LZ4];
+                out.extend(lz4_flex::compress_prepend_size(data));
+                Ok(out)
+            }
+        }
+    }
+}
+
+/// Algorithms this build can decompress, as bits like [`Compression::bit`]
+pub(crate) const fn supported() -> u8 {
+    #[allow(unused_mut)]
+    let mut bits = 0;
+    #[cfg(feature = "zstd")]
+    {
+        bits |= 1 << ZSTD;
+    }
+    #[cfg(feature = "lz4")]
+    {
+        bits |= 1 << LZ4;
+    }
+    bits
+}
+
+/// Decompress a payload produced by [`Compression::compress`], failing without allocating more
+/// if it would be longer than `limit` bytes
+#[cfg_attr(not(any(feature = "zstd", feature = "lz4")), allow(unused_variables))]
+pub(crate) fn decompress(data: &[u8], limit: usize) -> Result<Vec<u8>, io::Error> {
+    let Some((&algorithm, payload)) = data.split_first() else {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    };
+    match algorithm {
+        #[cfg(feature = "zstd")]
+        ZSTD => {
+            use io::Read as _;
+
+            let mut out = Vec::new();
+            // One byte more than allowed tells apart payloads that are exactly at the limit
+            let allowed = u64::try_from(limit).unwrap_or(u64::MAX).saturating_add(1);
+            zstd::stream::read::Decoder::new(payload)?
+                .take(allowed)
+                .read_to_end(&mut out)?;
+            if out.len() > limit {
+                return Err(too_large());
+            }
+            Ok(out)
+        }
+        #[cfg(feature = "lz4")]
+        LZ4 => {
+            // The size is prepended, check it before lz4 allocates that much
+            let size = payload
+                .first_chunk::<4>()
+                .map(|size| u32::from_le_bytes(*size))
+                .ok_or(io::ErrorKind::UnexpectedEof)?;
+            if usize::try_from(size).map_or(true, |size| size > limit) {
+                return Err(too_large());
+            }
+            lz4_flex::decompress_size_prepended(payload).map_err(io::Error::other)
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "message was compressed with an unsupported algorithm",
+        )),
+    }
+}
+
+#[cfg(any(feature = "zstd", feature = "lz4"))]
+fn too_large() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "message decompresses to more than the maximum message size",
+    )
+}
@@ -1,14 +1,49 @@
 use {
-    crate::{error::ConnectionError, model::OptionsRaw},
-    interprocess::local_socket::Stream,
+    crate::{
+        capture::CaptureConnection,
+        checksum::Checksum,
+        compression::{self, Compression},
+        error::ConnectionError,
+        heartbeat::{self, Liveness},
+        metrics::{ConnectionCounters, MessageMetrics, ServerCounters},
+        model::OptionsRaw,
+        pipe,
+        request::{CancelHandle, RequestContext},
+        sender::{Overflow, Sender},
+        stream::{ResponseStream, StreamStatus},
+        token,
+        trace::{self, Operation, Span},
+    },
+    interprocess::{
+        TryClone,
+        local_socket::{Stream, traits::Stream as _},
+    },
     serde::{Deserialize, Serialize},
     std::{
-        io::{BufReader, prelude::*},
+        any::type_name,
+        collections::VecDeque,
+        fmt::Display,
+        io::{BufReader, ErrorKind, IoSlice, prelude::*},
         marker::PhantomData,
-        sync::Arc,
+        ops::{Deref, DerefMut},
+        process::Child,
+        sync::{
+            Arc, Mutex, MutexGuard, PoisonError, TryLockError,
+            atomic::{AtomicBool, AtomicU8, Ordering},
+        },
+        time::{Duration, Instant, SystemTime},
     },
 };
 
+#[cfg(unix)]
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
+
+#[cfg(feature = "noise")]
+use {
+    crate::noise::{self, Cipher},
+    std::sync::OnceLock,
+};
+
 /// Represents a connection that can send and receive messages
 // S[end] and R[eceive]
 #[derive(Debug)]
@@ -17,8 +52,9 @@ where
     T: Serialize,
     R: for<'de> Deserialize<'de>,
 {
-    connection: BufReader<Stream>,
-    opts: Arc<OptionsRaw>,
+    raw: Arc<RawConnection>,
+    /// Receive buffer kept between messages, see [`Connection::receive_borrowed`]
+    buffer: Vec<u8>,
     _tx: PhantomData<T>,
     _rx: PhantomData<R>,
 }
@@ -28,81 +64,1536 @@ where
     T: Serialize,
     R: for<'de> Deserialize<'de>,
 {
-    /// Make a new connection given a stream.
+    /// Make a new connection given a stream, `server` collects the metrics of all connections of
+    /// a server. Clients present `token` before anything else, see
+    /// [`ClientServerOptions::token_auth`](crate::model::ClientServerOptions::token_auth).
     // NOTE: This method should not be exposed publicly
-    pub(crate) fn new(stream: Stream, opts: Arc<OptionsRaw>) -> Self {
-        let connection = BufReader::new(stream);
-        Connection {
-            connection,
-            opts,
+    pub(crate) fn new(
+        stream: Stream,
+        opts: Arc<OptionsRaw>,
+        side: Side,
+        server: Option<Arc<ServerCounters>>,
+        token: Option<&[u8]>,
+    ) -> Result<Self, std::io::Error> {
+        let metrics = ConnectionCounters::new(server);
+        let raw = RawConnection::new(stream, opts, side, metrics, token)?;
+        Ok(Self {
+            raw,
+            buffer: Vec::new(),
             _tx: PhantomData,
             _rx: PhantomData,
-        }
+        })
+    }
+
+    /// Make a connection that talks over `reader` and `writer` instead of a socket, for pipes and
+    /// the like.
+    ///
+    /// Frames are the same as over a socket, so the other end needs a connection of its own on
+    /// the other ends of the pipes, for instance made with [`Connection::from_stdio`]. The
+    /// connection has the default options of a model, [`IpcModel::spawn_child`] and
+    /// [`IpcModel::stdio_server`] make connections with the options of a model. Only supported
+    /// on unix.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the threads passing the bytes on can't be started, or with
+    /// [`std::io::ErrorKind::Unsupported`] on other platforms.
+    ///
+    /// [`IpcModel::spawn_child`]: crate::model::IpcModel::spawn_child
+    /// [`IpcModel::stdio_server`]: crate::model::IpcModel::stdio_server
+    pub fn from_rw<Rd, Wr>(reader: Rd, writer: Wr) -> Result<Self, std::io::Error>
+    where
+        Rd: Read + Send + 'static,
+        Wr: Write + Send + 'static,
+    {
+        Self::over_pipes(reader, writer, Side::Client)
+    }
+
+    /// Talk to a child process over its standard input and output, which have to be piped.
+    ///
+    /// The child is waited for once its output closes. Dropping the connection closes the input
+    /// of the child, which is how it learns that we are done. See [`Connection::from_rw`].
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`std::io::ErrorKind::InvalidInput`] if the standard input or output of the
+    /// child isn't piped, otherwise like [`Connection::from_rw`].
+    pub fn from_child(child: Child) -> Result<Self, std::io::Error> {
+        let (output, input) = pipe::child(child)?;
+        Self::over_pipes(output, input, Side::Client)
+    }
+
+    /// Talk to the parent process over our standard input and output, the other end of
+    /// [`Connection::from_child`].
+    ///
+    /// Nothing else may write to the standard output, printing to it would corrupt the frames.
+    /// Print to the standard error instead. See [`Connection::from_rw`].
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`Connection::from_rw`].
+    pub fn from_stdio() -> Result<Self, std::io::Error> {
+        Self::over_pipes(std::io::stdin(), std::io::stdout(), Side::Server)
+    }
+
+    fn over_pipes<Rd, Wr>(reader: Rd, writer: Wr, side: Side) -> Result<Self, std::io::Error>
+    where
+        Rd: Read + Send + 'static,
+        Wr: Write + Send + 'static,
+    {
+        let opts = Arc::new(OptionsRaw::new(""));
+        Self::new(pipe::wrap(reader, writer)?, opts, side, None, None)
     }
 
     /// Send a message to the other end of the connection.
     pub fn send(&mut self, message: T) -> Result<(), ConnectionError> {
-        let bytes = bitcode::serialize(&message).map_err(ConnectionError::SerilizationFailed)?;
-        let packet_bytes = self.make_packet(bytes);
-        self.connection
-            .get_mut()
-            .write_all(&packet_bytes)
-            .map_err(ConnectionError::WriteFailed)?;
-        Ok(())
+        self.send_message(FrameKind::Message, &message)
+    }
+
+    /// Send several messages with as few writes as possible. The other end receives them one by
+    /// one, like messages sent with [`Connection::send`].
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`Connection::send`], part of the batch may have been written then.
+    pub fn send_batch(&mut self, messages: &[T]) -> Result<(), ConnectionError> {
+        let payloads = messages
+            .iter()
+            .map(|message| bitcode::serialize(message).map_err(ConnectionError::SerilizationFailed))
+            .collect::<Result<Vec<_>, _>>()?;
+        let operation = Operation::start(&self.raw.span, "send batch", type_name::<T>());
+        operation.bytes(payloads.iter().map(Vec::len).sum());
+        operation.finish(
+            self.raw
+                .write_frames(FrameKind::Message, MAIN_CHANNEL, &payloads),
+        )
+    }
+
+    /// Hold back every frame sent from now on, until [`Connection::uncork`] writes them all at
+    /// once.
+    ///
+    /// Saves a write per message when sending many small messages in a row. Nothing reaches the
+    /// other end while corked, including answers to its pings and cancellations of its requests,
+    /// so uncork before waiting for a response. Heartbeats aren't sent either, corking for longer
+    /// than the missed heartbeat limit of the other end makes it give up on the connection.
+    pub fn cork(&mut self) {
+        self.raw.cork();
+    }
+
+    /// Write the frames held back since [`Connection::cork`] with a single vectored write and stop
+    /// holding frames back. They count as sent in the [`Connection::metrics`] from then on.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the write fails, the frames that were held back are dropped then.
+    pub fn uncork(&mut self) -> Result<(), ConnectionError> {
+        self.raw.uncork()
+    }
+
+    /// Send a message along with a deadline for the other end to respond by.
+    ///
+    /// The deadline is only advisory, the other end can see it with
+    /// [`Connection::receive_request`] and decide to give up once it passes.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`Connection::send`].
+    pub fn send_with_deadline(
+        &mut self,
+        message: T,
+        deadline: SystemTime,
+    ) -> Result<(), ConnectionError> {
+        // Deadlines before the epoch don't make sense, treat them as already passed.
+        let millis = deadline
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let mut bytes = u64::try_from(millis)
+            .unwrap_or(u64::MAX)
+            .to_le_bytes()
+            .to_vec();
+        bytes.extend(bitcode::serialize(&message).map_err(ConnectionError::SerilizationFailed)?);
+        let operation = Operation::start(&self.raw.span, "send", type_name::<T>());
+        operation.bytes(bytes.len());
+        operation.finish(self.raw.write_frame(FrameKind::DeadlineMessage, &bytes))
+    }
+
+    /// Send a message serialized with rkyv instead of bitcode, so that the other end can read it
+    /// in place with [`Received::archived`] instead of deserializing it.
+    ///
+    /// `M` doesn't have to be the message type of the connection. The other end has to receive
+    /// the message with [`Connection::receive_borrowed`], [`Connection::receive`] fails with
+    /// [`ConnectionError::UnexpectedFrame`] on it.
+    ///
+    /// # Errors
+    ///
+    /// Fails if rkyv can't serialize the message or writing to the connection fails.
+    #[cfg(feature = "rkyv")]
+    pub fn send_archived<M>(&mut self, message: &M) -> Result<(), ConnectionError>
+    where
+        M: for<'a> rkyv::Serialize<ArchiveSerializer<'a>>,
+    {
+        let bytes = rkyv::to_bytes::<rkyv::rancor::Error>(message)
+            .map_err(ConnectionError::ArchiveFailed)?;
+        let operation = Operation::start(&self.raw.span, "send", type_name::<M>());
+        operation.bytes(bytes.len());
+        operation.finish(self.raw.write_frame(FrameKind::ArchivedMessage, &bytes))
     }
 
     /// Receive a message from the other end of the connection
     pub fn receive(&mut self) -> Result<R, ConnectionError> {
+        self.receive_request().map(|(message, _context)| message)
+    }
+
+    /// Receive a message if one has fully arrived, without waiting for it.
+    ///
+    /// Whatever has arrived of the next message is read and kept until the rest arrives, later
+    /// calls to this or [`Connection::receive`] pick up where it left off. Returns `None` as well
+    /// if another thread is reading from the connection.
+    ///
+    /// Meant for event loops that wait for the connection to become readable, see the [`AsFd`]
+    /// implementation. Several messages can arrive at once, call this until it returns `None`
+    /// before waiting again.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`Connection::receive`], with [`ConnectionError::UnexepctedEof`] once the other
+    /// end closed the connection.
+    pub fn try_receive(&mut self) -> Result<Option<R>, ConnectionError> {
+        while let Some(frame) = self.raw.try_read_frame()? {
+            let operation = Operation::start(&self.raw.span, "receive", type_name::<R>());
+            operation.bytes(frame.data.len());
+            if let Some((message, _context)) = operation.finish(self.request(&frame))? {
+                return Ok(Some(message));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Receive a message without deserializing it yet, so that it can borrow from the frame.
+    ///
+    /// The frame is read into a buffer the connection keeps from one message to the next, so
+    /// receiving this way doesn't allocate once the buffer has grown to the size of the messages.
+    /// See [`Received`] for how to get at the message.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`Connection::receive`], except that the message isn't deserialized yet.
+    pub fn receive_borrowed(&mut self) -> Result<Received<'_, R>, ConnectionError> {
+        let operation = Operation::start(&self.raw.span, "receive", type_name::<R>());
+        let received = loop {
+            let (kind, channel) = match self.raw.read_frame_reusing(&mut self.buffer) {
+                Ok(read) => read,
+                Err(e) => break Err(e),
+            };
+            operation.bytes(self.buffer.len());
+            match Payload::of(kind, channel, &self.buffer) {
+                Ok(Some(payload)) => break Ok(payload),
+                Ok(None) => (),
+                Err(e) => break Err(e),
+            }
+        };
+        operation.finish(received).map(|payload| Received {
+            raw: &self.raw,
+            data: &self.buffer[payload.start..],
+            payload,
+            #[cfg(feature = "rkyv")]
+            aligned: std::cell::OnceCell::new(),
+            _rx: PhantomData,
+        })
+    }
+
+    /// Receive a message along with the context it was sent in.
+    ///
+    /// The [`RequestContext`] lets a long running handler check if the other end has cancelled
+    /// the request or if the deadline sent with [`Connection::send_with_deadline`] has passed.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`Connection::receive`].
+    pub fn receive_request(&mut self) -> Result<(R, RequestContext), ConnectionError> {
+        let operation = Operation::start(&self.raw.span, "receive", type_name::<R>());
+        let request = self.read_request(&operation);
+        operation.finish(request)
+    }
+
+    fn read_request(&self, operation: &Operation) -> Result<(R, RequestContext), ConnectionError> {
+        loop {
+            let frame = self.raw.read_frame()?;
+            operation.bytes(frame.data.len());
+            if let Some(request) = self.request(&frame)? {
+                return Ok(request);
+            }
+        }
+    }
+
+    /// The message a frame carries, `None` for frames that are skipped
+    fn request(&self, frame: &Frame) -> Result<Option<(R, RequestContext)>, ConnectionError> {
+        let Some(payload) = Payload::of(frame.kind, frame.channel, &frame.data)? else {
+            return Ok(None);
+        };
+        if payload.archived {
+            return Err(ConnectionError::UnexpectedFrame);
+        }
+        Ok(Some((
+            self.decode(&frame.data[payload.start..])?,
+            RequestContext::new(self.raw.clone(), payload.deadline),
+        )))
+    }
+
+    /// Counters of the messages that went through this connection so far
+    #[must_use]
+    pub fn metrics(&self) -> MessageMetrics {
+        self.raw.metrics.snapshot()
+    }
+
+    /// Ask the other end to describe itself, which also measures the round trip time.
+    ///
+    /// The other end answers while it is reading from the connection, or from its heartbeat
+    /// thread if it has heartbeats enabled. Nothing else may be waiting to be read from the other
+    /// end, any other frame fails the ping with [`ConnectionError::UnexpectedFrame`].
+    ///
+    /// A server using [`ClientServerOptions::token_auth`](crate::model::ClientServerOptions::token_auth)
+    /// only answers clients that sent it the right token, it rejects anybody pinging it before.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the connection breaks, or the answer isn't a description of the other end.
+    pub fn ping(&mut self) -> Result<PeerInfo, ConnectionError> {
+        let start = Instant::now();
+        self.raw.write_frame(FrameKind::Ping, &[])?;
+        let frame = self.raw.read_frame()?;
+        if frame.kind != FrameKind::Pong {
+            return Err(ConnectionError::UnexpectedFrame);
+        }
+        let mut info: PeerInfo =
+            bitcode::deserialize(&frame.data).map_err(ConnectionError::DeserilizationFailed)?;
+        info.round_trip = start.elapsed();
+        Ok(info)
+    }
+
+    /// Get a [`Sender`] that queues up to `capacity` messages and writes them on a thread of
+    /// its own, `overflow` decides what happens once the queue is full.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the thread can't be spawned.
+    pub fn buffered_sender(
+        &self,
+        capacity: usize,
+        overflow: Overflow,
+    ) -> Result<Sender<T>, std::io::Error> {
+        Sender::new(self.raw.clone(), capacity, overflow)
+    }
+
+    /// Get a handle that can cancel the current request from another thread.
+    ///
+    /// See [`CancelHandle`].
+    #[must_use]
+    pub fn cancel_handle(&self) -> CancelHandle {
+        CancelHandle::new(self.raw.clone())
+    }
+
+    /// See [`Client::set_read_timeout`](crate::client::Client::set_read_timeout)
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), std::io::Error> {
+        self.raw.set_read_timeout(timeout)
+    }
+
+    /// Send every item of `items` as a single streamed response.
+    ///
+    /// The other end reads the items with [`Connection::receive_stream`]. Between items we check
+    /// if the other end has cancelled the stream, in which case we stop pulling items from the
+    /// iterator and return [`StreamStatus::Cancelled`]. Anything that can be turned into an
+    /// iterator works, including the receiving end of a [`std::sync::mpsc::channel`].
+    ///
+    /// # Errors
+    ///
+    /// Fails if an item can't be serialized or the connection breaks, the other end then doesn't
+    /// see the end of the stream.
+    pub fn send_stream<I>(&mut self, items: I) -> Result<StreamStatus, ConnectionError>
+    where
+        I: IntoIterator<Item = T>,
+    {
+        self.send_stream_fallible(items.into_iter().map(Ok::<T, std::convert::Infallible>))
+    }
+
+    /// Like [`Connection::send_stream`], but the producer can fail part of the way through.
+    ///
+    /// An error ends the stream, the other end gets a [`ConnectionError::StreamFailed`] with the
+    /// error message.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`Connection::send_stream`], an error of the producer is not one of ours.
+    pub fn send_stream_fallible<I, E>(&mut self, items: I) -> Result<StreamStatus, ConnectionError>
+    where
+        I: IntoIterator<Item = Result<T, E>>,
+        E: Display,
+    {
+        for item in items {
+            if self.raw.cancel_received(true)? {
+                // The other end reads until the end of the stream, even when cancelling
+                self.raw.write_frame(FrameKind::StreamEnd, &[])?;
+                return Ok(StreamStatus::Cancelled);
+            }
+            match item {
+                Ok(item) => self.send_message(FrameKind::StreamItem, &item)?,
+                Err(e) => {
+                    self.raw
+                        .write_frame(FrameKind::StreamError, e.to_string().as_bytes())?;
+                    return Ok(StreamStatus::Failed);
+                }
+            }
+        }
+        self.raw.write_frame(FrameKind::StreamEnd, &[])?;
+        Ok(StreamStatus::Completed)
+    }
+
+    /// Receive a response that was sent with [`Connection::send_stream`].
+    ///
+    /// The returned iterator yields items until the stream ends. Dropping it early tells the other
+    /// end to stop producing items.
+    pub const fn receive_stream(&mut self) -> ResponseStream<'_, T, R> {
+        ResponseStream::new(self)
+    }
+
+    /// Send a message that was already serialized
+    pub(crate) fn send_encoded(&self, bytes: &[u8]) -> Result<(), ConnectionError> {
+        self.raw.write_frame(FrameKind::Message, bytes)
+    }
+
+    /// Serialize a message and send it as a frame of the given kind
+    fn send_message<M>(&self, kind: FrameKind, message: &M) -> Result<(), ConnectionError>
+    where
+        M: Serialize,
+    {
+        let bytes = bitcode::serialize(message).map_err(ConnectionError::SerilizationFailed)?;
+        let operation = Operation::start(&self.raw.span, "send", type_name::<M>());
+        operation.bytes(bytes.len());
+        operation.finish(self.raw.write_frame(kind, &bytes))
+    }
+
+    /// Deserialize the payload of a frame
+    pub(crate) fn decode(&self, data: &[u8]) -> Result<R, ConnectionError> {
+        self.raw.decode(data)
+    }
+
+    /// Write a single frame to the connection
+    pub(crate) fn write_frame(&self, kind: FrameKind, data: &[u8]) -> Result<(), ConnectionError> {
+        self.raw.write_frame(kind, data)
+    }
+
+    /// Read a single frame from the connection
+    pub(crate) fn read_frame(&self) -> Result<Frame, ConnectionError> {
+        self.raw.read_frame()
+    }
+
+    /// Let the client in once its token was checked, see [`RawConnection::authorize`]
+    pub(crate) fn authorize(&self) -> Result<(), ConnectionError> {
+        self.raw.authorize()
+    }
+
+    /// Read a frame if it has fully arrived, see [`RawConnection::try_read_frame`]
+    #[cfg(all(unix, feature = "event-loop"))]
+    pub(crate) fn try_read_frame(&self) -> Result<Option<Frame>, ConnectionError> {
+        self.raw.try_read_frame()
+    }
+
+    /// See [`RawConnection::take_skipped_read`]
+    #[cfg(all(unix, feature = "event-loop"))]
+    pub(crate) fn take_skipped_read(&self) -> bool {
+        self.raw.take_skipped_read()
+    }
+
+    /// Give up the typed wrapper, used to hand the connection over to a multiplexer
+    pub(crate) fn into_raw(self) -> Arc<RawConnection> {
+        self.raw
+    }
+}
+
+/// The socket of a connection, polled for readiness.
+///
+/// Only reading from it is meant to become ready, writes block until the other end took the
+/// frame.
+#[cfg(unix)]
+impl<T, R> AsFd for Connection<T, R>
+where
+    T: Serialize,
+    R: for<'de> Deserialize<'de>,
+{
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        // SAFETY: The file descriptor is owned by the reader, which lives as long as we do.
+        unsafe { BorrowedFd::borrow_raw(self.raw.fd) }
+    }
+}
+
+#[cfg(unix)]
+impl<T, R> AsRawFd for Connection<T, R>
+where
+    T: Serialize,
+    R: for<'de> Deserialize<'de>,
+{
+    fn as_raw_fd(&self) -> RawFd {
+        self.raw.fd
+    }
+}
+
+/// The reading half of a connection, along with what [`RawConnection::try_read_frame`] read
+/// ahead. Reads take from what was read ahead first.
+#[derive(Debug)]
+pub(crate) struct Reader {
+    ahead: VecDeque<u8>,
+    stream: Stream,
+}
+
+impl Read for Reader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.ahead.is_empty() {
+            self.stream.read(buf)
+        } else {
+            self.ahead.read(buf)
+        }
+    }
+}
+
+/// The writing half of a connection, along with buffers that are reused between frames
+#[derive(Debug)]
+struct Writer {
+    stream: Stream,
+    /// Headers of the frames being written
+    headers: Vec<u8>,
+    /// The encrypted payload of the frame being written
+    sealed: Vec<u8>,
+    /// Frames are held back until uncorked, see [`Connection::cork`]
+    corked: bool,
+    /// The first `held_count` frames are waiting to be written, the others keep their buffers
+    /// for the next ones
+    held: Vec<HeldFrame>,
+    held_count: usize,
+}
+
+impl Writer {
+    const fn new(stream: Stream) -> Self {
+        Self {
+            stream,
+            headers: Vec::new(),
+            sealed: Vec::new(),
+            corked: false,
+            held: Vec::new(),
+            held_count: 0,
+        }
+    }
+}
+
+/// A frame waiting to be written along with others, its header is in [`Writer::headers`]
+#[derive(Debug)]
+struct HeldFrame {
+    kind: FrameKind,
+    channel: u32,
+    /// The payload as it goes on the wire
+    payload: Vec<u8>,
+    /// The payload before it was compressed and sealed, only kept if frames are captured
+    plain: Vec<u8>,
+    /// When it was sent, for the metrics
+    start: Instant,
+}
+
+/// Frames the connection writes for itself, like handshakes, go straight to the stream
+impl Deref for Writer {
+    type Target = Stream;
+
+    fn deref(&self) -> &Stream {
+        &self.stream
+    }
+}
+
+impl DerefMut for Writer {
+    fn deref_mut(&mut self) -> &mut Stream {
+        &mut self.stream
+    }
+}
+
+/// The untyped part of a connection that reads and writes frames.
+///
+/// Reading and writing are locked separately so that frames can be written from other threads,
+/// like a cancel frame from a [`CancelHandle`], while a thread is blocked reading.
+#[derive(Debug)]
+pub(crate) struct RawConnection {
+    reader: Mutex<BufReader<Reader>>,
+    writer: Mutex<Writer>,
+    opts: Arc<OptionsRaw>,
+    liveness: Liveness,
+    /// Compression algorithms the other end can decompress, see [`compression::supported`]
+    peer_decompresses: AtomicU8,
+    /// A read without waiting gave up because another thread was reading, see
+    /// [`RawConnection::take_skipped_read`]
+    skipped_read: AtomicBool,
+    /// Cleared while a server waits for the token of the client. Until then frames are short and
+    /// none are handled by the connection itself.
+    authorized: AtomicBool,
+    /// Parent of the spans of everything happening on the connection
+    span: Span,
+    metrics: ConnectionCounters,
+    /// Set if frames are captured, see [`crate::capture`]
+    capture: Option<CaptureConnection>,
+    /// Set once the Noise handshake is done, encrypts every payload after it
+    #[cfg(feature = "noise")]
+    cipher: OnceLock<Cipher>,
+    /// Used to shut the socket down without taking any locks
+    #[cfg(unix)]
+    fd: RawFd,
+}
+
+impl RawConnection {
+    #[cfg_attr(not(feature = "noise"), allow(unused_variables))]
+    fn new(
+        stream: Stream,
+        opts: Arc<OptionsRaw>,
+        side: Side,
+        metrics: ConnectionCounters,
+        token: Option<&[u8]>,
+    ) -> Result<Arc<Self>, std::io::Error> {
+        let writer = stream.try_clone()?;
+        #[cfg(unix)]
+        let fd = {
+            let Stream::UdSocket(socket) = &stream;
+            socket.as_fd().as_raw_fd()
+        };
+        let heartbeat = opts.heartbeat;
+        let authorized = side == Side::Client || opts.token_file.is_none();
+        let span = trace::connection(side, &stream);
+        let capture = opts.capture.as_ref().map(|file| file.connection(side));
+        let raw = Arc::new(Self {
+            reader: Mutex::new(BufReader::new(Reader {
+                ahead: VecDeque::new(),
+                stream,
+            })),
+            writer: Mutex::new(Writer::new(writer)),
+            opts,
+            liveness: Liveness::new(),
+            peer_decompresses: AtomicU8::new(0),
+            skipped_read: AtomicBool::new(false),
+            authorized: AtomicBool::new(authorized),
+            span,
+            metrics,
+            capture,
+            #[cfg(feature = "noise")]
+            cipher: OnceLock::new(),
+            #[cfg(unix)]
+            fd,
+        });
+        // A client that stops talking while setting up must not keep the server waiting
+        if side == Side::Server {
+            raw.set_read_timeout(Some(raw.opts.auth_timeout))?;
+        }
+        #[cfg(feature = "noise")]
+        if let Some(keypair) = &raw.opts.noise.keypair {
+            let cipher = noise::handshake(
+                &raw.opts.noise,
+                keypair,
+                side,
+                |message| {
+                    let packet =
+                        raw.make_packet(FrameKind::Handshake, false, MAIN_CHANNEL, message);
+                    raw.writer().write_all(&packet)
+                },
+                || {
+                    let frame = raw.read_any_frame(&mut raw.reader());
+                    match frame {
+                        Ok(frame) if frame.kind == FrameKind::Handshake => Ok(frame.data),
+                        Ok(_) => Err(std::io::Error::new(
+                            ErrorKind::InvalidData,
+                            "expected a handshake message",
+                        )),
+                        Err(e) => Err(into_io_error(e)),
+                    }
+                },
+            )?;
+            raw.cipher.get_or_init(|| cipher);
+        }
+        if authorized {
+            raw.set_read_timeout(None)?;
+        }
+        if let Some(token) = token {
+            // The server doesn't look at anything else before the token
+            let mut writer = raw.writer();
+            let packet = raw
+                .sealed_packet(FrameKind::Token, MAIN_CHANNEL, token)
+                .map_err(into_io_error)?;
+            writer.write_all(&packet)?;
+            drop(writer);
+        }
+        if raw.opts.compression != Compression::None {
+            // Tell the other end what we understand, it only compresses once it knows
+            let capabilities = [compression::supported()];
+            let mut writer = raw.writer();
+            let packet = raw
+                .sealed_packet(FrameKind::Capabilities, MAIN_CHANNEL, &capabilities)
+                .map_err(into_io_error)?;
+            writer.write_all(&packet)?;
+            drop(writer);
+        }
+        if let Some((interval, missed_limit)) = heartbeat {
+            heartbeat::start(&raw, interval, missed_limit)?;
+        }
+        Ok(raw)
+    }
+
+    fn reader(&self) -> MutexGuard<'_, BufReader<Reader>> {
+        // A panic while holding the lock can't leave the stream in a worse state than an IO error
+        self.reader.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Lock the reading half, or `None` if another thread is currently reading
+    pub(crate) fn try_reader(&self) -> Option<MutexGuard<'_, BufReader<Reader>>> {
+        match self.reader.try_lock() {
+            Ok(reader) => Some(reader),
+            Err(TryLockError::Poisoned(e)) => Some(e.into_inner()),
+            Err(TryLockError::WouldBlock) => None,
+        }
+    }
+
+    pub(crate) fn options(&self) -> &OptionsRaw {
+        &self.opts
+    }
+
+    pub(crate) const fn liveness(&self) -> &Liveness {
+        &self.liveness
+    }
+
+    fn writer(&self) -> MutexGuard<'_, Writer> {
+        self.writer.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Lock the writing half, or `None` if another thread is currently writing
+    fn try_writer(&self) -> Option<MutexGuard<'_, Writer>> {
+        match self.writer.try_lock() {
+            Ok(writer) => Some(writer),
+            Err(TryLockError::Poisoned(e)) => Some(e.into_inner()),
+            Err(TryLockError::WouldBlock) => None,
+        }
+    }
+
+    /// Write a serialized message of type `type_name` to the main channel
+    pub(crate) fn write_message(
+        &self,
+        type_name: &'static str,
+        bytes: &[u8],
+    ) -> Result<(), ConnectionError> {
+        let operation = Operation::start(&self.span, "send", type_name);
+        operation.bytes(bytes.len());
+        operation.finish(self.write_frame(FrameKind::Message, bytes))
+    }
+
+    /// Write a single frame to the main channel of the connection
+    pub(crate) fn write_frame(&self, kind: FrameKind, data: &[u8]) -> Result<(), ConnectionError> {
+        self.write_channel_frame(kind, MAIN_CHANNEL, data)
+    }
+
+    /// Write a single frame to the given channel
+    pub(crate) fn write_channel_frame(
+        &self,
+        kind: FrameKind,
+        channel: u32,
+        data: &[u8],
+    ) -> Result<(), ConnectionError> {
+        let start = Instant::now();
+        let compressed = self.compress(data)?;
+        let payload = compressed.as_deref().unwrap_or(data);
+        let mut writer = self.writer();
+        if writer.corked {
+            return self.hold(
+                &mut writer,
+                kind,
+                channel,
+                data,
+                compressed.as_deref(),
+                start,
+            );
+        }
+        let Writer {
+            stream,
+            headers,
+            sealed,
+            ..
+        } = &mut *writer;
+        // Encrypted with the writer locked, the other end decrypts in the order we write
+        let sealed = self.seal(kind, compressed.is_some(), channel, payload, sealed)?;
+        headers.clear();
+        self.push_header(headers, kind, compressed.is_some(), channel, sealed);
+        let written =
+            write_all_vectored(stream, &mut [IoSlice::new(headers), IoSlice::new(sealed)]);
+        let sealed_len = sealed.len();
+        drop(writer);
+        written.map_err(|e| self.peer_error(ConnectionError::WriteFailed(e)))?;
+        self.sent(kind, sealed_len, start);
+        self.captured(kind, channel, data);
+        Ok(())
+    }
+
+    /// Write several frames with as few writes as possible
+    pub(crate) fn write_frames(
+        &self,
+        kind: FrameKind,
+        channel: u32,
+        payloads: &[Vec<u8>],
+    ) -> Result<(), ConnectionError> {
+        let start = Instant::now();
+        let compressed = payloads
+            .iter()
+            .map(|data| self.compress(data))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut writer = self.writer();
+        let held = payloads
+            .iter()
+            .zip(&compressed)
+            .try_for_each(|(data, compressed)| {
+                self.hold(
+                    &mut writer,
+                    kind,
+                    channel,
+                    data,
+                    compressed.as_deref(),
+                    start,
+                )
+            });
+        let written = match held {
+            Ok(()) if writer.corked => Ok(()),
+            Ok(()) => self.flush(&mut writer),
+            // Don't leave half of the batch behind for the next one
+            Err(e) if !writer.corked => {
+                writer.held_count = 0;
+                Err(e)
+            }
+            Err(e) => Err(e),
+        };
+        drop(writer);
+        written
+    }
+
+    /// Seal a frame into the next held frame of `writer`, to be written by
+    /// [`RawConnection::flush`]. `compressed` is `data` once compressed, if it was.
+    fn hold(
+        &self,
+        writer: &mut Writer,
+        kind: FrameKind,
+        channel: u32,
+        data: &[u8],
+        compressed: Option<&[u8]>,
+        start: Instant,
+    ) -> Result<(), ConnectionError> {
+        let Writer {
+            headers,
+            held,
+            held_count,
+            ..
+        } = writer;
+        if *held_count == 0 {
+            headers.clear();
+        }
+        if *held_count == held.len() {
+            held.push(HeldFrame {
+                kind,
+                channel,
+                payload: Vec::new(),
+                plain: Vec::new(),
+                start,
+            });
+        }
+        let frame = &mut held[*held_count];
+        frame.kind = kind;
+        frame.channel = channel;
+        frame.start = start;
+        let payload = compressed.unwrap_or(data);
+        self.seal_into(
+            kind,
+            compressed.is_some(),
+            channel,
+            payload,
+            &mut frame.payload,
+        )?;
+        self.push_header(headers, kind, compressed.is_some(), channel, &frame.payload);
+        frame.plain.clear();
+        if self.capture.is_some() {
+            frame.plain.extend_from_slice(data);
+        }
+        *held_count += 1;
+        Ok(())
+    }
+
+    /// Write the frames held by `writer` with as few writes as possible, they are counted as
+    /// sent and captured once written
+    fn flush(&self, writer: &mut Writer) -> Result<(), ConnectionError> {
+        let Writer {
+            stream,
+            headers,
+            held,
+            held_count,
+            ..
+        } = writer;
+        let frames = &held[..*held_count];
+        let mut slices: Vec<_> = headers
+            .chunks(self.header_length())
+            .zip(frames)
+            .flat_map(|(header, frame)| [IoSlice::new(header), IoSlice::new(&frame.payload)])
+            .collect();
+        let written = write_all_vectored(stream, &mut slices);
+        if written.is_ok() {
+            for frame in frames {
+                self.sent(frame.kind, frame.payload.len(), frame.start);
+                self.captured(frame.kind, frame.channel, &frame.plain);
+            }
+        }
+        *held_count = 0;
+        written.map_err(|e| self.peer_error(ConnectionError::WriteFailed(e)))
+    }
+
+    /// Hold frames back until [`RawConnection::uncork`]
+    pub(crate) fn cork(&self) {
+        self.writer().corked = true;
+    }
+
+    /// Write the frames held back since [`RawConnection::cork`] in one go and stop holding them
+    /// back
+    pub(crate) fn uncork(&self) -> Result<(), ConnectionError> {
+        let mut writer = self.writer();
+        writer.corked = false;
+        let written = self.flush(&mut writer);
+        drop(writer);
+        written
+    }
+
+    /// Count a frame that was written, `sealed_len` is the length of its payload on the wire
+    fn sent(&self, kind: FrameKind, sealed_len: usize, start: Instant) {
+        if kind.is_message() {
+            self.metrics
+                .sent(self.header_length() + sealed_len, start.elapsed());
+        }
+    }
+
+    /// Capture a frame that was sent, before it was compressed and sealed
+    fn captured(&self, kind: FrameKind, channel: u32, data: &[u8]) {
+        if let Some(capture) = &self.capture {
+            capture.sent(kind, channel, data);
+        }
+    }
+
+    /// Deserialize the payload of a message frame
+    pub(crate) fn decode<'a, M>(&self, data: &'a [u8]) -> Result<M, ConnectionError>
+    where
+        M: Deserialize<'a>,
+    {
+        bitcode::deserialize(data).map_err(|e| {
+            self.metrics.decode_error();
+            ConnectionError::DeserilizationFailed(e)
+        })
+    }
+
+    /// Compress the payload of a frame if it is worth it and the other end supports it
+    fn compress(&self, data: &[u8]) -> Result<Option<Vec<u8>>, ConnectionError> {
+        let compression = self.opts.compression;
+        if compression == Compression::None || data.len() < self.opts.compression_threshold {
+            return Ok(None);
+        }
+        let peer_decompresses = || self.peer_decompresses.load(Ordering::Relaxed);
+        if compression.bit() & peer_decompresses() == 0 {
+            // The other end may have told us since we last read, like when we only send
+            if let Some(mut reader) = self.try_reader() {
+                self.peek_header(&mut reader, false)?;
+            }
+        }
+        if compression.bit() & peer_decompresses() == 0 {
+            return Ok(None);
+        }
+        let compressed = compression
+            .compress(data)
+            .map_err(ConnectionError::CompressionFailed)?;
+        // Some data doesn't compress, no point in making the other end decompress it
+        Ok((compressed.len() < data.len()).then_some(compressed))
+    }
+
+    /// Encrypt the payload of a frame if the connection is encrypted, must be called with the
+    /// writer locked. The kind, flags and channel of the frame are authenticated along with it.
+    #[cfg_attr(
+        not(feature = "noise"),
+        allow(
+            clippy::unnecessary_wraps,
+            clippy::unused_self,
+            clippy::missing_const_for_fn,
+            clippy::ptr_arg,
+            clippy::needless_pass_by_ref_mut,
+            unused_variables
+        )
+    )]
+    fn seal<'a>(
+        &self,
+        kind: FrameKind,
+        compressed: bool,
+        channel: u32,
+        data: &'a [u8],
+        buffer: &'a mut Vec<u8>,
+    ) -> Result<&'a [u8], ConnectionError> {
+        #[cfg(feature = "noise")]
+        if self.cipher.get().is_some() {
+            self.seal_into(kind, compressed, channel, data, buffer)?;
+            return Ok(buffer);
+        }
+        Ok(data)
+    }
+
+    /// Like [`RawConnection::seal`], but always leaves the payload in `out`
+    #[cfg_attr(
+        not(feature = "noise"),
+        allow(clippy::unnecessary_wraps, clippy::unused_self, unused_variables)
+    )]
+    fn seal_into(
+        &self,
+        kind: FrameKind,
+        compressed: bool,
+        channel: u32,
+        data: &[u8],
+        out: &mut Vec<u8>,
+    ) -> Result<(), ConnectionError> {
+        #[cfg(feature = "noise")]
+        if let Some(cipher) = self.cipher.get() {
+            return cipher
+                .encrypt(kind_byte(kind, compressed), channel, data, out)
+                .map_err(|e| ConnectionError::WriteFailed(std::io::Error::other(e)));
+        }
+        out.clear();
+        out.extend_from_slice(data);
+        Ok(())
+    }
+
+    /// Seal a frame and put its header in front, must be called with the writer locked
+    fn sealed_packet(
+        &self,
+        kind: FrameKind,
+        channel: u32,
+        data: &[u8],
+    ) -> Result<Vec<u8>, ConnectionError> {
+        let mut buffer = Vec::new();
+        let sealed = self.seal(kind, false, channel, data, &mut buffer)?;
+        Ok(self.make_packet(kind, false, channel, sealed))
+    }
+
+    /// Whether a frame is longer than the other end may send, which is short for a client that
+    /// isn't authorized yet
+    fn too_long(&self, header: &Header) -> bool {
+        if !self.is_authorized() {
+            return header.len > token::MAX_FRAME_LEN;
+        }
+        #[cfg(feature = "noise")]
+        if self.cipher.get().is_some() {
+            return header.len > noise::sealed_len(self.opts.max_message_size);
+        }
+        header.len > self.opts.max_message_size
+    }
+
+    /// Check, decrypt and decompress the payload of a frame, must be called with the reader
+    /// locked
+    ///
+    /// Plain payloads stay where they are, so `data` keeps its allocation.
+    fn open(&self, header: &Header, data: &mut Vec<u8>) -> Result<(), ConnectionError> {
+        if !self.payload_matches(header, data) {
+            return Err(ConnectionError::ChecksumMismatch);
+        }
+        // Every frame after the handshake is sealed, frames that aren't were not sent by the
+        // other end
+        #[cfg(feature = "noise")]
+        if let Some(cipher) = self.cipher.get() {
+            *data = cipher
+                .decrypt(
+                    kind_byte(header.kind, header.compressed),
+                    header.channel,
+                    data,
+                )
+                .map_err(|_| ConnectionError::DecryptionFailed)?;
+        }
+        if header.compressed {
+            *data = compression::decompress(data, self.opts.max_message_size)
+                .map_err(ConnectionError::CompressionFailed)?;
+        }
+        Ok(())
+    }
+
+    /// Read a single frame into `buffer`, see [`RawConnection::read_frame_into`]
+    pub(crate) fn read_frame_reusing(
+        &self,
+        buffer: &mut Vec<u8>,
+    ) -> Result<(FrameKind, u32), ConnectionError> {
+        let mut reader = self.reader();
+        let read = self.read_frame_into(&mut reader, buffer);
+        drop(reader);
+        read
+    }
+
+    /// Read a single frame from the connection
+    pub(crate) fn read_frame(&self) -> Result<Frame, ConnectionError> {
+        let mut reader = self.reader();
+        let frame = self.read_frame_from(&mut reader);
+        drop(reader);
+        frame
+    }
+
+    /// Read a single frame with the reading half already locked.
+    ///
+    /// Frames that are only meant for the connection itself, like heartbeats, are handled and
+    /// skipped.
+    pub(crate) fn read_frame_from(
+        &self,
+        reader: &mut BufReader<Reader>,
+    ) -> Result<Frame, ConnectionError> {
+        let mut data = Vec::new();
+        let (kind, channel) = self.read_frame_into(reader, &mut data)?;
+        Ok(Frame {
+            kind,
+            channel,
+            data,
+        })
+    }
+
+    /// Read a single frame into `buffer`, reusing its allocation, and return its kind and
+    /// channel. See [`RawConnection::read_frame_from`].
+    pub(crate) fn read_frame_into(
+        &self,
+        reader: &mut BufReader<Reader>,
+        buffer: &mut Vec<u8>,
+    ) -> Result<(FrameKind, u32), ConnectionError> {
+        if self.liveness.is_unresponsive() {
+            return Err(ConnectionError::PeerUnresponsive);
+        }
+        self.liveness.start_reading();
+        let read = loop {
+            let (kind, channel) = match self.read_any_frame_into(reader, buffer) {
+                Ok(read) => read,
+                Err(e) => break Err(e),
+            };
+            let frame = Frame {
+                kind,
+                channel,
+                data: std::mem::take(buffer),
+            };
+            let handled = self.handle_control(&frame);
+            *buffer = frame.data;
+            if !handled {
+                break Ok((kind, channel));
+            }
+        };
+        self.liveness.stop_reading();
+        read.map_err(|e| self.peer_error(e))
+    }
+
+    fn read_any_frame(&self, reader: &mut BufReader<Reader>) -> Result<Frame, ConnectionError> {
+        let mut data = Vec::new();
+        let (kind, channel) = self.read_any_frame_into(reader, &mut data)?;
+        Ok(Frame {
+            kind,
+            channel,
+            data,
+        })
+    }
+
+    fn read_any_frame_into(
+        &self,
+        reader: &mut BufReader<Reader>,
+        data: &mut Vec<u8>,
+    ) -> Result<(FrameKind, u32), ConnectionError> {
         let header_len = self.header_length();
         let mut header = vec![0; header_len];
-        let nread = self
-            .connection
-            .read(&mut header)
+        read_exact(reader, &mut header)?;
+        let header = self.parse_header(&header).map_err(ConnectionError::from)?;
+        if self.too_long(&header) {
+            return Err(if self.is_authorized() {
+                ConnectionError::PacketTooLarge
+            } else {
+                ConnectionError::Unauthorized
+            });
+        }
+
+        let start = Instant::now();
+        data.clear();
+        data.resize(header.len, 0);
+        read_exact(reader, data)?;
+        self.liveness.seen();
+        self.open(&header, data).inspect_err(|_| {
+            if header.kind.is_message() {
+                self.metrics.decode_error();
+            }
+        })?;
+        if header.kind.is_message() {
+            self.metrics
+                .received(header_len + header.len, start.elapsed());
+        }
+        if let Some(capture) = &self.capture {
+            capture.received(header.kind, header.channel, data);
+        }
+        Ok((header.kind, header.channel))
+    }
+
+    /// Read a frame if it has fully arrived, without waiting for it.
+    ///
+    /// What has arrived of the frame so far is kept for the next read. Frames that are only meant
+    /// for the connection itself are handled and skipped. Returns `None` as well if another thread
+    /// is reading.
+    pub(crate) fn try_read_frame(&self) -> Result<Option<Frame>, ConnectionError> {
+        let Some(mut reader) = self.try_reader() else {
+            self.skipped_read.store(true, Ordering::Relaxed);
+            return Ok(None);
+        };
+        if self.liveness.is_unresponsive() {
+            return Err(ConnectionError::PeerUnresponsive);
+        }
+        loop {
+            if !self.frame_arrived(&mut reader)? {
+                return Ok(None);
+            }
+            let frame = self
+                .read_any_frame(&mut reader)
+                .map_err(|e| self.peer_error(e))?;
+            if !self.handle_control(&frame) {
+                return Ok(Some(frame));
+            }
+        }
+    }
+
+    /// Whether a read without waiting gave up since the last call because another thread was
+    /// reading.
+    ///
+    /// That thread may have taken frames off the socket without handling them, like the heartbeat
+    /// does, so waiting for the socket to become readable again could wait for nothing.
+    #[cfg(all(unix, feature = "event-loop"))]
+    pub(crate) fn take_skipped_read(&self) -> bool {
+        self.skipped_read.swap(false, Ordering::Relaxed)
+    }
+
+    /// Read everything that has arrived without blocking and check if the next frame is complete
+    fn frame_arrived(&self, reader: &mut BufReader<Reader>) -> Result<bool, ConnectionError> {
+        // Keep what was read ahead in one place, so the header can be looked at as a whole
+        let buffered = reader.buffer().to_vec();
+        reader.consume(buffered.len());
+        let reader = reader.get_mut();
+        let mut ahead = VecDeque::from(buffered);
+        ahead.append(&mut reader.ahead);
+        reader.ahead = ahead;
+
+        // Non-blocking mode is shared with the writer, see `peek_header`
+        let writer = self.writer();
+        writer
+            .set_nonblocking(true)
+            .map_err(ConnectionError::ReadFailed)?;
+        let mut chunk = [0; 8192];
+        let closed = loop {
+            match reader.stream.read(&mut chunk) {
+                Ok(0) => break true,
+                Ok(read) => reader.ahead.extend(&chunk[..read]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break false,
+                Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => {
+                    writer.set_nonblocking(false).ok();
+                    return Err(ConnectionError::ReadFailed(e));
+                }
+            }
+        };
+        writer
+            .set_nonblocking(false)
             .map_err(ConnectionError::ReadFailed)?;
+        drop(writer);
 
-        if nread != self.header_length() {
-            // TODO: This usually gets hit when the server closes and a client tries to read from it. Maybe check for 0 and report a different error?
-            return Err(ConnectionError::UnexepctedEof);
+        let header_len = self.header_length();
+        let ahead = reader.ahead.make_contiguous();
+        let arrived = ahead.len() >= header_len
+            && self
+                .parse_header(&ahead[..header_len])
+                // Bad headers are reported by reading the frame
+                .ok()
+                .is_none_or(|header| {
+                    ahead.len() >= header_len + header.len || self.too_long(&header)
+                });
+        if !arrived && closed {
+            return Err(self.peer_error(ConnectionError::UnexepctedEof));
         }
-        let data_len = self.parse_header(&header).map_err(|e| match e {
-            ParseHeaderError::NotEnoughBytes => ConnectionError::UnexepctedEof,
-            ParseHeaderError::PacketTooLarge => ConnectionError::PacketTooLarge,
-            ParseHeaderError::MagicBytesMissing => ConnectionError::HeaderMismatch,
-        })?;
+        Ok(arrived)
+    }
 
-        let mut data = vec![0; data_len];
-        let nread = self
-            .connection
-            .read(&mut data)
+    /// Handle frames meant for the connection itself, returns `false` for any other frame and
+    /// for every frame of a client that isn't authorized yet
+    fn handle_control(&self, frame: &Frame) -> bool {
+        if !self.is_authorized() {
+            return false;
+        }
+        match frame.kind {
+            FrameKind::Heartbeat => true,
+            FrameKind::Ping => {
+                // Any error shows up on the next read or write as well
+                if let Ok(info) = bitcode::serialize(&self.info()) {
+                    self.write_frame(FrameKind::Pong, &info).ok();
+                }
+                true
+            }
+            FrameKind::Capabilities => {
+                let decompresses = frame.data.first().copied().unwrap_or_default();
+                self.peer_decompresses
+                    .store(decompresses, Ordering::Relaxed);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// How we describe ourselves when pinged
+    fn info(&self) -> PeerInfo {
+        #[cfg(feature = "noise")]
+        let encrypted = self.cipher.get().is_some();
+        #[cfg(not(feature = "noise"))]
+        let encrypted = false;
+        PeerInfo {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            compression: format!("{:?}", self.opts.compression),
+            checksum: format!("{:?}", self.opts.checksum),
+            encrypted,
+            token_auth: self.opts.token_file.is_some(),
+            heartbeat: self.opts.heartbeat.map(|(interval, _)| interval),
+            round_trip: Duration::ZERO,
+        }
+    }
+
+    /// Errors caused by the heartbeat shutting the connection down are reported as such
+    fn peer_error(&self, error: ConnectionError) -> ConnectionError {
+        if self.liveness.is_unresponsive() {
+            ConnectionError::PeerUnresponsive
+        } else {
+            error
+        }
+    }
+
+    /// Checks, without blocking, if the other end sent a cancel frame.
+    ///
+    /// Anything else that is waiting to be read is left alone so that it can be received normally
+    /// later. If `wait` is false and another thread is reading, we don't wait for it and report
+    /// no cancellation.
+    pub(crate) fn cancel_received(&self, wait: bool) -> Result<bool, ConnectionError> {
+        let mut reader = if wait {
+            self.reader()
+        } else {
+            match self.try_reader() {
+                Some(reader) => reader,
+                None => return Ok(false),
+            }
+        };
+
+        let Some(header) = self.peek_header(&mut reader, wait)? else {
+            return Ok(false);
+        };
+        let frame_len = self.header_length() + header.len;
+        // Encrypted cancels carry a tag, wait for all of it to check the frame is genuine
+        if header.kind != FrameKind::Cancel
+            || header.channel != MAIN_CHANNEL
+            || reader.buffer().len() < frame_len
+        {
+            return Ok(false);
+        }
+        let mut data = reader.buffer()[self.header_length()..frame_len].to_vec();
+        self.open(&header, &mut data)?;
+        reader.consume(frame_len);
+        drop(reader);
+        Ok(true)
+    }
+
+    /// Send a heartbeat to the other end.
+    ///
+    /// Also handles heartbeats of the other end that piled up while nobody was reading. This
+    /// never waits on other threads, if the connection is busy it is alive anyway. Nothing is sent
+    /// while corked, see [`Connection::cork`].
+    pub(crate) fn heartbeat(&self) -> Result<(), ConnectionError> {
+        if let Some(mut reader) = self.try_reader() {
+            self.peek_header(&mut reader, false)?;
+        }
+        let Some(mut writer) = self.try_writer() else {
+            return Ok(());
+        };
+        // Held frames were sealed already, a heartbeat can't overtake them
+        if writer.corked || writer.held_count > 0 {
+            return Ok(());
+        }
+        let packet = self.sealed_packet(FrameKind::Heartbeat, MAIN_CHANNEL, &[])?;
+        writer
+            .write_all(&packet)
+            .map_err(ConnectionError::WriteFailed)
+    }
+
+    /// Let the client in once its token was checked, see [`RawConnection::authorized`]
+    pub(crate) fn authorize(&self) -> Result<(), ConnectionError> {
+        self.set_read_timeout(None)
             .map_err(ConnectionError::ReadFailed)?;
-        if nread != data_len {
-            return Err(ConnectionError::UnexepctedEof);
+        self.authorized.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn is_authorized(&self) -> bool {
+        self.authorized.load(Ordering::Relaxed)
+    }
+
+    /// Make blocking reads fail after `timeout`, or wait forever if `None`.
+    ///
+    /// Only supported on unix, on other platforms reads keep waiting.
+    #[cfg_attr(not(unix), allow(clippy::unused_self, clippy::unnecessary_wraps))]
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), std::io::Error> {
+        #[cfg(unix)]
+        {
+            let timeout = timeout.unwrap_or_default();
+            let timeval = libc::timeval {
+                tv_sec: libc::time_t::try_from(timeout.as_secs()).unwrap_or(libc::time_t::MAX),
+                tv_usec: libc::suseconds_t::from(timeout.subsec_micros().cast_signed()),
+            };
+            let len = libc::socklen_t::try_from(size_of::<libc::timeval>())
+                .map_err(std::io::Error::other)?;
+            // SAFETY: The file descriptor is owned by the reader, which lives as long as we do,
+            // and the option is read from a valid timeval of the given size.
+            let result = unsafe {
+                libc::setsockopt(
+                    self.fd,
+                    libc::SOL_SOCKET,
+                    libc::SO_RCVTIMEO,
+                    (&raw const timeval).cast(),
+                    len,
+                )
+            };
+            if result != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
         }
-        bitcode::deserialize(&data).map_err(ConnectionError::DeserilizationFailed)
+        Ok(())
     }
 
-    fn make_packet(&self, data: Vec<u8>) -> Vec<u8> {
-        let mut header = self.gen_header(&data);
-        let mut packet = data;
-        header.append(&mut packet);
-        header
+    /// Shut the connection down, which makes blocked reads and writes return.
+    ///
+    /// Only supported on unix, on other platforms blocked calls keep waiting.
+    pub(crate) fn shutdown(&self) {
+        #[cfg(unix)]
+        // SAFETY: The file descriptor is owned by the reader, which lives as long as we do.
+        unsafe {
+            libc::shutdown(self.fd, libc::SHUT_RDWR);
+        }
     }
 
-    fn gen_header(&self, data: &[u8]) -> Vec<u8> {
-        let mut res = self.opts.magic_bytes.to_vec();
+    /// Get the header of the next frame if it is fully buffered, without blocking.
+    ///
+    /// Frames for the connection itself in front of it are handled and thrown away. If `wait` is
+    /// false and another thread is writing, only what's already in the buffer is looked at.
+    fn peek_header(
+        &self,
+        reader: &mut BufReader<Reader>,
+        wait: bool,
+    ) -> Result<Option<Header>, ConnectionError> {
+        let header_len = self.header_length();
+        loop {
+            if reader.buffer().is_empty() {
+                // Non-blocking mode is shared with the writer, so hold its lock while we peek to
+                // make sure no writes see it.
+                let writer = if wait {
+                    self.writer()
+                } else {
+                    match self.try_writer() {
+                        Some(writer) => writer,
+                        None => return Ok(None),
+                    }
+                };
+                writer
+                    .set_nonblocking(true)
+                    .map_err(ConnectionError::ReadFailed)?;
+                let available = reader.fill_buf().map(<[u8]>::len);
+                writer
+                    .set_nonblocking(false)
+                    .map_err(ConnectionError::ReadFailed)?;
+                drop(writer);
+                match available {
+                    Ok(_) => (),
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(None),
+                    Err(e) => return Err(ConnectionError::ReadFailed(e)),
+                }
+            }
+
+            let buffer = reader.buffer();
+            if buffer.len() < header_len {
+                // Either nothing to read or a partial frame, check again later
+                return Ok(None);
+            }
+            // Bad headers are reported by the next regular read
+            let Ok(header) = self.parse_header(&buffer[..header_len]) else {
+                return Ok(None);
+            };
+            let frame_len = header_len + header.len;
+            let control = self.is_authorized()
+                && matches!(
+                    header.kind,
+                    FrameKind::Heartbeat | FrameKind::Capabilities | FrameKind::Ping
+                );
+            if !control || buffer.len() < frame_len {
+                return Ok(Some(header));
+            }
+            let mut data = buffer[header_len..frame_len].to_vec();
+            self.open(&header, &mut data)?;
+            let frame = Frame {
+                kind: header.kind,
+                channel: header.channel,
+                data,
+            };
+            self.handle_control(&frame);
+            reader.consume(frame_len);
+            self.liveness.seen();
+        }
+    }
+
+    fn make_packet(&self, kind: FrameKind, compressed: bool, channel: u32, data: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(self.header_length() + data.len());
+        self.push_header(&mut packet, kind, compressed, channel, data);
+        packet.extend_from_slice(data);
+        packet
+    }
+
+    /// Append the header of a frame with the payload `data` to `res`
+    fn push_header(
+        &self,
+        res: &mut Vec<u8>,
+        kind: FrameKind,
+        compressed: bool,
+        channel: u32,
+        data: &[u8],
+    ) {
+        let start = res.len();
+        res.extend_from_slice(&self.opts.magic_bytes);
+        res.push(kind_byte(kind, compressed));
+        res.extend_from_slice(&channel.to_le_bytes());
         // Assumes u128 targets don't exist
         let len: u64 = data.len() as u64;
         for val in len.to_le_bytes() {
             res.push(val);
         }
-        res
+        let checksum = self.opts.checksum;
+        if checksum != Checksum::None {
+            let header_sum = checksum.compute(&res[start..]);
+            res.extend_from_slice(&header_sum.to_le_bytes());
+            res.extend_from_slice(&checksum.compute(data).to_le_bytes());
+        }
     }
 
     fn header_length(&self) -> usize {
-        self.opts.magic_bytes.len() + size_of::<u64>()
+        header_length(&self.opts)
+    }
+
+    /// Length of the part of the header that the header checksum covers
+    fn unchecked_header_length(&self) -> usize {
+        unchecked_header_length(&self.opts)
     }
 
-    fn parse_header(&self, bytes: &[u8]) -> Result<usize, ParseHeaderError> {
+    /// Checks the payload of a frame against the checksum in its header, if there is one
+    fn payload_matches(&self, header: &Header, data: &[u8]) -> bool {
+        let checksum = self.opts.checksum;
+        checksum == Checksum::None || checksum.compute(data) == header.checksum
+    }
+
+    fn parse_header(&self, bytes: &[u8]) -> Result<Header, ParseHeaderError> {
         if bytes.len() < self.header_length() {
             return Err(ParseHeaderError::NotEnoughBytes);
         }
@@ -111,17 +1602,432 @@ where
                 return Err(ParseHeaderError::MagicBytesMissing);
             }
         }
-        let len = u64::from_le_bytes(
-            bytes[self.opts.magic_bytes.len()..self.header_length()]
-                .try_into()
-                .unwrap(),
-        );
+        // Check the header before trusting anything in it
+        let checked_len = self.unchecked_header_length();
+        let checksum = if self.opts.checksum == Checksum::None {
+            0
+        } else {
+            let sums = bytes[checked_len..]
+                .first_chunk::<8>()
+                .ok_or(ParseHeaderError::NotEnoughBytes)?;
+            let [h0, h1, h2, h3, p0, p1, p2, p3] = *sums;
+            let header_sum = u32::from_le_bytes([h0, h1, h2, h3]);
+            if self.opts.checksum.compute(&bytes[..checked_len]) != header_sum {
+                return Err(ParseHeaderError::ChecksumMismatch);
+            }
+            u32::from_le_bytes([p0, p1, p2, p3])
+        };
+        let kind_offset = self.opts.magic_bytes.len();
+        let compressed = bytes[kind_offset] & COMPRESSED_FLAG != 0;
+        let kind = FrameKind::from_byte(bytes[kind_offset] & !COMPRESSED_FLAG)
+            .ok_or(ParseHeaderError::UnknownKind)?;
+        let channel_offset = kind_offset + 1;
+        let channel = bytes[channel_offset..]
+            .first_chunk::<4>()
+            .ok_or(ParseHeaderError::NotEnoughBytes)?;
+        let channel = u32::from_le_bytes(*channel);
+        let len = u64::from_le_bytes(bytes[channel_offset + 4..checked_len].try_into().unwrap());
 
         if (usize::MAX as u64) < len {
             return Err(ParseHeaderError::PacketTooLarge);
         }
 
-        Ok(len as usize)
+        Ok(Header {
+            kind,
+            compressed,
+            channel,
+            len: len as usize,
+            checksum,
+        })
+    }
+}
+
+/// Fill the whole buffer, treating a short read as the other end going away
+fn read_exact(reader: &mut impl Read, buf: &mut [u8]) -> Result<(), ConnectionError> {
+    reader.read_exact(buf).map_err(|e| match e.kind() {
+        // This usually gets hit when the other end closes the connection
+        ErrorKind::UnexpectedEof => ConnectionError::UnexepctedEof,
+        _ => ConnectionError::ReadFailed(e),
+    })
+}
+
+/// Length of the header of every frame of connections with these options
+pub(crate) const fn header_length(opts: &OptionsRaw) -> usize {
+    unchecked_header_length(opts) + opts.checksum.header_length()
+}
+
+const fn unchecked_header_length(opts: &OptionsRaw) -> usize {
+    opts.magic_bytes.len() + size_of::<u8>() + size_of::<u32>() + size_of::<u64>()
+}
+
+/// Write all of `slices`, like the unstable `Write::write_all_vectored`
+fn write_all_vectored(
+    writer: &mut impl Write,
+    mut slices: &mut [IoSlice<'_>],
+) -> Result<(), std::io::Error> {
+    IoSlice::advance_slices(&mut slices, 0);
+    while !slices.is_empty() {
+        match writer.write_vectored(slices) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(written) => IoSlice::advance_slices(&mut slices, written),
+            Err(e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Errors while setting the connection up are reported as IO errors
+fn into_io_error(error: ConnectionError) -> std::io::Error {
+    match error {
+        ConnectionError::ReadFailed(e) | ConnectionError::WriteFailed(e) => e,
+        ConnectionError::UnexepctedEof => ErrorKind::UnexpectedEof.into(),
+        e => std::io::Error::new(ErrorKind::InvalidData, format!("{e:?}")),
+    }
+}
+
+/// A message that arrived but wasn't deserialized yet, see [`Connection::receive_borrowed`].
+///
+/// The message stays in the receive buffer of the connection, which can't receive again until
+/// this is dropped. [`Received::message`] deserializes the message into a type that borrows its
+/// strings from the buffer instead of copying them, which saves allocating for large messages
+/// that are only read.
+/// The borrowing type has to be serialized the same way as `R`, like a struct with the same
+/// fields where `String`s are `&str`. Other fields are copied as usual, bytes included.
+///
+/// ```no_run
+/// # use easy_ipc::prelude::*;
+/// # use serde::{Deserialize, Serialize};
+/// #[derive(Serialize, Deserialize)]
+/// struct Document {
+///     title: String,
+///     body: String,
+/// }
+///
+/// #[derive(Deserialize)]
+/// struct DocumentView<'a> {
+///     title: &'a str,
+///     body: &'a str,
+/// }
+/// # fn read(connection: &mut easy_ipc::connection::Connection<(), Document>) {
+/// let received = connection.receive_borrowed().unwrap();
+/// let document: DocumentView = received.message().unwrap();
+/// println!("{}: {} bytes", document.title, document.body.len());
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Received<'c, R>
+where
+    R: for<'de> Deserialize<'de>,
+{
+    raw: &'c Arc<RawConnection>,
+    /// The message in the receive buffer of the connection
+    data: &'c [u8],
+    payload: Payload,
+    /// Copy of `data` for archives that aren't aligned in the receive buffer
+    #[cfg(feature = "rkyv")]
+    aligned: std::cell::OnceCell<rkyv::util::AlignedVec>,
+    _rx: PhantomData<R>,
+}
+
+impl<'c, R> Received<'c, R>
+where
+    R: for<'de> Deserialize<'de>,
+{
+    /// Deserialize the message, borrowing from the receive buffer where `M` allows it.
+    ///
+    /// Fails with [`ConnectionError::UnexpectedFrame`] for messages sent with
+    /// `Connection::send_archived` of the `rkyv` feature.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`ConnectionError::DeserilizationFailed`] if the message isn't an `M`.
+    pub fn message<'buf, M>(&'buf self) -> Result<M, ConnectionError>
+    where
+        M: Deserialize<'buf>,
+    {
+        if self.payload.archived {
+            return Err(ConnectionError::UnexpectedFrame);
+        }
+        self.raw.decode(self.data)
+    }
+
+    /// Deserialize the message into the type of the connection, like [`Connection::receive`]
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`Received::message`].
+    pub fn into_owned(self) -> Result<R, ConnectionError> {
+        self.message()
+    }
+
+    /// Check an archived message sent with [`Connection::send_archived`] and get a view of it
+    /// where it is in the receive buffer, without deserializing or copying it.
+    ///
+    /// The message is only copied if the receive buffer isn't aligned for the archive, which is
+    /// up to the allocator.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`ConnectionError::UnexpectedFrame`] for messages that weren't archived and
+    /// with [`ConnectionError::ArchiveFailed`] if the archive isn't a valid `M`.
+    #[cfg(feature = "rkyv")]
+    pub fn archived<M>(&self) -> Result<&M::Archived, ConnectionError>
+    where
+        M: rkyv::Archive,
+        M::Archived: for<'a> rkyv::bytecheck::CheckBytes<ArchiveValidator<'a>>,
+    {
+        use rkyv::util::AlignedVec;
+
+        if !self.payload.archived {
+            return Err(ConnectionError::UnexpectedFrame);
+        }
+        let bytes = if self.data.as_ptr().align_offset(AlignedVec::<16>::ALIGNMENT) == 0 {
+            self.data
+        } else {
+            self.aligned.get_or_init(|| {
+                let mut aligned = AlignedVec::with_capacity(self.data.len());
+                aligned.extend_from_slice(self.data);
+                aligned
+            })
+        };
+        rkyv::access::<M::Archived, rkyv::rancor::Error>(bytes).map_err(|e| {
+            self.raw.metrics.decode_error();
+            ConnectionError::ArchiveFailed(e)
+        })
+    }
+
+    /// The serialized message
+    #[must_use]
+    pub const fn bytes(&self) -> &'c [u8] {
+        self.data
+    }
+
+    /// The context the message was sent in, see [`Connection::receive_request`]
+    #[must_use]
+    pub fn context(&self) -> RequestContext {
+        RequestContext::new(self.raw.clone(), self.payload.deadline)
+    }
+}
+
+/// What rkyv serializes archived messages with, see [`Connection::send_archived`]
+#[cfg(feature = "rkyv")]
+pub(crate) type ArchiveSerializer<'a> = rkyv::api::high::HighSerializer<
+    rkyv::util::AlignedVec,
+    rkyv::ser::allocator::ArenaHandle<'a>,
+    rkyv::rancor::Error,
+>;
+
+/// What rkyv checks archived messages with, see [`Received::archived`]
+#[cfg(feature = "rkyv")]
+type ArchiveValidator<'a> = rkyv::api::high::HighValidator<'a, rkyv::rancor::Error>;
+
+/// Where the message is in the payload of a message frame
+#[derive(Debug, Clone, Copy)]
+struct Payload {
+    /// Where the message starts, after the deadline of deadline messages
+    start: usize,
+    deadline: Option<SystemTime>,
+    /// The message was serialized with rkyv, see [`FrameKind::ArchivedMessage`]
+    archived: bool,
+}
+
+impl Payload {
+    /// The payload of a frame carrying a message, `None` for frames that are skipped
+    fn of(kind: FrameKind, channel: u32, data: &[u8]) -> Result<Option<Self>, ConnectionError> {
+        if channel != MAIN_CHANNEL {
+            return Err(ConnectionError::UnexpectedFrame);
+        }
+        let (start, deadline) = match kind {
+            FrameKind::Message | FrameKind::ArchivedMessage => (0, None),
+            FrameKind::DeadlineMessage => {
+                let Some(deadline) = data.first_chunk::<8>() else {
+                    return Err(ConnectionError::UnexepctedEof);
+                };
+                let millis = u64::from_le_bytes(*deadline);
+                (
+                    8,
+                    Some(SystemTime::UNIX_EPOCH + Duration::from_millis(millis)),
+                )
+            }
+            // The other end gave up on a request we already finished, nothing to do.
+            FrameKind::Cancel => return Ok(None),
+            _ => return Err(ConnectionError::UnexpectedFrame),
+        };
+        Ok(Some(Self {
+            start,
+            deadline,
+            archived: kind == FrameKind::ArchivedMessage,
+        }))
+    }
+}
+
+/// What the other end of a connection told about itself, see [`Connection::ping`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct PeerInfo {
+    /// Version of `easy_ipc` the other end was built with
+    pub version: String,
+    /// Compression the other end is configured with, see
+    /// [`ClientServerOptions::compression`](crate::model::ClientServerOptions::compression)
+    pub compression: String,
+    /// Checksum the other end is configured with, see
+    /// [`ClientServerOptions::checksum`](crate::model::ClientServerOptions::checksum)
+    pub checksum: String,
+    /// Whether the connection is encrypted with the Noise protocol
+    pub encrypted: bool,
+    /// Whether the other end is configured with token authentication
+    pub token_auth: bool,
+    /// Interval between the heartbeats of the other end, if it sends any
+    pub heartbeat: Option<Duration>,
+    /// Time from sending the ping until the answer arrived
+    #[serde(skip)]
+    pub round_trip: Duration,
+}
+
+/// Which end of the connection we are
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Side {
+    Client,
+    Server,
+}
+
+/// Channel used by plain connections that aren't multiplexed
+pub(crate) const MAIN_CHANNEL: u32 = 0;
+
+/// Set in the kind byte of the header if the payload is compressed
+const COMPRESSED_FLAG: u8 = 0x80;
+
+/// The kind byte of a header, with its flags
+const fn kind_byte(kind: FrameKind, compressed: bool) -> u8 {
+    let flags = if compressed { COMPRESSED_FLAG } else { 0 };
+    kind as u8 | flags
+}
+
+/// The decoded header of a frame
+#[derive(Debug, Clone, Copy)]
+struct Header {
+    kind: FrameKind,
+    /// The payload is compressed, see [`crate::compression`]
+    compressed: bool,
+    channel: u32,
+    /// Length of the payload as sent
+    len: usize,
+    /// Checksum of the payload as sent, 0 without checksums
+    checksum: u32,
+}
+
+/// A single frame read from the connection
+#[derive(Debug)]
+pub(crate) struct Frame {
+    pub(crate) kind: FrameKind,
+    /// Logical channel the frame belongs to, see [`crate::mux`]
+    pub(crate) channel: u32,
+    pub(crate) data: Vec<u8>,
+}
+
+/// What a frame on the wire contains, sent in the header right after the magic bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub(crate) enum FrameKind {
+    /// A regular user message
+    Message = 0,
+    /// One item of a streamed response
+    StreamItem = 1,
+    /// The streamed response finished
+    StreamEnd = 2,
+    /// The streamed response failed, the payload is the error message
+    StreamError = 3,
+    /// The other end doesn't want the result of the current request or stream any more
+    Cancel = 4,
+    /// A user message prefixed with a deadline in milliseconds since the unix epoch
+    DeadlineMessage = 5,
+    /// A user message on a multiplexed channel
+    ChannelData = 6,
+    /// The receiver of a multiplexed channel allows more messages, the payload is the number of
+    /// messages as a u32
+    ChannelCredit = 7,
+    /// The other end closed a multiplexed channel
+    ChannelClose = 8,
+    /// The other end opened a multiplexed channel, the payload is its name. Sent before any other
+    /// frame of the channel.
+    ChannelOpen = 9,
+    /// Tells the other end that we are still there, never seen by users
+    Heartbeat = 10,
+    /// What we support, sent once when the connection is made. The payload is a single byte with
+    /// the compression algorithms we can decompress.
+    Capabilities = 11,
+    /// A message of the Noise handshake, see [`crate::noise`]
+    Handshake = 12,
+    /// The token of the client, sent right after connecting when
+    /// [`ClientServerOptions::token_auth`](crate::model::ClientServerOptions::token_auth) is set
+    Token = 13,
+    /// Asks the other end to describe itself, answered by the connection itself
+    Ping = 14,
+    /// Answer to a ping, the payload is a [`PeerInfo`]
+    Pong = 15,
+    /// A user message serialized with rkyv instead of bitcode, sent by
+    /// `Connection::send_archived` of the `rkyv` feature
+    ArchivedMessage = 16,
+}
+
+impl FrameKind {
+    pub(crate) const fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Self::Message),
+            1 => Some(Self::StreamItem),
+            2 => Some(Self::StreamEnd),
+            3 => Some(Self::StreamError),
+            4 => Some(Self::Cancel),
+            5 => Some(Self::DeadlineMessage),
+            6 => Some(Self::ChannelData),
+            7 => Some(Self::ChannelCredit),
+            8 => Some(Self::ChannelClose),
+            9 => Some(Self::ChannelOpen),
+            10 => Some(Self::Heartbeat),
+            11 => Some(Self::Capabilities),
+            12 => Some(Self::Handshake),
+            13 => Some(Self::Token),
+            14 => Some(Self::Ping),
+            15 => Some(Self::Pong),
+            16 => Some(Self::ArchivedMessage),
+            _ => None,
+        }
+    }
+
+    /// Name of the kind for people to read
+    pub(crate) const fn name(self) -> &'static str {
+        match self {
+            Self::Message => "message",
+            Self::StreamItem => "stream item",
+            Self::StreamEnd => "stream end",
+            Self::StreamError => "stream error",
+            Self::Cancel => "cancel",
+            Self::DeadlineMessage => "deadline message",
+            Self::ChannelData => "channel data",
+            Self::ChannelCredit => "channel credit",
+            Self::ChannelClose => "channel close",
+            Self::ChannelOpen => "channel open",
+            Self::Heartbeat => "heartbeat",
+            Self::Capabilities => "capabilities",
+            Self::Handshake => "handshake",
+            Self::Token => "token",
+            Self::Ping => "ping",
+            Self::Pong => "pong",
+            Self::ArchivedMessage => "archived message",
+        }
+    }
+
+    /// Frames carrying user messages, the ones counted by the metrics
+    const fn is_message(self) -> bool {
+        matches!(
+            self,
+            Self::Message
+                | Self::StreamItem
+                | Self::DeadlineMessage
+                | Self::ChannelData
+                | Self::ArchivedMessage
+        )
     }
 }
 
@@ -129,5 +2035,20 @@ where
 pub(crate) enum ParseHeaderError {
     NotEnoughBytes,
     MagicBytesMissing,
+    UnknownKind,
     PacketTooLarge,
+    ChecksumMismatch,
+}
+
+impl From<ParseHeaderError> for ConnectionError {
+    fn from(value: ParseHeaderError) -> Self {
+        match value {
+            ParseHeaderError::NotEnoughBytes => Self::UnexepctedEof,
+            ParseHeaderError::PacketTooLarge => Self::PacketTooLarge,
+            ParseHeaderError::ChecksumMismatch => Self::ChecksumMismatch,
+            ParseHeaderError::MagicBytesMissing | ParseHeaderError::UnknownKind => {
+                Self::HeaderMismatch
+            }
+        }
+    }
 }
@@ -6,7 +6,9 @@ use std::fmt::Display;
 pub enum ConnectionError {
     /// Header magic bytes did not match or there were not enough bytes to for
     HeaderMismatch,
-    /// Packet was larger that `usize` bytes, this may be due to a malformed header.
+    /// Packet was larger than
+    /// [`ClientServerOptions::max_message_size`](crate::model::ClientServerOptions::max_message_size)
+    /// or `usize` bytes, this may be due to a malformed header.
     PacketTooLarge,
     /// Not enough bytes to read the packet
     UnexepctedEof,
@@ -20,18 +22,83 @@ pub enum ConnectionError {
     ReadFailed(std::io::Error),
     /// Failing initializing the connection
     InitError(std::io::Error),
+    /// Got a frame that doesn't make sense at this point, like a stream item when expecting a
+    /// single message.
+    UnexpectedFrame,
+    /// The other end failed while producing a streamed response, contains its error message.
+    StreamFailed(String),
+    /// The multiplexed channel was closed by the other end, or the connection it runs on failed.
+    ChannelClosed,
+    /// A multiplexed channel with this name is already open, or the other end didn't close it yet
+    /// since we did.
+    ChannelInUse(String),
+    /// Two different names of multiplexed channels map to the same channel id, contains our name
+    /// and the other one. One of the channels needs another name.
+    ChannelNameCollision(String, String),
+    /// The other end stopped sending heartbeats, see
+    /// [`ClientServerOptions::heartbeat`](crate::model::ClientServerOptions::heartbeat).
+    PeerUnresponsive,
+    /// A [`ReconnectingClient`](crate::reconnect::ReconnectingClient) ran out of attempts to
+    /// connect to the server, contains the error of the last attempt.
+    ReconnectFailed(InitError),
+    /// Compressing or decompressing a message failed
+    CompressionFailed(std::io::Error),
+    /// A frame didn't match its checksum, see
+    /// [`ClientServerOptions::checksum`](crate::model::ClientServerOptions::checksum).
+    ///
+    /// If the header was corrupted the connection can't be used any more, as we no longer know
+    /// where the next frame starts.
+    ChecksumMismatch,
+    /// A frame of an encrypted connection couldn't be decrypted, it was corrupted or not sent by
+    /// the end we did the handshake with. See
+    /// [`ClientServerOptions::noise`](crate::model::ClientServerOptions::noise).
+    DecryptionFailed,
+    /// The client didn't present the right token, see
+    /// [`ClientServerOptions::token_auth`](crate::model::ClientServerOptions::token_auth).
+    Unauthorized,
+    /// The queue of a [`Sender`](crate::sender::Sender) is full, see
+    /// [`Overflow::Error`](crate::sender::Overflow::Error).
+    QueueFull,
+    /// Another peer is registered with the [`Broker`](crate::broker::Broker) under this name
+    NameTaken(String),
+    /// Serializing a message with rkyv failed, or a received archive wasn't valid, see
+    /// [`Connection::send_archived`](crate::connection::Connection::send_archived).
+    #[cfg(feature = "rkyv")]
+    ArchiveFailed(rkyv::rancor::Error),
 }
 
 impl Display for ConnectionError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            ConnectionError::SerilizationFailed(e) => write!(f, "serilization fail {e}"),
-            ConnectionError::DeserilizationFailed(e) => write!(f, "deserilization fail {e}"),
-            ConnectionError::HeaderMismatch => {
+            Self::SerilizationFailed(e) => write!(f, "serilization fail {e}"),
+            Self::DeserilizationFailed(e) => write!(f, "deserilization fail {e}"),
+            Self::HeaderMismatch => {
                 write!(f, "header didn't match, likely version incompatability")
             }
-            ConnectionError::WriteFailed(e) => writeln!(f, "write failed, {e}"),
-            _ => todo!(),
+            Self::PacketTooLarge => write!(f, "packet is larger than allowed"),
+            Self::UnexepctedEof => write!(f, "connection ended in the middle of a packet"),
+            Self::WriteFailed(e) => write!(f, "write failed, {e}"),
+            Self::ReadFailed(e) => write!(f, "read failed, {e}"),
+            Self::InitError(e) => write!(f, "failed initializing the connection, {e}"),
+            Self::UnexpectedFrame => write!(f, "got an unexpected kind of frame"),
+            Self::StreamFailed(e) => write!(f, "stream failed, {e}"),
+            Self::ChannelClosed => write!(f, "channel was closed"),
+            Self::ChannelInUse(name) => write!(f, "channel `{name}` is already open"),
+            Self::ChannelNameCollision(ours, other) => {
+                write!(f, "channels `{ours}` and `{other}` have the same id")
+            }
+            Self::PeerUnresponsive => write!(f, "other end stopped responding"),
+            Self::ReconnectFailed(e) => write!(f, "failed to reconnect, {e:?}"),
+            Self::CompressionFailed(e) => write!(f, "compression failed, {e}"),
+            Self::ChecksumMismatch => write!(f, "frame didn't match its checksum"),
+            Self::DecryptionFailed => write!(f, "failed decrypting a frame"),
+            Self::Unauthorized => write!(f, "client presented a wrong token"),
+            Self::QueueFull => write!(f, "outgoing queue is full"),
+            Self::NameTaken(name) => {
+                write!(f, "name `{name}` is already registered with the broker")
+            }
+            #[cfg(feature = "rkyv")]
+            Self::ArchiveFailed(e) => write!(f, "archiving failed, {e}"),
         }
     }
 }
@@ -51,4 +118,15 @@ pub enum InitError {
     /// Specific to servers trying to connect to already existing sockets. This can happen if a
     /// server is already running or it exited in a non-graceful way.
     SocketAlreadyExists,
+    /// Starting the server in [`crate::model::IpcModel::client_or_spawn`] failed
+    FailedSpawningServer(std::io::Error),
+    /// The server started by [`crate::model::IpcModel::client_or_spawn`] exited before accepting
+    /// connections
+    SpawnedServerExited(std::process::ExitStatus),
+    /// The server started by [`crate::model::IpcModel::client_or_spawn`] didn't accept
+    /// connections in time
+    SpawnTimedOut,
+    /// Writing the token file on the server, or reading it on the client, failed. See
+    /// [`ClientServerOptions::token_auth`](crate::model::ClientServerOptions::token_auth).
+    TokenFile(std::io::Error),
 }
@@ -0,0 +1,442 @@
+use {
+    crate::{connection::Connection, error::ConnectionError, server::Server},
+    mio::{Events, Interest, Poll, Registry, Token, unix::SourceFd},
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::BTreeMap,
+        io::{self, ErrorKind},
+        os::fd::{AsRawFd, RawFd},
+        time::{Duration, Instant},
+    },
+};
+
+/// Identifies a connection of an [`EventLoopServer`], unique for the lifetime of the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ConnectionId(usize);
+
+/// Token of the listening socket, connections get the token of their id
+const SERVER: Token = Token(usize::MAX);
+
+/// What an [`EventLoopServer`] does when something happens on one of its connections.
+///
+/// Callbacks run on the thread of the event loop, no other connection is served while one runs.
+/// Sending on a connection waits until the socket takes the frame, which it does right away
+/// unless the client stopped reading.
+pub trait EventHandler<T, R>
+where
+    T: Serialize,
+    R: for<'de> Deserialize<'de>,
+{
+    /// A client connected
+    fn on_connect(&mut self, id: ConnectionId, connections: &mut Connections<T, R>) {
+        let _ = (id, connections);
+    }
+
+    /// A client sent a message
+    fn on_message(&mut self, id: ConnectionId, message: R, connections: &mut Connections<T, R>);
+
+    /// A connection is gone, `error` is `None` if it was closed with [`Connections::close`].
+    ///
+    /// Clients that went away show up as [`ConnectionError::UnexepctedEof`].
+    fn on_disconnect(
+        &mut self,
+        id: ConnectionId,
+        error: Option<ConnectionError>,
+        connections: &mut Connections<T, R>,
+    ) {
+        let _ = (id, error, connections);
+    }
+}
+
+/// The open connections of an [`EventLoopServer`], handed to every [`EventHandler`] callback
+#[derive(Debug)]
+pub struct Connections<T, R>
+where
+    T: Serialize,
+    R: for<'de> Deserialize<'de>,
+{
+    open: BTreeMap<ConnectionId, Connection<T, R>>,
+    /// Closed by the handler, told about once its callback returns
+    closed: Vec<ConnectionId>,
+    /// Where the sockets of the connections are registered for polling
+    registry: Registry,
+}
+
+impl<T, R> Connections<T, R>
+where
+    T: Serialize,
+    R: for<'de> Deserialize<'de>,
+{
+    /// Send a message to a connection
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`ErrorKind::NotConnected`] if the connection is closed, and like
+    /// [`Connection::send`] otherwise.
+    pub fn send(&mut self, id: ConnectionId, message: T) -> Result<(), ConnectionError> {
+        let Some(connection) = self.open.get_mut(&id) else {
+            return Err(ConnectionError::WriteFailed(ErrorKind::NotConnected.into()));
+        };
+        connection.send(message)
+    }
+
+    /// The connection itself, to look at its metrics or to send streamed responses.
+    ///
+    /// Don't receive on it, that is the job of the event loop.
+    pub fn get_mut(&mut self, id: ConnectionId) -> Option<&mut Connection<T, R>> {
+        self.open.get_mut(&id)
+    }
+
+    /// Close a connection, [`EventHandler::on_disconnect`] is called once the current callback
+    /// returns. Does nothing if it is already closed.
+    pub fn close(&mut self, id: ConnectionId) {
+        if let Some(connection) = self.open.remove(&id) {
+            deregister(&self.registry, &connection);
+            self.closed.push(id);
+        }
+    }
+
+    /// The ids of all open connections
+    pub fn ids(&self) -> impl Iterator<Item = ConnectionId> + '_ {
+        self.open.keys().copied()
+    }
+
+    /// Number of open connections
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.open.len()
+    }
+
+    /// Whether no connection is open
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.open.is_empty()
+    }
+}
+
+/// A server that serves all of its connections on a single thread.
+///
+/// Instead of a thread per connection, the event loop waits with epoll, or kqueue on macOS, until
+/// the server or any connection is readable and then calls the [`EventHandler`]. Messages are read
+/// as they arrive, a message that only partly arrived is kept until the rest does, see
+/// [`Connection::try_receive`]. Clients can't tell it apart from a [`Server`], the frames are the
+/// same.
+///
+/// Clients that have to present a token are waited for like any other connection, a client that
+/// is slow to send its token holds up nobody. Only available on unix with the `event-loop`
+/// feature.
+///
+/// ```no_run
+/// # use easy_ipc::{event_loop::{ConnectionId, Connections, EventHandler, EventLoopServer}, prelude::*};
+/// # use serde::{Deserialize, Serialize};
+/// # #[derive(Serialize, Deserialize)]
+/// # struct Message;
+/// # struct MyModel;
+/// # impl IpcModel for MyModel {
+/// #     type ClientMsg = Message;
+/// #     type ServerMsg = Message;
+/// #     fn model() -> Result<ClientServerModel<Message, Message>, InitError> {
+/// #         Ok(ClientServerOptions::new("/tmp/event_loop.sock").create())
+/// #     }
+/// # }
+/// struct Echo;
+///
+/// impl EventHandler<Message, Message> for Echo {
+///     fn on_message(
+///         &mut self,
+///         id: ConnectionId,
+///         message: Message,
+///         connections: &mut Connections<Message, Message>,
+///     ) {
+///         // A client that went away is noticed on the next poll
+///         connections.send(id, message).ok();
+///     }
+/// }
+///
+/// let mut server = EventLoopServer::new(MyModel::server().unwrap(), Echo).unwrap();
+/// server.run().unwrap();
+/// ```
+#[derive(Debug)]
+pub struct EventLoopServer<T, R, H>
+where
+    T: Serialize,
+    R: for<'de> Deserialize<'de>,
+{
+    server: Server<T, R>,
+    handler: H,
+    connections: Connections<T, R>,
+    /// Clients that didn't present their token yet, and when they are given up on
+    pending: BTreeMap<ConnectionId, (Instant, Connection<T, R>)>,
+    poll: Poll,
+    events: Events,
+    /// Connections to look at on the next poll without waiting, because another thread was
+    /// reading from them while we tried to
+    retry: Vec<ConnectionId>,
+    next_id: usize,
+}
+
+impl<T, R, H> EventLoopServer<T, R, H>
+where
+    T: Serialize,
+    R: for<'de> Deserialize<'de>,
+    H: EventHandler<T, R>,
+{
+    /// Serve the connections of `server` with `handler`
+    ///
+    /// # Errors
+    ///
+    /// Fails if the operating system can't set up polling.
+    pub fn new(server: Server<T, R>, handler: H) -> Result<Self, io::Error> {
+        let poll = Poll::new()?;
+        let registry = poll.registry().try_clone()?;
+        register(&registry, server.as_raw_fd(), SERVER)?;
+        Ok(Self {
+            server,
+            handler,
+            connections: Connections {
+                open: BTreeMap::new(),
+                closed: Vec::new(),
+                registry,
+            },
+            pending: BTreeMap::new(),
+            poll,
+            events: Events::with_capacity(1024),
+            retry: Vec::new(),
+            next_id: 0,
+        })
+    }
+
+    /// Serve connections until waiting for them fails, see [`EventLoopServer::poll`]
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`EventLoopServer::poll`].
+    pub fn run(&mut self) -> Result<(), io::Error> {
+        loop {
+            self.poll(None)?;
+        }
+    }
+
+    /// Wait up to `timeout` for something to happen, forever if `None`, and handle everything
+    /// that did.
+    ///
+    /// Run this in a loop to do something else in between, like checking if the server should
+    /// shut down. Clients that don't present their token within
+    /// [`ClientServerOptions::auth_timeout`] are refused, the wait ends early for them.
+    ///
+    /// [`ClientServerOptions::auth_timeout`]: crate::model::ClientServerOptions::auth_timeout
+    ///
+    /// # Errors
+    ///
+    /// Fails if waiting fails, or if accepting on the listening socket fails, like when the
+    /// process runs out of file descriptors. Clients that fail to set up don't fail the poll,
+    /// they are counted as rejected by the server.
+    pub fn poll(&mut self, timeout: Option<Duration>) -> Result<(), io::Error> {
+        let retry = std::mem::take(&mut self.retry);
+        let timeout = if retry.is_empty() {
+            self.until_deadline(timeout)
+        } else {
+            Some(Duration::ZERO)
+        };
+        if let Err(e) = self.poll.poll(&mut self.events, timeout) {
+            self.retry = retry;
+            if e.kind() == ErrorKind::Interrupted {
+                return Ok(());
+            }
+            return Err(e);
+        }
+
+        let ready: Vec<_> = self.events.iter().map(mio::event::Event::token).collect();
+        let mut accepted = Ok(());
+        for token in ready {
+            if token == SERVER {
+                accepted = self.accept();
+            } else {
+                self.ready(ConnectionId(token.0));
+            }
+        }
+        for id in retry {
+            self.ready(id);
+        }
+        self.expire();
+        accepted
+    }
+
+    /// Shorten `timeout` to end when the first pending client is given up on
+    fn until_deadline(&self, timeout: Option<Duration>) -> Option<Duration> {
+        let Some(deadline) = self.pending.values().map(|(deadline, _)| *deadline).min() else {
+            return timeout;
+        };
+        let left = deadline.saturating_duration_since(Instant::now());
+        Some(timeout.map_or(left, |timeout| timeout.min(left)))
+    }
+
+    /// Refuse the clients that didn't present their token in time
+    fn expire(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<_> = self
+            .pending
+            .iter()
+            .filter(|(_, (deadline, _))| *deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            if let Some((_, connection)) = self.pending.remove(&id) {
+                deregister(&self.connections.registry, &connection);
+                let timed_out = ConnectionError::ReadFailed(ErrorKind::TimedOut.into());
+                self.server.rejected(&timed_out);
+            }
+        }
+    }
+
+    /// The handler, to look at what it collected
+    pub const fn handler(&self) -> &H {
+        &self.handler
+    }
+
+    /// The handler, to change how it handles connections from now on
+    pub const fn handler_mut(&mut self) -> &mut H {
+        &mut self.handler
+    }
+
+    /// The open connections, to send messages outside of handler callbacks
+    pub const fn connections(&mut self) -> &mut Connections<T, R> {
+        &mut self.connections
+    }
+
+    /// The server the connections are accepted from, to look at its metrics
+    pub const fn server(&self) -> &Server<T, R> {
+        &self.server
+    }
+
+    /// Accept every client that is waiting, fails only if the listening socket does
+    fn accept(&mut self) -> Result<(), io::Error> {
+        while let Some(stream) = self.server.try_accept_stream()? {
+            let connection = match self.server.open(stream) {
+                Ok(connection) => connection,
+                Err(e) => {
+                    self.server.rejected(&e);
+                    continue;
+                }
+            };
+            let id = ConnectionId(self.next_id);
+            self.next_id += 1;
+            let token = Token(id.0);
+            if let Err(e) = register(&self.connections.registry, connection.as_raw_fd(), token) {
+                self.server.rejected(&ConnectionError::InitError(e));
+                continue;
+            }
+            if self.server.token_auth() {
+                let deadline = Instant::now() + self.server.auth_timeout();
+                self.pending.insert(id, (deadline, connection));
+                self.authorize(id);
+            } else {
+                self.connect(id, connection);
+            }
+        }
+        Ok(())
+    }
+
+    /// Something arrived on a connection
+    fn ready(&mut self, id: ConnectionId) {
+        if self.pending.contains_key(&id) {
+            self.authorize(id);
+        } else {
+            self.serve(id);
+        }
+    }
+
+    /// Check the token of a client once it has arrived
+    fn authorize(&mut self, id: ConnectionId) {
+        let Some((_, connection)) = self.pending.get(&id) else {
+            return;
+        };
+        let frame = match connection.try_read_frame() {
+            Ok(Some(frame)) => Ok(frame),
+            Ok(None) => {
+                if connection.take_skipped_read() {
+                    self.retry.push(id);
+                }
+                return;
+            }
+            Err(e) => Err(e),
+        };
+        let Some((_, connection)) = self.pending.remove(&id) else {
+            return;
+        };
+        let authorized = frame.and_then(|frame| {
+            if !self.server.authorizes(&frame) {
+                return Err(ConnectionError::Unauthorized);
+            }
+            connection.authorize()
+        });
+        match authorized {
+            Ok(()) => self.connect(id, connection),
+            Err(e) => {
+                deregister(&self.connections.registry, &connection);
+                self.server.rejected(&e);
+            }
+        }
+    }
+
+    /// Hand a client that was set up to the handler
+    fn connect(&mut self, id: ConnectionId, connection: Connection<T, R>) {
+        self.server.accepted();
+        self.connections.open.insert(id, connection);
+        self.handler.on_connect(id, &mut self.connections);
+        self.disconnect_closed();
+        // Messages may have arrived along with the token, they won't wake the poll again
+        self.serve(id);
+    }
+
+    /// Handle everything that arrived on a connection
+    fn serve(&mut self, id: ConnectionId) {
+        while let Some(connection) = self.connections.open.get_mut(&id) {
+            match connection.try_receive() {
+                Ok(Some(message)) => {
+                    self.handler.on_message(id, message, &mut self.connections);
+                    self.disconnect_closed();
+                }
+                Ok(None) => {
+                    if connection.take_skipped_read() {
+                        self.retry.push(id);
+                    }
+                    return;
+                }
+                Err(e) => {
+                    if let Some(connection) = self.connections.open.remove(&id) {
+                        deregister(&self.connections.registry, &connection);
+                    }
+                    self.handler
+                        .on_disconnect(id, Some(e), &mut self.connections);
+                    self.disconnect_closed();
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Tell the handler about the connections it closed
+    fn disconnect_closed(&mut self) {
+        while let Some(id) = self.connections.closed.pop() {
+            self.handler.on_disconnect(id, None, &mut self.connections);
+        }
+    }
+}
+
+/// Wake the poll when `fd` becomes readable
+fn register(registry: &Registry, fd: RawFd, token: Token) -> Result<(), io::Error> {
+    registry.register(&mut SourceFd(&fd), token, Interest::READABLE)
+}
+
+/// Stop polling a connection before it is dropped, so that a later connection that gets the
+/// same file descriptor doesn't get its events
+fn deregister<T, R>(registry: &Registry, connection: &Connection<T, R>)
+where
+    T: Serialize,
+    R: for<'de> Deserialize<'de>,
+{
+    registry
+        .deregister(&mut SourceFd(&connection.as_raw_fd()))
+        .ok();
+}
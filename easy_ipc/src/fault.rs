@@ -0,0 +1,298 @@
+#[cfg(unix)]
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::Shutdown,
+    os::{fd::AsFd, unix::net::UnixStream},
+    thread,
+};
+use {
+    crate::{capture::Direction, connection, model::OptionsRaw},
+    interprocess::local_socket::Stream,
+    std::{
+        io,
+        sync::atomic::{AtomicU64, Ordering},
+        time::Duration,
+    },
+};
+
+/// Pause between the pieces of a [`Fault::Split`] frame, long enough for the reader to see them
+/// separately
+#[cfg(unix)]
+const SPLIT_PAUSE: Duration = Duration::from_millis(1);
+
+/// Something that happens to a frame on its way to the other end, see [`FaultyTransport`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Fault {
+    /// The frame arrives as it was sent
+    Pass,
+    /// The frame arrives late, so do all frames after it
+    Delay(Duration),
+    /// The frame is written in pieces of this many bytes with a pause in between, like a partial
+    /// write would leave it
+    Split(usize),
+    /// Only this many bytes of the frame arrive, at most all but the last one, then the connection
+    /// is cut
+    Truncate(usize),
+    /// The byte at this offset of the frame is flipped. The offset wraps around at the end of the
+    /// frame, offset 0 is the first of the magic bytes.
+    Corrupt(usize),
+    /// The connection is cut before the frame arrives
+    Disconnect,
+}
+
+/// Which faults happen, and when
+#[derive(Debug, Clone)]
+#[cfg_attr(not(unix), allow(dead_code))]
+enum Plan {
+    Script(Vec<Fault>),
+    Random {
+        seed: u64,
+        faults: Vec<(Fault, f64)>,
+    },
+}
+
+/// A transport that damages frames on their way, to test how applications handle broken
+/// connections. See [`ClientServerOptions::faults`].
+///
+/// Clients of a model with faults connect through it. It sits between the [`Connection`] of the
+/// client and the socket and passes frames on whole, applying a [`Fault`] to every frame going in
+/// its [`Direction`]. Frames the connection sends for itself, like heartbeats or the handshake,
+/// count as frames too. Frames going the other way pass untouched.
+///
+/// Every connection starts the plan over, what happens to its frames only depends on the plan
+/// and the order of the frames. Only supported on unix, elsewhere connecting fails.
+///
+/// [`ClientServerOptions::faults`]: crate::model::ClientServerOptions::faults
+/// [`Connection`]: crate::connection::Connection
+#[derive(Debug)]
+pub struct FaultyTransport {
+    plan: Plan,
+    direction: Direction,
+    /// Connections made so far, mixed into the seed of random faults so they differ
+    connections: AtomicU64,
+}
+
+impl FaultyTransport {
+    /// Apply `faults` to the frames in order, the first fault to the first frame. Frames after
+    /// the end of the script pass.
+    pub fn script<I>(faults: I) -> Self
+    where
+        I: IntoIterator<Item = Fault>,
+    {
+        Self::new(Plan::Script(faults.into_iter().collect()))
+    }
+
+    /// Apply every fault with the probability given along with it, `0.0` to `1.0`, and let the
+    /// frame pass otherwise. At most one fault happens to a frame, the first ones listed win.
+    ///
+    /// The same `seed` makes the same faults happen to the same frames.
+    pub fn random<I>(seed: u64, faults: I) -> Self
+    where
+        I: IntoIterator<Item = (Fault, f64)>,
+    {
+        Self::new(Plan::Random {
+            seed,
+            faults: faults.into_iter().collect(),
+        })
+    }
+
+    const fn new(plan: Plan) -> Self {
+        Self {
+            plan,
+            direction: Direction::ToServer,
+            connections: AtomicU64::new(0),
+        }
+    }
+
+    /// Damage the frames going this way, [`Direction::ToServer`] by default.
+    ///
+    /// Faults show up as errors on the end receiving the frames, and as failed writes on the other
+    /// end once the connection is cut.
+    #[must_use]
+    pub const fn direction(mut self, direction: Direction) -> Self {
+        self.direction = direction;
+        self
+    }
+
+    /// Put the transport between a newly connected client and the socket
+    #[cfg(unix)]
+    pub(crate) fn wrap(&self, stream: Stream, opts: &OptionsRaw) -> Result<Stream, io::Error> {
+        let Stream::UdSocket(socket) = &stream;
+        let outer = UnixStream::from(socket.as_fd().try_clone_to_owned()?);
+        drop(stream);
+        let (inner, relay) = UnixStream::pair()?;
+
+        let layout = Layout {
+            magic: opts.magic_bytes.len(),
+            header: connection::header_length(opts),
+        };
+        let connection = self.connections.fetch_add(1, Ordering::Relaxed);
+        let faults = Some(Faults::new(&self.plan, connection));
+        let (to_server, to_client) = match self.direction {
+            Direction::ToServer => (faults, None),
+            Direction::ToClient => (None, faults),
+        };
+        let cut = Cut([relay.try_clone()?, outer.try_clone()?]);
+        let up = (relay.try_clone()?, outer.try_clone()?, cut.try_clone()?);
+        thread::spawn(move || layout.relay(up.0, up.1, to_server, &up.2));
+        thread::spawn(move || layout.relay(outer, relay, to_client, &cut));
+        Ok(Stream::UdSocket(inner.into()))
+    }
+
+    #[cfg(not(unix))]
+    #[allow(clippy::unused_self)]
+    pub(crate) fn wrap(&self, _stream: Stream, _opts: &OptionsRaw) -> Result<Stream, io::Error> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "faulty connections are only supported on unix",
+        ))
+    }
+}
+
+/// What happens to the frames of one connection
+#[cfg(unix)]
+struct Faults {
+    plan: Plan,
+    frame: usize,
+    rng: u64,
+}
+
+#[cfg(unix)]
+impl Faults {
+    fn new(plan: &Plan, connection: u64) -> Self {
+        let seed = match plan {
+            Plan::Random { seed, .. } => *seed,
+            Plan::Script(_) => 0,
+        };
+        Self {
+            plan: plan.clone(),
+            frame: 0,
+            rng: seed.wrapping_add(connection),
+        }
+    }
+
+    /// The fault of the next frame
+    fn next(&mut self) -> Fault {
+        let frame = self.frame;
+        self.frame += 1;
+        match &self.plan {
+            Plan::Script(faults) => faults.get(frame).copied().unwrap_or(Fault::Pass),
+            Plan::Random { faults, .. } => {
+                // 53 random bits fit a float exactly
+                #[allow(clippy::cast_precision_loss)]
+                let roll = (splitmix(&mut self.rng) >> 11) as f64 / (1_u64 << 53) as f64;
+                let mut bound = 0.0;
+                faults
+                    .iter()
+                    .find(|(_, probability)| {
+                        bound += probability.clamp(0.0, 1.0);
+                        roll < bound
+                    })
+                    .map_or(Fault::Pass, |(fault, _)| *fault)
+            }
+        }
+    }
+}
+
+/// Small and good enough to pick faults, see <https://prng.di.unimi.it/splitmix64.c>
+#[cfg(unix)]
+const fn splitmix(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Both sockets of the transport, shutting them down cuts the client off from the server
+#[cfg(unix)]
+struct Cut([UnixStream; 2]);
+
+#[cfg(unix)]
+impl Cut {
+    fn try_clone(&self) -> Result<Self, io::Error> {
+        Ok(Self([self.0[0].try_clone()?, self.0[1].try_clone()?]))
+    }
+
+    fn cut(&self) {
+        for socket in &self.0 {
+            socket.shutdown(Shutdown::Both).ok();
+        }
+    }
+}
+
+/// Where to find the length of a frame
+#[cfg(unix)]
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+    magic: usize,
+    header: usize,
+}
+
+#[cfg(unix)]
+impl Layout {
+    /// Pass frames from `from` on to `to` until either end goes away or a fault cuts the
+    /// connection
+    fn relay(
+        self,
+        mut from: UnixStream,
+        mut to: UnixStream,
+        mut faults: Option<Faults>,
+        cut: &Cut,
+    ) {
+        loop {
+            let Ok(frame) = self.read_frame(&mut from) else {
+                // Let the other end see the connection close the way it did
+                to.shutdown(Shutdown::Write).ok();
+                return;
+            };
+            let fault = faults.as_mut().map_or(Fault::Pass, Faults::next);
+            if !deliver(fault, frame, &mut to) {
+                cut.cut();
+                return;
+            }
+        }
+    }
+
+    fn read_frame(self, from: &mut impl Read) -> Result<Vec<u8>, io::Error> {
+        let mut frame = vec![0; self.header];
+        from.read_exact(&mut frame)?;
+        let len_offset = self.magic + size_of::<u8>() + size_of::<u32>();
+        let len = frame[len_offset..]
+            .first_chunk::<8>()
+            .map_or(0, |len| u64::from_le_bytes(*len));
+        let len = usize::try_from(len).map_err(|_| io::Error::from(ErrorKind::InvalidData))?;
+        frame.resize(self.header + len, 0);
+        from.read_exact(&mut frame[self.header..])?;
+        Ok(frame)
+    }
+}
+
+/// Pass a frame on with `fault` applied, `false` if the connection is to be cut
+#[cfg(unix)]
+fn deliver(fault: Fault, mut frame: Vec<u8>, to: &mut UnixStream) -> bool {
+    let written = match fault {
+        Fault::Pass => to.write_all(&frame),
+        Fault::Delay(delay) => {
+            thread::sleep(delay);
+            to.write_all(&frame)
+        }
+        Fault::Split(size) => frame.chunks(size.max(1)).try_for_each(|piece| {
+            thread::sleep(SPLIT_PAUSE);
+            to.write_all(piece)
+        }),
+        Fault::Truncate(len) => {
+            let len = len.min(frame.len() - 1);
+            to.write_all(&frame[..len]).ok();
+            return false;
+        }
+        Fault::Corrupt(offset) => {
+            let len = frame.len();
+            frame[offset % len] ^= 0xff;
+            to.write_all(&frame)
+        }
+        Fault::Disconnect => return false,
+    };
+    written.is_ok()
+}
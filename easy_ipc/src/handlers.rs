@@ -1,4 +1,4 @@
-use crate::model::ClientServerModel;
+use crate::{model::ClientServerModel, request::CancelHandle, trace};
 
 use {
     serde::{Deserialize, Serialize},
@@ -38,24 +38,25 @@ where
         Ok(false) => (),
         // Bad, we failed deleting the socket file, this might lead to a zombie socket file or it
         // could be because of bad permissions
-        Err(e) => eprintln!(
+        Err(e) => trace::warn(&format!(
             "Couldn't clean up socket file {}: {e}",
             &path.as_ref().display()
-        ),
+        )),
     }
 }
 
-/// Handles os signals by calling the cleanup function
+/// Waits for an os signal that asks to terminate, calls `on_signal` and then terminates the process
+/// as the signal would have by default.
 #[cfg(target_family = "unix")]
-fn handle_os_signals<P>(path: P) -> Result<(), std::io::Error>
+fn handle_os_signals<F>(on_signal: F) -> Result<(), std::io::Error>
 where
-    P: AsRef<Path>,
+    F: FnOnce(),
 {
     use signal_hook::{consts::*, iterator::Signals};
     // Handle all term signals
     let mut signals = Signals::new(TERM_SIGNALS)?;
     if let Some(sig) = signals.forever().next() {
-        clean(path);
+        on_signal();
         unsafe {
             libc::signal(sig, libc::SIG_DFL);
             libc::raise(sig);
@@ -66,6 +67,21 @@ where
     Ok(())
 }
 
+/// Sends a cancel for the current request when the process is asked to terminate, so that the
+/// server doesn't keep working on a request nobody is waiting for.
+pub fn cancel_on_signals(handle: CancelHandle) {
+    #[cfg(target_family = "unix")]
+    std::thread::spawn(move || {
+        // Failing here only means the server finishes the request, not worth bringing down the
+        // client for.
+        let _ = handle_os_signals(|| {
+            let _ = handle.cancel();
+        });
+    });
+    #[cfg(not(target_family = "unix"))]
+    let _ = handle;
+}
+
 /// Sets up handlers to try and delete a given path upon panic and signals that ask to terminate
 /// the process.
 pub(crate) fn setup_handlers<C, S>(model: &ClientServerModel<C, S>)
@@ -88,7 +104,7 @@ where
     {
         let path_clone = path.clone();
         std::thread::spawn(move || {
-            if let Err(e) = handle_os_signals(&path_clone) {
+            if let Err(e) = handle_os_signals(|| clean(&path_clone)) {
                 panic!("Failed setting up signal handlers: {e}");
             }
             panic!("Stopped handling signals.");
@@ -0,0 +1,108 @@
+use {
+    crate::connection::RawConnection,
+    std::{
+        sync::{
+            Arc, Mutex, MutexGuard, PoisonError,
+            atomic::{AtomicBool, Ordering},
+        },
+        thread,
+        time::{Duration, Instant},
+    },
+};
+
+/// Keeps track of when we last heard from the other end of a connection.
+///
+/// The other end can only be late while we are waiting on it. Heartbeats that arrive while nobody
+/// is reading pile up in the socket and are only seen once someone reads again, so the time spent
+/// not reading doesn't count.
+#[derive(Debug)]
+pub struct Liveness {
+    inner: Mutex<LivenessInner>,
+    unresponsive: AtomicBool,
+}
+
+#[derive(Debug)]
+struct LivenessInner {
+    reading: bool,
+    last_seen: Instant,
+}
+
+impl Liveness {
+    pub fn new() -> Self {
+        Self {
+            inner: Mutex::new(LivenessInner {
+                reading: false,
+                last_seen: Instant::now(),
+            }),
+            unresponsive: AtomicBool::new(false),
+        }
+    }
+
+    fn inner(&self) -> MutexGuard<'_, LivenessInner> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// A thread starts waiting for the other end
+    pub fn start_reading(&self) {
+        let mut inner = self.inner();
+        inner.reading = true;
+        inner.last_seen = Instant::now();
+    }
+
+    /// The thread waiting for the other end is done
+    pub fn stop_reading(&self) {
+        self.inner().reading = false;
+    }
+
+    /// Something arrived from the other end
+    pub fn seen(&self) {
+        self.inner().last_seen = Instant::now();
+    }
+
+    /// If the other end was declared unresponsive, the connection can't be used any more
+    pub fn is_unresponsive(&self) -> bool {
+        self.unresponsive.load(Ordering::Relaxed)
+    }
+
+    /// Declare the other end unresponsive if we waited on it for longer than `limit`
+    fn check(&self, limit: Duration) -> bool {
+        let inner = self.inner();
+        let overdue = inner.reading && inner.last_seen.elapsed() > limit;
+        drop(inner);
+        if overdue {
+            self.unresponsive.store(true, Ordering::Relaxed);
+        }
+        overdue
+    }
+}
+
+/// Start sending heartbeats on a connection and watching for the ones of the other end.
+///
+/// The thread stops once the connection is dropped or broken.
+pub fn start(
+    raw: &Arc<RawConnection>,
+    interval: Duration,
+    missed_limit: u32,
+) -> Result<(), std::io::Error> {
+    let raw = Arc::downgrade(raw);
+    let limit = interval.saturating_mul(missed_limit.max(1));
+    thread::Builder::new()
+        .name("easy_ipc heartbeat".to_string())
+        .spawn(move || {
+            loop {
+                thread::sleep(interval);
+                let Some(raw) = raw.upgrade() else {
+                    return;
+                };
+                if raw.liveness().check(limit) {
+                    // Wake up whoever is blocked reading, they report the peer as unresponsive
+                    raw.shutdown();
+                    return;
+                }
+                if raw.heartbeat().is_err() {
+                    return;
+                }
+            }
+        })?;
+    Ok(())
+}
@@ -73,7 +73,13 @@
 //!
 //! # Limitations
 //!
-//! This crate cannot handle non-blocking send/receive messages at the moment.
+//! Sending always blocks until the whole frame is written, there is no non-blocking send. A
+//! [`sender::Sender`] moves the writing to a thread of its own instead.
+//!
+//! Receiving and accepting can be done without blocking with
+//! [`connection::Connection::try_receive`] and [`server::Server::try_accept`], but waiting for
+//! the sockets to become readable through [`std::os::fd::AsFd`] is only supported on unix, as is
+//! the `event_loop` module behind the `event-loop` feature. There is no async support.
 
 #![warn(clippy::all)]
 #![warn(clippy::pedantic)]
@@ -88,32 +94,79 @@
 pub mod prelude {
     pub use crate::ipc_model;
     pub use easy_ipc_derive::IpcModel;
+    pub use easy_ipc_derive::Schema;
 
     pub use crate::client::Client;
     pub use crate::error::InitError;
     pub use crate::model::ClientServerModel;
     pub use crate::model::ClientServerOptions;
     pub use crate::model::IpcModel;
+    pub use crate::schema::Schema;
     pub use crate::server::Server;
 }
 
+/// The rkyv version archived messages are made with, see
+/// [`Connection::send_archived`](crate::connection::Connection::send_archived)
+#[cfg(feature = "rkyv")]
+pub use rkyv;
+
+/// Many-to-many messaging between processes through a broker
+pub mod broker;
+/// Capturing and replaying connections
+pub mod capture;
+/// Frame checksums
+pub mod checksum;
 /// Client process
 pub mod client;
+/// Message compression
+pub mod compression;
 /// Connection between client and server
 pub mod connection;
 /// Error enumerations
 pub mod error;
+/// Serving many connections on a single thread
+#[cfg(all(unix, feature = "event-loop"))]
+pub mod event_loop;
+/// Damaging frames on purpose to test how applications handle broken connections
+pub mod fault;
+/// Counters of servers and connections
+pub mod metrics;
 /// Definition of client server model
 pub mod model;
+/// Multiple logical channels over one connection
+pub mod mux;
 /// Handle getting default namespace information
 pub mod namespace;
+/// Encrypted connections with the Noise protocol
+#[cfg(feature = "noise")]
+pub mod noise;
+/// Clients that reconnect when the server restarts
+pub mod reconnect;
+/// Request deadlines and cancellation
+pub mod request;
+/// Describe the layout of messages
+pub mod schema;
+/// Sending from bounded queues
+pub mod sender;
 /// Server process
 pub mod server;
+/// Streaming responses
+pub mod stream;
 
 /// Handle OS signals
 mod handlers;
+/// Keepalive heartbeats
+mod heartbeat;
 /// Helper macros
 mod macros;
+/// In-process connections
+mod memory;
+/// Connections over pipes and other readers and writers
+mod pipe;
 /// Tests
 #[cfg(test)]
 mod test;
+/// Pre-shared token authentication
+mod token;
+/// Spans and events of the `tracing` feature, they do nothing without it
+mod trace;
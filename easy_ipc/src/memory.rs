@@ -0,0 +1,164 @@
+#[cfg(unix)]
+use std::{
+    io::{Read, Write},
+    os::{
+        fd::{AsFd, BorrowedFd},
+        unix::net::UnixStream,
+    },
+};
+use {
+    interprocess::local_socket::Stream,
+    std::{
+        collections::BTreeMap,
+        io::{self, ErrorKind},
+        sync::{
+            Mutex, PoisonError,
+            mpsc::{Receiver, Sender, TryRecvError, channel},
+        },
+    },
+};
+
+/// Servers listening in memory, by name. Clients hand them one end of a socket pair.
+static LISTENERS: Mutex<BTreeMap<String, Incoming>> = Mutex::new(BTreeMap::new());
+
+fn listeners<T>(f: impl FnOnce(&mut BTreeMap<String, Incoming>) -> T) -> T {
+    f(&mut LISTENERS.lock().unwrap_or_else(PoisonError::into_inner))
+}
+
+/// How clients reach a listener
+#[derive(Debug)]
+struct Incoming {
+    sender: Sender<Stream>,
+    /// A byte is written for every client, so the listener can be polled like a socket
+    #[cfg(unix)]
+    wake: UnixStream,
+}
+
+/// The listening end of an in-memory server, see
+/// [`ClientServerOptions::in_memory`](crate::model::ClientServerOptions::in_memory).
+///
+/// The name is free again once this is dropped.
+#[derive(Debug)]
+pub struct MemoryListener {
+    name: String,
+    /// Behind a lock so the server can be shared between threads like a socket listener
+    incoming: Mutex<Receiver<Stream>>,
+    /// Readable while clients are waiting to be accepted
+    #[cfg(unix)]
+    woken: UnixStream,
+}
+
+impl MemoryListener {
+    /// Listen on `name`, fails like binding a socket that is in use if someone already does
+    pub fn bind(name: &str) -> Result<Self, io::Error> {
+        #[cfg(unix)]
+        let (wake, woken) = UnixStream::pair()?;
+        listeners(|listeners| {
+            if listeners.contains_key(name) {
+                return Err(ErrorKind::AddrInUse.into());
+            }
+            let (sender, incoming) = channel();
+            let entry = Incoming {
+                sender,
+                #[cfg(unix)]
+                wake,
+            };
+            listeners.insert(name.to_string(), entry);
+            Ok(Self {
+                name: name.to_string(),
+                incoming: Mutex::new(incoming),
+                #[cfg(unix)]
+                woken,
+            })
+        })
+    }
+
+    /// Wait for the next client
+    pub fn accept(&self) -> Result<Stream, io::Error> {
+        // We hold on to the sender in the registry as long as we live
+        let stream = self
+            .incoming
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .recv()
+            .map_err(|_| io::Error::from(ErrorKind::BrokenPipe))?;
+        self.accepted()?;
+        Ok(stream)
+    }
+
+    /// The next client if one is waiting
+    pub fn try_accept(&self) -> Result<Option<Stream>, io::Error> {
+        let incoming = self.incoming.lock().unwrap_or_else(PoisonError::into_inner);
+        match incoming.try_recv() {
+            Ok(stream) => {
+                drop(incoming);
+                self.accepted()?;
+                Ok(Some(stream))
+            }
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(ErrorKind::BrokenPipe.into()),
+        }
+    }
+
+    /// Take the byte written for a client, it is written right after the client is sent
+    #[cfg_attr(
+        not(unix),
+        allow(
+            clippy::unnecessary_wraps,
+            clippy::unused_self,
+            clippy::missing_const_for_fn
+        )
+    )]
+    fn accepted(&self) -> Result<(), io::Error> {
+        #[cfg(unix)]
+        (&self.woken).read_exact(&mut [0])?;
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+impl AsFd for MemoryListener {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.woken.as_fd()
+    }
+}
+
+impl Drop for MemoryListener {
+    fn drop(&mut self) {
+        listeners(|listeners| listeners.remove(&self.name));
+    }
+}
+
+/// Connect to the in-memory server listening on `name`, refused like a socket nobody listens on
+/// if there is none.
+pub fn connect(name: &str) -> Result<Stream, io::Error> {
+    let (client, server) = pair()?;
+    let sent = listeners(|listeners| {
+        listeners.get(name).is_some_and(|incoming| {
+            let sent = incoming.sender.send(server).is_ok();
+            #[cfg(unix)]
+            let sent = sent && (&incoming.wake).write_all(&[0]).is_ok();
+            sent
+        })
+    });
+    if sent {
+        Ok(client)
+    } else {
+        Err(ErrorKind::ConnectionRefused.into())
+    }
+}
+
+/// Two connected streams, the same kind the sockets of the file system give us
+#[cfg(unix)]
+fn pair() -> Result<(Stream, Stream), io::Error> {
+    let (a, b) = UnixStream::pair()?;
+    Ok((Stream::UdSocket(a.into()), Stream::UdSocket(b.into())))
+}
+
+#[cfg(not(unix))]
+fn pair() -> Result<(Stream, Stream), io::Error> {
+    Err(io::Error::new(
+        ErrorKind::Unsupported,
+        "in-memory connections are only supported on unix",
+    ))
+}
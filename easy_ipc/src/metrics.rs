@@ -0,0 +1,318 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+/// Number of buckets of a [`Histogram`]
+const BUCKETS: usize = 25;
+
+/// Distribution of latencies, bucketed by powers of two microseconds.
+///
+/// The first bucket counts latencies under a microsecond, every following one latencies under
+/// twice the bound of the previous one. The last bucket counts everything longer, about 8 seconds
+/// and up.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Histogram {
+    counts: [u64; BUCKETS],
+    sum: Duration,
+}
+
+impl Histogram {
+    /// Number of recorded latencies
+    #[must_use]
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Sum of all recorded latencies
+    #[must_use]
+    pub const fn sum(&self) -> Duration {
+        self.sum
+    }
+
+    /// Average latency, `None` if nothing was recorded
+    #[must_use]
+    pub fn mean(&self) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let nanos = self.sum.as_nanos() / u128::from(count);
+        Some(Duration::from_nanos(
+            u64::try_from(nanos).unwrap_or(u64::MAX),
+        ))
+    }
+
+    /// Upper bound of every bucket along with the number of latencies in it. The bound of the
+    /// last bucket is [`Duration::MAX`].
+    pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+        self.counts
+            .iter()
+            .enumerate()
+            .map(|(bucket, count)| (upper_bound(bucket), *count))
+    }
+
+    /// Upper bound of the bucket holding the given quantile, like `0.99` for the 99th
+    /// percentile. `None` if nothing was recorded.
+    #[must_use]
+    pub fn quantile(&self, quantile: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        // The buckets are far coarser than any rounding error
+        #[allow(
+            clippy::cast_possible_truncation,
+            clippy::cast_precision_loss,
+            clippy::cast_sign_loss
+        )]
+        let target = ((quantile.clamp(0.0, 1.0) * count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        self.buckets()
+            .find(|(_, count)| {
+                seen += count;
+                seen >= target
+            })
+            .map(|(bound, _)| bound)
+    }
+}
+
+/// Latencies under this bound go into `bucket`
+fn upper_bound(bucket: usize) -> Duration {
+    match u32::try_from(bucket) {
+        Ok(bucket) if (bucket as usize) < BUCKETS - 1 => Duration::from_micros(1 << bucket),
+        _ => Duration::MAX,
+    }
+}
+
+/// What a [`Histogram`] is a snapshot of
+#[derive(Debug, Default)]
+struct AtomicHistogram {
+    counts: [AtomicU64; BUCKETS],
+    sum_nanos: AtomicU64,
+}
+
+impl AtomicHistogram {
+    fn record(&self, latency: Duration) {
+        let micros = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+        let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+        if let Some(count) = self.counts.get(bucket.min(BUCKETS - 1)) {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+        let nanos = u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX);
+        self.sum_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> Histogram {
+        Histogram {
+            counts: self
+                .counts
+                .each_ref()
+                .map(|count| count.load(Ordering::Relaxed)),
+            sum: Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Messages that went through a connection, or all connections of a server. See
+/// [`Connection::metrics`](crate::connection::Connection::metrics) and [`ServerMetrics`].
+///
+/// Only user messages are counted, not the frames the connection uses for itself like heartbeats.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub struct MessageMetrics {
+    /// Messages written to the other end
+    pub messages_sent: u64,
+    /// Messages read from the other end
+    pub messages_received: u64,
+    /// Bytes of the sent messages as they went over the wire, headers included
+    pub bytes_sent: u64,
+    /// Bytes of the received messages as they went over the wire, headers included
+    pub bytes_received: u64,
+    /// Received messages that failed their checksum or couldn't be decrypted, decompressed or
+    /// deserialized
+    pub decode_errors: u64,
+    /// Time it took to write a message, including waiting for other threads writing
+    pub send_latency: Histogram,
+    /// Time from the header of a message arriving until the message was read and decoded
+    pub receive_latency: Histogram,
+}
+
+/// Counters of a server, see [`Server::metrics`](crate::server::Server::metrics).
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub struct ServerMetrics {
+    /// Connections that were set up and handed out by
+    /// [`Server::connections`](crate::server::Server::connections)
+    pub connections_accepted: u64,
+    /// Connections that are currently open, including ones still being set up
+    pub connections_active: u64,
+    /// Connections that failed to be set up or to authenticate
+    pub connections_rejected: u64,
+    /// Messages of every connection the server ever accepted
+    pub messages: MessageMetrics,
+}
+
+/// What a [`MessageMetrics`] is a snapshot of
+#[derive(Debug, Default)]
+struct MessageCounters {
+    messages_sent: AtomicU64,
+    messages_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    decode_errors: AtomicU64,
+    send_latency: AtomicHistogram,
+    receive_latency: AtomicHistogram,
+}
+
+impl MessageCounters {
+    fn snapshot(&self) -> MessageMetrics {
+        MessageMetrics {
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            decode_errors: self.decode_errors.load(Ordering::Relaxed),
+            send_latency: self.send_latency.snapshot(),
+            receive_latency: self.receive_latency.snapshot(),
+        }
+    }
+}
+
+/// What a [`ServerMetrics`] is a snapshot of, shared with every connection of the server
+#[derive(Debug, Default)]
+pub(crate) struct ServerCounters {
+    accepted: AtomicU64,
+    active: AtomicU64,
+    rejected: AtomicU64,
+    messages: MessageCounters,
+}
+
+impl ServerCounters {
+    /// A connection was set up and handed out
+    pub(crate) fn accepted(&self) {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        ::metrics::counter!("easy_ipc_connections_accepted").increment(1);
+    }
+
+    /// A connection failed to be set up or to authenticate
+    pub(crate) fn rejected(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        ::metrics::counter!("easy_ipc_connections_rejected").increment(1);
+    }
+
+    pub(crate) fn snapshot(&self) -> ServerMetrics {
+        ServerMetrics {
+            connections_accepted: self.accepted.load(Ordering::Relaxed),
+            connections_active: self.active.load(Ordering::Relaxed),
+            connections_rejected: self.rejected.load(Ordering::Relaxed),
+            messages: self.messages.snapshot(),
+        }
+    }
+}
+
+/// Counters of a single connection, which also add up in the counters of the server that
+/// accepted it.
+///
+/// With the `metrics` feature everything is also reported through the `metrics` crate facade,
+/// labeled with the side of the connection.
+#[derive(Debug)]
+pub(crate) struct ConnectionCounters {
+    messages: MessageCounters,
+    server: Option<Arc<ServerCounters>>,
+}
+
+impl ConnectionCounters {
+    /// Counters of a new connection, `server` is `None` for clients
+    pub(crate) fn new(server: Option<Arc<ServerCounters>>) -> Self {
+        if let Some(server) = &server {
+            server.active.fetch_add(1, Ordering::Relaxed);
+            #[cfg(feature = "metrics")]
+            ::metrics::gauge!("easy_ipc_connections_active").increment(1);
+        }
+        Self {
+            messages: MessageCounters::default(),
+            server,
+        }
+    }
+
+    /// A message of `bytes` bytes was written in `latency`
+    pub(crate) fn sent(&self, bytes: usize, latency: Duration) {
+        for counters in self.counters() {
+            counters.messages_sent.fetch_add(1, Ordering::Relaxed);
+            counters
+                .bytes_sent
+                .fetch_add(bytes as u64, Ordering::Relaxed);
+            counters.send_latency.record(latency);
+        }
+        #[cfg(feature = "metrics")]
+        {
+            let side = self.side();
+            ::metrics::counter!("easy_ipc_messages_sent", "side" => side).increment(1);
+            ::metrics::counter!("easy_ipc_bytes_sent", "side" => side).increment(bytes as u64);
+            ::metrics::histogram!("easy_ipc_send_latency_seconds", "side" => side)
+                .record(latency.as_secs_f64());
+        }
+    }
+
+    /// A message of `bytes` bytes was read in `latency`
+    pub(crate) fn received(&self, bytes: usize, latency: Duration) {
+        for counters in self.counters() {
+            counters.messages_received.fetch_add(1, Ordering::Relaxed);
+            counters
+                .bytes_received
+                .fetch_add(bytes as u64, Ordering::Relaxed);
+            counters.receive_latency.record(latency);
+        }
+        #[cfg(feature = "metrics")]
+        {
+            let side = self.side();
+            ::metrics::counter!("easy_ipc_messages_received", "side" => side).increment(1);
+            ::metrics::counter!("easy_ipc_bytes_received", "side" => side).increment(bytes as u64);
+            ::metrics::histogram!("easy_ipc_receive_latency_seconds", "side" => side)
+                .record(latency.as_secs_f64());
+        }
+    }
+
+    /// A received message couldn't be decoded
+    pub(crate) fn decode_error(&self) {
+        for counters in self.counters() {
+            counters.decode_errors.fetch_add(1, Ordering::Relaxed);
+        }
+        #[cfg(feature = "metrics")]
+        ::metrics::counter!("easy_ipc_decode_errors", "side" => self.side()).increment(1);
+    }
+
+    pub(crate) fn snapshot(&self) -> MessageMetrics {
+        self.messages.snapshot()
+    }
+
+    /// Our own counters and those of the server
+    fn counters(&self) -> impl Iterator<Item = &MessageCounters> {
+        std::iter::once(&self.messages).chain(self.server.as_deref().map(|server| &server.messages))
+    }
+
+    #[cfg(feature = "metrics")]
+    const fn side(&self) -> &'static str {
+        if self.server.is_some() {
+            "server"
+        } else {
+            "client"
+        }
+    }
+}
+
+impl Drop for ConnectionCounters {
+    fn drop(&mut self) {
+        if let Some(server) = &self.server {
+            server.active.fetch_sub(1, Ordering::Relaxed);
+            #[cfg(feature = "metrics")]
+            ::metrics::gauge!("easy_ipc_connections_active").decrement(1);
+        }
+    }
+}
@@ -1,11 +1,33 @@
-use std::{marker::PhantomData, sync::atomic::AtomicBool};
+use std::{
+    fs::File,
+    marker::PhantomData,
+    process::{Child, Command, Stdio},
+    sync::{Arc, atomic::AtomicBool},
+    time::{Duration, Instant},
+};
 
 use interprocess::local_socket::{GenericNamespaced, ToNsName};
 
+use crate::capture::CaptureFile;
+use crate::checksum::Checksum;
+use crate::compression::Compression;
+use crate::fault::FaultyTransport;
 use crate::handlers::setup_handlers;
+use crate::memory::{self, MemoryListener};
+#[cfg(feature = "noise")]
+use crate::noise::{Keypair, NoiseOptions};
+use crate::pipe;
+use crate::schema::{ModelSchema, Schema};
+use crate::token;
+use crate::trace;
 
 use {
-    crate::{client::Client, error::InitError, server::Server},
+    crate::{
+        client::Client,
+        connection::{Connection, Side},
+        error::InitError,
+        server::{Listener, Server},
+    },
     interprocess::local_socket::{GenericFilePath, ListenerOptions, Name, Stream, prelude::*},
     serde::{Deserialize, Serialize},
     std::path::{Path, PathBuf},
@@ -19,10 +41,36 @@ pub(crate) struct OptionsRaw {
     pub(crate) socket_name: PathBuf,
     pub(crate) magic_bytes: Vec<u8>,
     pub(crate) disable_single_server_check: bool,
+    /// Hash of the message schema that gets appended to the magic bytes
+    pub(crate) schema_hash: Option<u64>,
+    /// Number of messages a multiplexed channel can have in flight before the sender waits
+    pub(crate) channel_window: u32,
+    /// Interval between heartbeats and how many can be missed in a row
+    pub(crate) heartbeat: Option<(Duration, u32)>,
+    /// How long [`IpcModel::client_or_spawn`] waits for a spawned server
+    pub(crate) spawn_timeout: Duration,
+    /// How long a server waits for a client to set up the connection
+    pub(crate) auth_timeout: Duration,
+    pub(crate) compression: Compression,
+    /// Messages smaller than this many bytes are never compressed
+    pub(crate) compression_threshold: usize,
+    /// Longest message that is received, compressed ones once decompressed
+    pub(crate) max_message_size: usize,
+    pub(crate) checksum: Checksum,
+    /// File holding the token clients need to present
+    pub(crate) token_file: Option<PathBuf>,
+    /// Connect in the process instead of over a socket, the socket name is the name of the server
+    pub(crate) in_memory: bool,
+    /// Where frames are captured to, shared by all connections
+    pub(crate) capture: Option<Arc<CaptureFile>>,
+    /// What clients connect through to have their frames damaged
+    pub(crate) faults: Option<Arc<FaultyTransport>>,
+    #[cfg(feature = "noise")]
+    pub(crate) noise: NoiseOptions,
 }
 
 impl OptionsRaw {
-    fn new<P>(namespace: P) -> Self
+    pub(crate) fn new<P>(namespace: P) -> Self
     where
         P: AsRef<Path>,
     {
@@ -30,6 +78,21 @@ impl OptionsRaw {
             socket_name: namespace.as_ref().to_path_buf(),
             magic_bytes: b"4242".to_vec(),
             disable_single_server_check: false,
+            schema_hash: None,
+            channel_window: 64,
+            heartbeat: None,
+            spawn_timeout: Duration::from_secs(5),
+            auth_timeout: Duration::from_secs(5),
+            compression: Compression::None,
+            compression_threshold: 256,
+            max_message_size: 64 << 20,
+            checksum: Checksum::None,
+            token_file: None,
+            in_memory: false,
+            capture: None,
+            faults: None,
+            #[cfg(feature = "noise")]
+            noise: NoiseOptions::default(),
         }
     }
 }
@@ -71,6 +134,26 @@ where
         }
     }
 
+    /// Creates a model whose clients and server connect within the current process, made for
+    /// tests.
+    ///
+    /// Servers listen under `name` in a registry of the process instead of on a socket, clients
+    /// of a model with the same name connect to them over a socket pair. Nothing is created on
+    /// the file system, no signal handlers are set up and any number of servers can run at the
+    /// same time, so tests using distinct names can run in parallel without cleaning up after each
+    /// other. [`Server`], [`Client`] and their connections work as usual.
+    ///
+    /// Only supported on unix, elsewhere connecting fails.
+    pub fn in_memory<N>(name: N) -> Self
+    where
+        N: Into<String>,
+    {
+        let mut options = Self::new(name.into()).handlers(|_model| {});
+        options.options_inner.in_memory = true;
+        options.options_inner.disable_single_server_check = true;
+        options
+    }
+
     /// NOT RECOMMENDED: Set the magic bytes used in the packets to validate client and server
     /// methods.
     ///
@@ -118,12 +201,236 @@ where
         self
     }
 
+    /// Set how many messages each channel of a [`crate::mux::Multiplexer`] can have in flight
+    /// before the sender has to wait for the receiver to catch up. Defaults to 64.
+    ///
+    /// Both ends need to agree on this value. A window of 0 is treated as 1.
+    #[must_use]
+    pub fn channel_window(mut self, messages: u32) -> Self {
+        self.options_inner.channel_window = messages.max(1);
+        self
+    }
+
+    /// Send a heartbeat to the other end every `interval` and consider it gone once it missed
+    /// `missed_limit` of its heartbeats in a row.
+    ///
+    /// Heartbeats are handled inside the connection, they are never seen as messages. Waiting on
+    /// an unresponsive peer, for instance in [`Connection::receive`], fails with
+    /// [`ConnectionError::PeerUnresponsive`]. Time spent not waiting on the peer doesn't count, so
+    /// a server that takes long to handle a request doesn't time out its idle client.
+    ///
+    /// Both ends need to enable heartbeats, a peer without them looks unresponsive. Interrupting a
+    /// blocked read is only supported on unix, on other platforms the error is returned by the
+    /// next read.
+    ///
+    /// [`Connection::receive`]: crate::connection::Connection::receive
+    /// [`ConnectionError::PeerUnresponsive`]: crate::error::ConnectionError::PeerUnresponsive
+    #[must_use]
+    pub const fn heartbeat(mut self, interval: Duration, missed_limit: u32) -> Self {
+        self.options_inner.heartbeat = Some((interval, missed_limit));
+        self
+    }
+
+    /// Set how long [`IpcModel::client_or_spawn`] waits for a server it started to accept
+    /// connections. Defaults to 5 seconds.
+    #[must_use]
+    pub const fn spawn_timeout(mut self, timeout: Duration) -> Self {
+        self.options_inner.spawn_timeout = timeout;
+        self
+    }
+
+    /// Set how long a server waits for a client to present its token and finish the Noise
+    /// handshake before refusing it, see [`ClientServerOptions::token_auth`]. Defaults to 5
+    /// seconds.
+    ///
+    /// Only supported on unix, on other platforms a client that stops talking while setting up
+    /// keeps [`Server::connections`] waiting.
+    ///
+    /// [`Server::connections`]: crate::server::Server::connections
+    #[must_use]
+    pub const fn auth_timeout(mut self, timeout: Duration) -> Self {
+        self.options_inner.auth_timeout = timeout;
+        self
+    }
+
+    /// Compress messages with the given algorithm, the algorithms are behind cargo features.
+    ///
+    /// Messages are only compressed if they are at least 256 bytes, see
+    /// [`ClientServerOptions::compression_threshold`], and if they actually get smaller. Each end
+    /// tells the other which algorithms it can decompress when connecting, we only compress once
+    /// the other end said it supports the algorithm. That is picked up before sending too, so
+    /// ends that only send compress as well once it arrived. Peers without compression enabled
+    /// are sent uncompressed messages. Compressed messages are refused if they decompress to more
+    /// than [`ClientServerOptions::max_message_size`].
+    #[must_use]
+    pub const fn compression(mut self, compression: Compression) -> Self {
+        self.options_inner.compression = compression;
+        self
+    }
+
+    /// Only compress messages of at least `bytes` bytes, small messages rarely get smaller.
+    #[must_use]
+    pub const fn compression_threshold(mut self, bytes: usize) -> Self {
+        self.options_inner.compression_threshold = bytes;
+        self
+    }
+
+    /// Refuse to receive messages longer than `bytes` bytes, defaults to 64 MiB.
+    ///
+    /// The length is checked before the message is read, longer frames fail with
+    /// [`ConnectionError::PacketTooLarge`]. Compressed messages are checked once more while they
+    /// are decompressed and fail with [`ConnectionError::CompressionFailed`] if they get longer.
+    /// Both ends should agree on it, a longer message ends the connection.
+    ///
+    /// [`ConnectionError::PacketTooLarge`]: crate::error::ConnectionError::PacketTooLarge
+    /// [`ConnectionError::CompressionFailed`]: crate::error::ConnectionError::CompressionFailed
+    #[must_use]
+    pub const fn max_message_size(mut self, bytes: usize) -> Self {
+        self.options_inner.max_message_size = bytes;
+        self
+    }
+
+    /// Add a checksum to every frame, the algorithms are behind cargo features.
+    ///
+    /// The header and the payload are checked separately, a frame that doesn't match its
+    /// checksum makes the receiving end fail with [`ConnectionError::ChecksumMismatch`]. The
+    /// checksum is part of the frame format, so both ends need to use the same one.
+    ///
+    /// [`ConnectionError::ChecksumMismatch`]: crate::error::ConnectionError::ChecksumMismatch
+    #[must_use]
+    pub const fn checksum(mut self, checksum: Checksum) -> Self {
+        self.options_inner.checksum = checksum;
+        self
+    }
+
+    /// Only accept clients that can read the token file at `path`.
+    ///
+    /// The server writes a new random token to the file every time it starts, or when asked to
+    /// with [`Server::rotate_token`]. On unix only the user running the server can read it.
+    /// Clients read the token when connecting and send it before anything else, the server
+    /// refuses connections with a wrong token with [`ConnectionError::Unauthorized`] before any
+    /// message is received on them. Such a client only notices when the server hangs up.
+    ///
+    /// This is lighter than [`ClientServerOptions::noise`] but doesn't encrypt anything, it is
+    /// meant for local sockets where other users could connect. Clients that don't send their
+    /// token within [`ClientServerOptions::auth_timeout`] are refused as well.
+    ///
+    /// [`ConnectionError::Unauthorized`]: crate::error::ConnectionError::Unauthorized
+    #[must_use]
+    pub fn token_auth<P>(mut self, path: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        self.options_inner.token_file = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Write every frame of every connection to the file at `path`, along with when it was seen,
+    /// which way it went and which connection it belongs to.
+    ///
+    /// Frames are captured after decryption and decompression, frames the connection uses for
+    /// itself like heartbeats and tokens are left out. Captures are appended to the file, so
+    /// several processes can capture to the same one. On unix only the owning user can read a
+    /// file created for a capture. Read it back with [`Recording::read`] to
+    /// look at the frames or to replay a connection in a regression test.
+    ///
+    /// Capturing slows every frame down with a write to the file, it is meant for debugging.
+    ///
+    /// [`Recording::read`]: crate::capture::Recording::read
+    #[must_use]
+    pub fn capture<P>(mut self, path: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        let file = CaptureFile::new(path.as_ref().to_path_buf());
+        self.options_inner.capture = Some(Arc::new(file));
+        self
+    }
+
+    /// Connect clients through `transport`, which damages their frames to test how the
+    /// application copes with broken connections. See [`FaultyTransport`] for what can go wrong.
+    ///
+    /// Meant for tests only, every client connection runs two threads passing its frames on.
+    #[must_use]
+    pub fn faults(mut self, transport: FaultyTransport) -> Self {
+        self.options_inner.faults = Some(Arc::new(transport));
+        self
+    }
+
+    /// Encrypt and authenticate connections with the Noise protocol, `keypair` is the static key
+    /// of this end.
+    ///
+    /// The handshake runs right after connecting and every frame after it is encrypted. Both
+    /// ends learn the static key of the other during the handshake, clients that know the key of
+    /// the server ahead of time should set it with [`ClientServerOptions::noise_server_key`].
+    /// Servers accept any client unless [`ClientServerOptions::noise_allowed_clients`] is set.
+    ///
+    /// Frame headers, which hold the kind and length of frames, are sent in the clear but
+    /// authenticated, frames with a changed header or without a valid tag fail. Both ends need
+    /// to enable this, a handshake with an end that doesn't fails. Making a client waits until
+    /// the server accepted the connection and the handshake is done.
+    #[cfg(feature = "noise")]
+    #[must_use]
+    pub fn noise(mut self, keypair: Keypair) -> Self {
+        self.options_inner.noise.keypair = Some(keypair);
+        self
+    }
+
+    /// The public key of the server, only used by clients.
+    ///
+    /// Clients only talk to a server with this key, and use the shorter Noise IK handshake.
+    /// Without it clients accept any server.
+    #[cfg(feature = "noise")]
+    #[must_use]
+    pub fn noise_server_key(mut self, public_key: impl Into<Vec<u8>>) -> Self {
+        self.options_inner.noise.server_key = Some(public_key.into());
+        self
+    }
+
+    /// The public keys of the clients that may connect, only used by servers.
+    ///
+    /// Connections of other clients fail with [`std::io::ErrorKind::PermissionDenied`] once the
+    /// handshake is done.
+    #[cfg(feature = "noise")]
+    #[must_use]
+    pub fn noise_allowed_clients<I>(mut self, public_keys: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<Vec<u8>>,
+    {
+        self.options_inner.noise.allowed_clients =
+            Some(public_keys.into_iter().map(Into::into).collect());
+        self
+    }
+
     /// Create a new client-server model with the given options
-    pub fn create(self) -> ClientServerModel<C, S> {
+    pub fn create(mut self) -> ClientServerModel<C, S> {
+        if let Some(hash) = self.options_inner.schema_hash {
+            self.options_inner
+                .magic_bytes
+                .extend_from_slice(&hash.to_le_bytes());
+        }
         ClientServerModel::new(self)
     }
 }
 
+impl<C, S> ClientServerOptions<C, S>
+where
+    C: Serialize + for<'de> Deserialize<'de> + Schema,
+    S: Serialize + for<'de> Deserialize<'de> + Schema,
+{
+    /// Include a hash of the message layout in the header of every packet.
+    ///
+    /// Clients and servers that disagree on the layout of the messages will then fail with
+    /// [`crate::error::ConnectionError::HeaderMismatch`] instead of sending messages that get
+    /// decoded into garbage. See [`ModelSchema::hash`].
+    #[must_use]
+    pub fn schema_fingerprint(mut self) -> Self {
+        self.options_inner.schema_hash = Some(ModelSchema::new::<C, S>().hash());
+        self
+    }
+}
+
 /// A specific model for interprocess communication, including what messages that can be sent back
 /// and forth as well as options used to create a model.
 ///
@@ -182,6 +489,35 @@ pub trait IpcModel {
         Self::model()?.client()
     }
 
+    /// Make a new client, starting the server with `command` if it isn't running.
+    ///
+    /// If there is nobody listening on the socket, the server is spawned detached from the
+    /// current process, so it keeps running after we exit, and we wait for it to accept
+    /// connections, see [`ClientServerOptions::spawn_timeout`]. A lock file next to the socket
+    /// makes sure that only one server is started when several clients do this at the same time.
+    ///
+    /// The standard input and outputs of the server are always set to null, whatever `command`
+    /// says, nobody would read them once we exit. The server is not killed when the client is
+    /// dropped, a thread waits for it in the background so it doesn't linger as a zombie if it
+    /// exits before we do.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`InitError::FailedSpawningServer`] if the server can't be started,
+    /// [`InitError::SpawnedServerExited`] if it exits before accepting connections and
+    /// [`InitError::SpawnTimedOut`] if it doesn't accept them in time. Otherwise fails like
+    /// [`IpcModel::client`].
+    ///
+    /// This should not be implemented (in fact it is not possible).
+    fn client_or_spawn(
+        command: Command,
+    ) -> Result<Client<Self::ClientMsg, Self::ServerMsg>, InitError>
+    where
+        Self: Sized,
+    {
+        Self::model()?.client_or_spawn(command)
+    }
+
     /// Try to create a new server instance.
     ///
     /// Needs to be created before clients. Only one server can exist at a time on a given host.
@@ -193,6 +529,47 @@ pub trait IpcModel {
     {
         Self::model()?.server()
     }
+
+    /// Start `command` as a child process and make a client that talks to it over its standard
+    /// input and output, LSP-style. No socket is involved.
+    ///
+    /// The child serves the connection with [`IpcModel::stdio_server`]. It is meant to live as
+    /// long as the client, dropping the client closes its standard input. The standard error is
+    /// left as `command` says, by default it is the one of the current process. Token
+    /// authentication doesn't apply, only we can talk to the child. Only supported on unix.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`InitError::FailedSpawningServer`] if the child can't be started and
+    /// [`InitError::FailedConnectingToSocket`] if talking to it can't be set up, for instance
+    /// on other platforms than unix.
+    ///
+    /// This should not be implemented (in fact it is not possible).
+    fn spawn_child(command: Command) -> Result<Client<Self::ClientMsg, Self::ServerMsg>, InitError>
+    where
+        Self: Sized,
+    {
+        Self::model()?.spawn_child(command)
+    }
+
+    /// Serve the parent process over the standard input and output, in a child started with
+    /// [`IpcModel::spawn_child`].
+    ///
+    /// Nothing else may write to the standard output, printing to it would corrupt the frames.
+    /// Print to the standard error instead.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`InitError::FailedConnectingToSocket`] if talking to the parent can't be set
+    /// up, for instance on other platforms than unix.
+    ///
+    /// This should not be implemented (in fact it is not possible).
+    fn stdio_server() -> Result<Connection<Self::ServerMsg, Self::ClientMsg>, InitError>
+    where
+        Self: Sized,
+    {
+        Self::model()?.stdio_server()
+    }
 }
 
 /// A model for a Client Server IPC interface. Client messages are denoted by the generic `C` and
@@ -229,10 +606,107 @@ where
     }
     /// Make a new client, errors if unable to connect to server. Multiple clients can exist across
     /// threads and processes.
-    fn client(self) -> Result<Client<C, S>, InitError> {
-        let name = pathbuf_to_interprocess_name(&self.options.options_inner.socket_name)?;
-        let stream = Stream::connect(name).map_err(InitError::FailedConnectingToSocket)?;
-        Ok(Client::new(self.options.options_inner, stream))
+    ///
+    /// Prefer [`IpcModel::client`], which makes sure the client uses the same model as the
+    /// server. This is for tools that are told what the server looks like at runtime.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`IpcModel::client`].
+    pub fn client(self) -> Result<Client<C, S>, InitError> {
+        let stream = self
+            .connect()?
+            .map_err(InitError::FailedConnectingToSocket)?;
+        Client::new(self.options.options_inner, stream)
+    }
+
+    /// Connect to the socket, the inner error is the one of the connection attempt itself
+    fn connect(&self) -> Result<Result<Stream, std::io::Error>, InitError> {
+        let opts = &self.options.options_inner;
+        let stream = if opts.in_memory {
+            memory::connect(&self.memory_name())
+        } else {
+            let name = pathbuf_to_interprocess_name(&opts.socket_name)?;
+            Stream::connect(name)
+        };
+        Ok(match &opts.faults {
+            Some(faults) => stream.and_then(|stream| faults.wrap(stream, opts)),
+            None => stream,
+        })
+    }
+
+    /// Make a new client, spawning the server with `command` if nobody is listening.
+    ///
+    /// See [`IpcModel::client_or_spawn`].
+    fn client_or_spawn(self, mut command: Command) -> Result<Client<C, S>, InitError> {
+        let stream = match self.connect()? {
+            Ok(stream) => stream,
+            Err(e) if server_missing(&e) => {
+                let lock_path = lock_path(&self.options.options_inner.socket_name);
+                let lock = File::create(lock_path).map_err(InitError::FailedSpawningServer)?;
+                lock.lock().map_err(InitError::FailedSpawningServer)?;
+                // Someone else might have started the server while we waited for the lock
+                match self.connect()? {
+                    Ok(stream) => stream,
+                    Err(e) if server_missing(&e) => self.spawn_server(&mut command)?,
+                    Err(e) => return Err(InitError::FailedConnectingToSocket(e)),
+                }
+                // Dropping the file releases the lock
+            }
+            Err(e) => return Err(InitError::FailedConnectingToSocket(e)),
+        };
+        Client::new(self.options.options_inner, stream)
+    }
+
+    /// Start the server and wait until it accepts connections
+    fn spawn_server(&self, command: &mut Command) -> Result<Stream, InitError> {
+        command
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        // Keep terminal signals, like Ctrl-C, meant for us away from the server
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(command, 0);
+        let mut child = command.spawn().map_err(InitError::FailedSpawningServer)?;
+
+        let start = Instant::now();
+        let connected = loop {
+            match self.connect()? {
+                Ok(stream) => break Ok(stream),
+                Err(e) if server_missing(&e) => (),
+                Err(e) => break Err(InitError::FailedConnectingToSocket(e)),
+            }
+            if let Some(status) = child.try_wait().map_err(InitError::FailedSpawningServer)? {
+                return Err(InitError::SpawnedServerExited(status));
+            }
+            if start.elapsed() > self.options.options_inner.spawn_timeout {
+                break Err(InitError::SpawnTimedOut);
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        reap(child);
+        connected
+    }
+
+    /// Start a child process and talk to it over pipes, see [`IpcModel::spawn_child`]
+    fn spawn_child(self, mut command: Command) -> Result<Client<C, S>, InitError> {
+        command.stdin(Stdio::piped()).stdout(Stdio::piped());
+        let child = command.spawn().map_err(InitError::FailedSpawningServer)?;
+        let (output, input) = pipe::child(child).map_err(InitError::FailedSpawningServer)?;
+        let stream = pipe::wrap(output, input).map_err(InitError::FailedConnectingToSocket)?;
+        let mut opts = self.options.options_inner;
+        opts.token_file = None;
+        Client::new(opts, stream)
+    }
+
+    /// Serve the parent process, see [`IpcModel::stdio_server`]
+    fn stdio_server(self) -> Result<Connection<S, C>, InitError> {
+        let stream = pipe::wrap(std::io::stdin(), std::io::stdout())
+            .map_err(InitError::FailedConnectingToSocket)?;
+        let mut opts = self.options.options_inner;
+        opts.token_file = None;
+        Connection::new(stream, Arc::new(opts), Side::Server, None, None)
+            .map_err(InitError::FailedConnectingToSocket)
     }
 
     /// Try to create a new server instance.
@@ -245,6 +719,15 @@ where
         self.server_with_opts(opts)
     }
 
+    /// Name of the server of an in-memory model
+    fn memory_name(&self) -> String {
+        self.options
+            .options_inner
+            .socket_name
+            .to_string_lossy()
+            .into_owned()
+    }
+
     /// Get a reference to the internal options
     #[cfg(test)]
     pub(crate) fn options(&self) -> &OptionsRaw {
@@ -255,13 +738,21 @@ where
     ///
     /// See: [`ClientServerModel::server`]
     fn server_with_opts(self, opts: ListenerOptions) -> Result<Server<S, C>, InitError> {
-        let name = pathbuf_to_interprocess_name(&self.options.options_inner.socket_name)?;
-        let opts = opts.name(name);
-        // Can fail for IO reasons
-        let listener = opts.create_sync().map_err(|e| match e {
-            // Server is already running on the socket or the cleanup of the file failed
-            e if e.kind() == std::io::ErrorKind::AddrInUse => InitError::SocketAlreadyExists,
-            e => InitError::FailedConnectingToSocket(e),
+        let span = trace::server(&self.options.options_inner.socket_name);
+        let listener = if self.options.options_inner.in_memory {
+            MemoryListener::bind(&self.memory_name()).map(Listener::Memory)
+        } else {
+            let name = pathbuf_to_interprocess_name(&self.options.options_inner.socket_name)?;
+            // Can fail for IO reasons
+            opts.name(name).create_sync().map(Listener::Socket)
+        };
+        let listener = listener.map_err(|e| {
+            trace::error(&span, "failed binding the socket", &e);
+            match e {
+                // Server is already running on the socket or the cleanup of the file failed
+                e if e.kind() == std::io::ErrorKind::AddrInUse => InitError::SocketAlreadyExists,
+                e => InitError::FailedConnectingToSocket(e),
+            }
         })?;
         // Gaurentee that there is only one server running in the current process.
         let server_lock = SERVER_RUNNING.fetch_or(true, std::sync::atomic::Ordering::Relaxed);
@@ -274,12 +765,76 @@ where
         // server's socket. We also need to setup handlers after we have gaurenteed that a server
         // hasn't already been spawned to ensure we don't try to setup two instances of handlers.
         (self.options.handler)(&self);
-        Ok(Server::new(listener, self.options.options_inner))
+        // Only once we own the socket, the token of a running server must not be replaced
+        let token = match &self.options.options_inner.token_file {
+            Some(path) => Some(token::generate(path).map_err(InitError::TokenFile)?),
+            None => None,
+        };
+        trace::info(&span, "listening");
+        Ok(Server::new(
+            listener,
+            self.options.options_inner,
+            token,
+            span,
+        ))
+    }
+}
+
+impl<C, S> ClientServerModel<C, S>
+where
+    C: Serialize + for<'de> Deserialize<'de> + Schema,
+    S: Serialize + for<'de> Deserialize<'de> + Schema,
+{
+    /// Get the structural description of the client and server messages
+    #[must_use]
+    pub fn schema(&self) -> ModelSchema {
+        ModelSchema::new::<C, S>()
     }
+
+    /// Get the description of the client and server messages as pretty printed JSON.
+    ///
+    /// This is handy to commit next to your code so that changes to the messages show up in
+    /// review.
+    #[must_use]
+    pub fn schema_json(&self) -> String {
+        self.schema().to_json()
+    }
+}
+
+/// Wait for a spawned server in the background, so that it doesn't stay around as a zombie when
+/// it exits before we do
+fn reap(mut child: Child) {
+    // Without the thread the server still runs, it is only left unreaped
+    std::thread::Builder::new()
+        .name("easy_ipc reaper".to_string())
+        .spawn(move || child.wait())
+        .ok();
+}
+
+/// Errors when connecting that mean there is no server running
+fn server_missing(error: &std::io::Error) -> bool {
+    matches!(
+        error.kind(),
+        std::io::ErrorKind::NotFound | std::io::ErrorKind::ConnectionRefused
+    )
+}
+
+/// Path of the lock file guarding against spawning a server twice.
+///
+/// Namespaced sockets don't live on the file system, so their lock goes in the temporary
+/// directory.
+fn lock_path(socket_name: &Path) -> PathBuf {
+    let mut path = if socket_name.iter().count() == 1 {
+        std::env::temp_dir().join(socket_name).into_os_string()
+    } else {
+        socket_name.as_os_str().to_owned()
+    };
+    path.push(".lock");
+    PathBuf::from(path)
 }
 
 /// Converts [`PathBuf`] to [`Name`] using consistent method
-fn pathbuf_to_interprocess_name<'a, P>(path: P) -> Result<Name<'a>, InitError>
+pub(crate) fn pathbuf_to_interprocess_name<'a, P>(path: P) -> Result<Name<'a>, InitError>
 where
     P: AsRef<Path> + 'a,
 {
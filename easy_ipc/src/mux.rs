@@ -0,0 +1,499 @@
+use {
+    crate::{
+        client::Client,
+        connection::{Connection, Frame, FrameKind, MAIN_CHANNEL, RawConnection},
+        error::ConnectionError,
+        model::IpcModel,
+    },
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::{HashMap, VecDeque},
+        marker::PhantomData,
+        sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError},
+    },
+};
+
+/// Channels the other end may send to before we open them, more break the multiplexer
+const MAX_UNOPENED_CHANNELS: usize = 64;
+
+/// Marker for the client end of a [`Multiplexer`]
+#[derive(Debug)]
+pub enum ClientEnd {}
+
+/// Marker for the server end of a [`Multiplexer`]
+#[derive(Debug)]
+pub enum ServerEnd {}
+
+/// Runs several independently typed channels over a single connection.
+///
+/// Both ends open channels by name with `open_channel::<C, S>(name)`, where `C` is the client
+/// message and `S` is the server message of the channel, the same way as for a
+/// [`ClientServerOptions`](crate::model::ClientServerOptions). Messages sent on a channel before
+/// the other end opened it are kept until it does, for up to 64 channels. This way a single
+/// server socket can host several [`IpcModel`]s, see [`Multiplexer::open_model`].
+///
+/// Once both ends closed a channel, its name can be opened again.
+///
+/// Channel ids are derived from the names, so opening a channel needs no round trip. Its name is
+/// sent along anyway, should two different names map to the same id, both channels fail with
+/// [`ConnectionError::ChannelNameCollision`] instead of being mixed up.
+///
+/// Every channel has its own flow control: at most
+/// [`ClientServerOptions::channel_window`](crate::model::ClientServerOptions::channel_window)
+/// messages can be in flight, after that [`Channel::send`] waits for the other end to receive
+/// some of them. A slow channel therefore never holds up the others.
+///
+/// There is no background thread, whichever channel is waiting reads from the connection and
+/// hands frames for other channels over to them. Channels can be moved to different threads.
+///
+/// # Example
+///
+/// ```no_run
+/// use easy_ipc::{mux::Multiplexer, prelude::*};
+///
+/// # fn run<Host, Jobs, Logs>()
+/// # where
+/// #     Host: IpcModel,
+/// #     Jobs: IpcModel<ClientMsg = String, ServerMsg = u64>,
+/// #     Logs: IpcModel<ClientMsg = String, ServerMsg = ()>,
+/// # {
+/// // Client
+/// let mux = Host::client().unwrap().into_multiplexer();
+/// let mut jobs = mux.open_model::<Jobs>("jobs").unwrap();
+/// let mut logs = mux.open_model::<Logs>("logs").unwrap();
+/// jobs.send("build".to_string()).unwrap();
+/// logs.send("started a build".to_string()).unwrap();
+/// let job_id = jobs.receive().unwrap();
+///
+/// // Server
+/// for conn in Host::server().unwrap().connections() {
+///     let mux = Multiplexer::server(conn.unwrap());
+///     let mut jobs = mux.open_model::<Jobs>("jobs").unwrap();
+///     let job = jobs.receive().unwrap();
+///     jobs.send(1).unwrap();
+/// }
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Multiplexer<E> {
+    shared: Arc<Shared>,
+    _end: PhantomData<E>,
+}
+
+impl<E> Multiplexer<E> {
+    fn new(raw: Arc<RawConnection>) -> Self {
+        let window = raw.options().channel_window;
+        Self {
+            shared: Arc::new(Shared {
+                raw,
+                state: Mutex::new(State::default()),
+                changed: Condvar::new(),
+                window,
+            }),
+            _end: PhantomData,
+        }
+    }
+
+    fn open<T, R>(&self, name: &str) -> Result<Channel<T, R>, ConnectionError>
+    where
+        T: Serialize,
+        R: for<'de> Deserialize<'de>,
+    {
+        let id = channel_id(name);
+        let mut state = self.shared.state();
+        let channel = state.channel(id, self.shared.window);
+        // Also set while the other end hasn't closed it since we did
+        if let Some(open) = &channel.name {
+            return Err(if open == name {
+                ConnectionError::ChannelInUse(name.to_owned())
+            } else {
+                ConnectionError::ChannelNameCollision(name.to_owned(), open.clone())
+            });
+        }
+        if let Some(other) = channel.remote_name.as_ref().filter(|other| *other != name) {
+            return Err(ConnectionError::ChannelNameCollision(
+                name.to_owned(),
+                other.clone(),
+            ));
+        }
+        channel.name = Some(name.to_owned());
+        drop(state);
+        let channel = Channel {
+            shared: self.shared.clone(),
+            id,
+            _tx: PhantomData,
+            _rx: PhantomData,
+        };
+        self.shared
+            .raw
+            .write_channel_frame(FrameKind::ChannelOpen, id, name.as_bytes())?;
+        Ok(channel)
+    }
+}
+
+impl Multiplexer<ClientEnd> {
+    /// Multiplex the connection of a client, same as [`Client::into_multiplexer`].
+    #[must_use]
+    pub fn client<C, S>(client: Client<C, S>) -> Self
+    where
+        C: Serialize + for<'de> Deserialize<'de>,
+        S: Serialize + for<'de> Deserialize<'de>,
+    {
+        Self::new(client.into_connection().into_raw())
+    }
+
+    /// Open the channel called `name`, sending client messages `C` and receiving server
+    /// messages `S`.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`ConnectionError::ChannelInUse`] if the channel is open, or the server didn't
+    /// close it yet since we did, and with [`ConnectionError::ChannelNameCollision`] if another
+    /// channel has the same id.
+    pub fn open_channel<C, S>(&self, name: &str) -> Result<Channel<C, S>, ConnectionError>
+    where
+        C: Serialize,
+        S: for<'de> Deserialize<'de>,
+    {
+        self.open(name)
+    }
+
+    /// Open the channel called `name` with the messages of the model `M`.
+    ///
+    /// # Errors
+    ///
+    /// Fails like `open_channel`.
+    pub fn open_model<M>(
+        &self,
+        name: &str,
+    ) -> Result<Channel<M::ClientMsg, M::ServerMsg>, ConnectionError>
+    where
+        M: IpcModel,
+    {
+        self.open(name)
+    }
+}
+
+impl Multiplexer<ServerEnd> {
+    /// Multiplex a connection accepted by a [`Server`](crate::server::Server).
+    #[must_use]
+    pub fn server<T, R>(connection: Connection<T, R>) -> Self
+    where
+        T: Serialize,
+        R: for<'de> Deserialize<'de>,
+    {
+        Self::new(connection.into_raw())
+    }
+
+    /// Open the channel called `name`, sending server messages `S` and receiving client
+    /// messages `C`.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`ConnectionError::ChannelInUse`] if the channel is open, or the client didn't
+    /// close it yet since we did, and with [`ConnectionError::ChannelNameCollision`] if another
+    /// channel has the same id.
+    pub fn open_channel<C, S>(&self, name: &str) -> Result<Channel<S, C>, ConnectionError>
+    where
+        C: for<'de> Deserialize<'de>,
+        S: Serialize,
+    {
+        self.open(name)
+    }
+
+    /// Open the channel called `name` with the messages of the model `M`.
+    ///
+    /// # Errors
+    ///
+    /// Fails like `open_channel`.
+    pub fn open_model<M>(
+        &self,
+        name: &str,
+    ) -> Result<Channel<M::ServerMsg, M::ClientMsg>, ConnectionError>
+    where
+        M: IpcModel,
+    {
+        self.open(name)
+    }
+}
+
+/// One logical channel of a [`Multiplexer`], sending `T` and receiving `R`.
+///
+/// Dropping the channel closes it, the other end then gets [`ConnectionError::ChannelClosed`]
+/// once it received everything that was sent before.
+#[derive(Debug)]
+pub struct Channel<T, R>
+where
+    T: Serialize,
+    R: for<'de> Deserialize<'de>,
+{
+    shared: Arc<Shared>,
+    id: u32,
+    _tx: PhantomData<T>,
+    _rx: PhantomData<R>,
+}
+
+impl<T, R> Channel<T, R>
+where
+    T: Serialize,
+    R: for<'de> Deserialize<'de>,
+{
+    /// Send a message to the other end of the channel.
+    ///
+    /// Waits if the other end hasn't received enough of the previous messages yet.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`ConnectionError::ChannelClosed`] if the other end closed the channel or the
+    /// connection broke, with [`ConnectionError::ChannelNameCollision`] if the other end opened
+    /// another channel with the same id, or if the message can't be serialized or written.
+    pub fn send(&mut self, message: T) -> Result<(), ConnectionError> {
+        let bytes = bitcode::serialize(&message).map_err(ConnectionError::SerilizationFailed)?;
+        self.shared.wait_for(self.id, |channel| {
+            if channel.remote_closed {
+                Some(Err(ConnectionError::ChannelClosed))
+            } else if channel.credit > 0 {
+                channel.credit -= 1;
+                Some(Ok(()))
+            } else {
+                None
+            }
+        })??;
+        self.shared
+            .raw
+            .write_channel_frame(FrameKind::ChannelData, self.id, &bytes)
+    }
+
+    /// Receive a message from the other end of the channel.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`ConnectionError::ChannelClosed`] once everything sent before the other end
+    /// closed the channel was received, or if the connection broke, with
+    /// [`ConnectionError::ChannelNameCollision`] like [`Channel::send`], or if the message can't
+    /// be deserialized.
+    pub fn receive(&mut self) -> Result<R, ConnectionError> {
+        let window = self.shared.window;
+        let (data, credit) = self.shared.wait_for(self.id, |channel| {
+            if let Some(data) = channel.incoming.pop_front() {
+                channel.consumed += 1;
+                // Hand out credit in batches so that not every message needs an answer
+                let credit = if channel.consumed >= (window / 2).max(1) {
+                    std::mem::take(&mut channel.consumed)
+                } else {
+                    0
+                };
+                Some(Ok((data, credit)))
+            } else if channel.remote_closed {
+                Some(Err(ConnectionError::ChannelClosed))
+            } else {
+                None
+            }
+        })??;
+        if credit > 0 {
+            self.shared.raw.write_channel_frame(
+                FrameKind::ChannelCredit,
+                self.id,
+                &credit.to_le_bytes(),
+            )?;
+        }
+        self.shared.raw.decode(&data)
+    }
+}
+
+impl<T, R> Drop for Channel<T, R>
+where
+    T: Serialize,
+    R: for<'de> Deserialize<'de>,
+{
+    fn drop(&mut self) {
+        let mut state = self.shared.state();
+        if let Some(channel) = state.channels.get_mut(&self.id) {
+            if channel.remote_closed {
+                state.channels.remove(&self.id);
+            } else {
+                channel.local_closed = true;
+                channel.incoming.clear();
+            }
+        }
+        drop(state);
+        // Nowhere to report errors here, the connection is most likely gone already.
+        let _ = self
+            .shared
+            .raw
+            .write_channel_frame(FrameKind::ChannelClose, self.id, &[]);
+    }
+}
+
+/// State shared by a multiplexer and all of its channels
+#[derive(Debug)]
+struct Shared {
+    raw: Arc<RawConnection>,
+    state: Mutex<State>,
+    /// Notified whenever a frame was handled or the reading half was released
+    changed: Condvar,
+    window: u32,
+}
+
+impl Shared {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Wait until `ready` returns something for the channel `id`.
+    ///
+    /// While waiting, either read frames from the connection ourselves or, if another thread is
+    /// already doing that, wait for it to hand us what we need.
+    fn wait_for<X>(
+        &self,
+        id: u32,
+        mut ready: impl FnMut(&mut ChannelState) -> Option<X>,
+    ) -> Result<X, ConnectionError> {
+        let mut state = self.state();
+        loop {
+            let channel = state.channel(id, self.window);
+            if let Some(e) = channel.collision() {
+                return Err(e);
+            }
+            if let Some(x) = ready(channel) {
+                return Ok(x);
+            }
+            if state.broken {
+                return Err(ConnectionError::ChannelClosed);
+            }
+            // Checked while holding the state lock, so the reader can't notify before we wait
+            let Some(mut reader) = self.raw.try_reader() else {
+                state = self
+                    .changed
+                    .wait(state)
+                    .unwrap_or_else(PoisonError::into_inner);
+                continue;
+            };
+            drop(state);
+            let frame = self.raw.read_frame_from(&mut reader);
+            drop(reader);
+            state = self.state();
+            let handled = frame.and_then(|frame| state.handle(frame, self.window));
+            self.changed.notify_all();
+            if let Err(e) = handled {
+                state.broken = true;
+                drop(state);
+                return Err(e);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    channels: HashMap<u32, ChannelState>,
+    /// Reading from the connection failed, nothing more will arrive
+    broken: bool,
+}
+
+impl State {
+    fn channel(&mut self, id: u32, window: u32) -> &mut ChannelState {
+        self.channels
+            .entry(id)
+            .or_insert_with(|| ChannelState::new(window))
+    }
+
+    /// Hand a frame to the channel it belongs to
+    fn handle(&mut self, frame: Frame, window: u32) -> Result<(), ConnectionError> {
+        if frame.channel == MAIN_CHANNEL {
+            return Err(ConnectionError::UnexpectedFrame);
+        }
+        if !self.channels.contains_key(&frame.channel) {
+            let unopened = self.channels.values().filter(|c| c.name.is_none()).count();
+            if unopened >= MAX_UNOPENED_CHANNELS {
+                return Err(ConnectionError::UnexpectedFrame);
+            }
+        }
+        let channel = self.channel(frame.channel, window);
+        // The name of a channel comes before anything else
+        if (frame.kind == FrameKind::ChannelOpen) == channel.remote_name.is_some() {
+            return Err(ConnectionError::UnexpectedFrame);
+        }
+        match frame.kind {
+            FrameKind::ChannelOpen => {
+                let name =
+                    String::from_utf8(frame.data).map_err(|_| ConnectionError::UnexpectedFrame)?;
+                channel.remote_name = Some(name);
+            }
+            FrameKind::ChannelData => {
+                // The other end can't have more messages in flight than the window allows
+                if channel.incoming.len() >= window as usize {
+                    return Err(ConnectionError::UnexpectedFrame);
+                }
+                if !channel.local_closed {
+                    channel.incoming.push_back(frame.data);
+                }
+            }
+            FrameKind::ChannelCredit => {
+                let credit = <[u8; 4]>::try_from(frame.data.as_slice())
+                    .map_err(|_| ConnectionError::UnexpectedFrame)?;
+                channel.credit = channel.credit.saturating_add(u32::from_le_bytes(credit));
+            }
+            // Closed on both ends, the name is free again
+            FrameKind::ChannelClose if channel.local_closed => {
+                self.channels.remove(&frame.channel);
+            }
+            FrameKind::ChannelClose => channel.remote_closed = true,
+            _ => return Err(ConnectionError::UnexpectedFrame),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct ChannelState {
+    /// Name the channel was opened with on our end, `None` if it wasn't opened yet. Kept after we
+    /// closed it until the other end did too.
+    name: Option<String>,
+    /// Name the channel was opened with on the other end, same as `name` unless two names
+    /// collide
+    remote_name: Option<String>,
+    incoming: VecDeque<Vec<u8>>,
+    /// Number of messages we can still send before waiting for the other end
+    credit: u32,
+    /// Number of messages received that the other end wasn't given credit for yet
+    consumed: u32,
+    local_closed: bool,
+    remote_closed: bool,
+}
+
+impl ChannelState {
+    const fn new(window: u32) -> Self {
+        Self {
+            name: None,
+            remote_name: None,
+            incoming: VecDeque::new(),
+            credit: window,
+            consumed: 0,
+            local_closed: false,
+            remote_closed: false,
+        }
+    }
+
+    /// Two different names map to the id of this channel
+    fn collision(&self) -> Option<ConnectionError> {
+        match (&self.name, &self.remote_name) {
+            (Some(ours), Some(other)) if ours != other => Some(
+                ConnectionError::ChannelNameCollision(ours.clone(), other.clone()),
+            ),
+            _ => None,
+        }
+    }
+}
+
+/// Both ends derive the id of a channel from its name, so opening one needs no round trip.
+/// Different names can map to the same id, see [`ConnectionError::ChannelNameCollision`].
+fn channel_id(name: &str) -> u32 {
+    // FNV-1a, skipping the id of the main channel
+    let mut hash: u32 = 0x811c_9dc5;
+    for byte in name.bytes() {
+        hash ^= u32::from(byte);
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    hash.max(MAIN_CHANNEL + 1)
+}
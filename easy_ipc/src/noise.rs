@@ -0,0 +1,287 @@
+use {
+    crate::connection::Side,
+    snow::{Builder, HandshakeState, StatelessTransportState},
+    std::{
+        fmt,
+        io::{self, ErrorKind},
+        sync::atomic::{AtomicU64, Ordering},
+    },
+};
+
+/// Handshake used when the client doesn't know the key of the server, both ends send theirs
+const XX: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+/// Handshake used when the client knows the key of the server ahead of time
+const IK: &str = "Noise_IK_25519_ChaChaPoly_BLAKE2s";
+
+/// Longest message Noise can encrypt at once, longer payloads are split
+const MAX_MESSAGE_LEN: usize = 65535;
+/// Bytes the authentication tag adds to every encrypted message
+const TAG_LEN: usize = 16;
+/// Bytes of the header encrypted along with every payload: the kind with its flags, the channel
+/// and the length of the payload
+const BOUND_LEN: usize = 1 + 4 + 8;
+
+/// A static key pair identifying one end of an encrypted connection, see
+/// [`ClientServerOptions::noise`](crate::model::ClientServerOptions::noise).
+///
+/// The private key should be stored somewhere only the process it belongs to can read it, the
+/// public key is given to the other ends.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Keypair {
+    private: Vec<u8>,
+    public: Vec<u8>,
+}
+
+impl Keypair {
+    /// Generate a new random key pair
+    ///
+    /// # Errors
+    ///
+    /// Fails if the system has no randomness to give.
+    pub fn generate() -> Result<Self, io::Error> {
+        let keypair = Builder::new(params(XX)?)
+            .generate_keypair()
+            .map_err(io::Error::other)?;
+        Ok(Self {
+            private: keypair.private,
+            public: keypair.public,
+        })
+    }
+
+    /// Use a key pair that was generated before, like one loaded from a file
+    #[must_use]
+    pub const fn from_keys(private: Vec<u8>, public: Vec<u8>) -> Self {
+        Self { private, public }
+    }
+
+    /// The private key, keep this secret
+    #[must_use]
+    pub fn private(&self) -> &[u8] {
+        &self.private
+    }
+
+    /// The public key, give this to the other ends
+    #[must_use]
+    pub fn public(&self) -> &[u8] {
+        &self.public
+    }
+}
+
+impl fmt::Debug for Keypair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Don't leak the private key into logs
+        f.debug_struct("Keypair")
+            .field("public", &self.public)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Noise settings of a model, see
+/// [`ClientServerOptions::noise`](crate::model::ClientServerOptions::noise).
+#[derive(Debug, Clone, Default)]
+pub(crate) struct NoiseOptions {
+    /// Our static key, connections are only encrypted if this is set
+    pub(crate) keypair: Option<Keypair>,
+    /// Public key of the server, clients that know it use the IK handshake
+    pub(crate) server_key: Option<Vec<u8>>,
+    /// Public keys of the clients a server accepts, any client is accepted if not set
+    pub(crate) allowed_clients: Option<Vec<Vec<u8>>>,
+}
+
+/// Encrypts and decrypts the payloads of frames once the handshake is done.
+///
+/// Every encrypted message uses the next nonce, so messages have to be decrypted in the order
+/// they were encrypted. Callers make sure of that by encrypting with the writer locked and
+/// decrypting with the reader locked.
+pub(crate) struct Cipher {
+    state: StatelessTransportState,
+    send_nonce: AtomicU64,
+    receive_nonce: AtomicU64,
+}
+
+impl Cipher {
+    /// Encrypt the payload of a frame of `kind` on `channel` into `out`, replacing what it held.
+    ///
+    /// The kind byte, flags included, the channel and the length of the payload are encrypted
+    /// first, as a Noise message of their own, and checked by [`Cipher::decrypt`]. So a frame
+    /// can't be turned into another by changing its header or cutting it short. The transport of
+    /// snow has no associated data, which would do the same without sending the fields twice.
+    /// The payload follows, split into as many Noise messages as needed.
+    pub(crate) fn encrypt(
+        &self,
+        kind: u8,
+        channel: u32,
+        data: &[u8],
+        out: &mut Vec<u8>,
+    ) -> Result<(), snow::Error> {
+        out.clear();
+        out.reserve(sealed_len(data.len()));
+        self.write_message(&bound(kind, channel, data.len()), out)?;
+        for chunk in data.chunks(MAX_MESSAGE_LEN - TAG_LEN) {
+            self.write_message(chunk, out)?;
+        }
+        Ok(())
+    }
+
+    /// Encrypt a single Noise message and append it to `out`
+    fn write_message(&self, plain: &[u8], out: &mut Vec<u8>) -> Result<(), snow::Error> {
+        let start = out.len();
+        out.resize(start + plain.len() + TAG_LEN, 0);
+        let nonce = self.send_nonce.fetch_add(1, Ordering::Relaxed);
+        let written = self.state.write_message(nonce, plain, &mut out[start..])?;
+        out.truncate(start + written);
+        Ok(())
+    }
+
+    /// Decrypt a payload produced by [`Cipher::encrypt`] on the other end, which fails unless
+    /// it was sent with this `kind` and `channel`
+    pub(crate) fn decrypt(
+        &self,
+        kind: u8,
+        channel: u32,
+        data: &[u8],
+    ) -> Result<Vec<u8>, snow::Error> {
+        let (fields, data) = data
+            .split_at_checked(BOUND_LEN + TAG_LEN)
+            .ok_or(snow::Error::Decrypt)?;
+        let mut out = Vec::with_capacity(data.len());
+        for message in std::iter::once(fields).chain(data.chunks(MAX_MESSAGE_LEN)) {
+            let start = out.len();
+            out.resize(start + message.len(), 0);
+            let nonce = self.receive_nonce.fetch_add(1, Ordering::Relaxed);
+            let read = self.state.read_message(nonce, message, &mut out[start..])?;
+            out.truncate(start + read);
+        }
+        let len = out.len().saturating_sub(BOUND_LEN);
+        if out.len() < BOUND_LEN || out[..BOUND_LEN] != bound(kind, channel, len) {
+            return Err(snow::Error::Decrypt);
+        }
+        out.drain(..BOUND_LEN);
+        Ok(out)
+    }
+}
+
+/// Length of a payload of `len` bytes once encrypted by [`Cipher::encrypt`]
+pub(crate) const fn sealed_len(len: usize) -> usize {
+    let tags = 1 + len.div_ceil(MAX_MESSAGE_LEN - TAG_LEN);
+    len.saturating_add(BOUND_LEN).saturating_add(tags * TAG_LEN)
+}
+
+/// The header fields encrypted along with a payload of `len` bytes
+fn bound(kind: u8, channel: u32, len: usize) -> [u8; BOUND_LEN] {
+    let mut bound = [0; BOUND_LEN];
+    bound[0] = kind;
+    bound[1..5].copy_from_slice(&channel.to_le_bytes());
+    bound[5..].copy_from_slice(&(len as u64).to_le_bytes());
+    bound
+}
+
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Cipher")
+            .field("remote_key", &self.state.get_remote_static())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Run the handshake with the other end, `send` and `receive` exchange handshake messages.
+///
+/// The client starts, the first byte of its first message tells the server which handshake it
+/// uses. Servers check the key of the client against the allowed ones once the handshake is done.
+pub(crate) fn handshake(
+    options: &NoiseOptions,
+    keypair: &Keypair,
+    side: Side,
+    mut send: impl FnMut(&[u8]) -> Result<(), io::Error>,
+    mut receive: impl FnMut() -> Result<Vec<u8>, io::Error>,
+) -> Result<Cipher, io::Error> {
+    let mut buf = vec![0; MAX_MESSAGE_LEN];
+    let mut state = match side {
+        Side::Client => {
+            let (pattern, state) = match &options.server_key {
+                Some(server_key) => (
+                    IK,
+                    Builder::new(params(IK)?)
+                        .local_private_key(&keypair.private)
+                        .remote_public_key(server_key)
+                        .build_initiator(),
+                ),
+                None => (
+                    XX,
+                    Builder::new(params(XX)?)
+                        .local_private_key(&keypair.private)
+                        .build_initiator(),
+                ),
+            };
+            let mut state = state.map_err(invalid)?;
+            let len = state.write_message(&[], &mut buf).map_err(invalid)?;
+            let mut first = vec![u8::from(pattern == IK)];
+            first.extend_from_slice(&buf[..len]);
+            send(&first)?;
+            state
+        }
+        Side::Server => {
+            let first = receive()?;
+            let Some((&ik, message)) = first.split_first() else {
+                return Err(invalid("empty handshake message"));
+            };
+            let pattern = if ik == 1 { IK } else { XX };
+            let mut state = Builder::new(params(pattern)?)
+                .local_private_key(&keypair.private)
+                .build_responder()
+                .map_err(invalid)?;
+            state.read_message(message, &mut buf).map_err(invalid)?;
+            state
+        }
+    };
+    exchange(&mut state, &mut buf, &mut send, &mut receive)?;
+
+    if side == Side::Server
+        && let Some(allowed) = &options.allowed_clients
+    {
+        let client_key = state.get_remote_static().unwrap_or_default();
+        if !allowed.iter().any(|key| key == client_key) {
+            return Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                "the key of the client is not allowed",
+            ));
+        }
+    }
+
+    Ok(Cipher {
+        state: state.into_stateless_transport_mode().map_err(invalid)?,
+        send_nonce: AtomicU64::new(0),
+        receive_nonce: AtomicU64::new(0),
+    })
+}
+
+/// Send and receive handshake messages until the handshake is done
+fn exchange(
+    state: &mut HandshakeState,
+    buf: &mut [u8],
+    send: &mut impl FnMut(&[u8]) -> Result<(), io::Error>,
+    receive: &mut impl FnMut() -> Result<Vec<u8>, io::Error>,
+) -> Result<(), io::Error> {
+    while !state.is_handshake_finished() {
+        if state.is_my_turn() {
+            let len = state.write_message(&[], buf).map_err(invalid)?;
+            send(&buf[..len])?;
+        } else {
+            let message = receive()?;
+            state.read_message(&message, buf).map_err(invalid)?;
+        }
+    }
+    Ok(())
+}
+
+fn params(pattern: &str) -> Result<snow::params::NoiseParams, io::Error> {
+    pattern.parse().map_err(invalid)
+}
+
+/// The handshake failed, either the other end misbehaved or keys didn't match
+fn invalid<E>(error: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(ErrorKind::InvalidData, error)
+}
@@ -0,0 +1,102 @@
+#[cfg(unix)]
+use std::{net::Shutdown, os::unix::net::UnixStream, thread};
+use {
+    interprocess::local_socket::Stream,
+    std::{
+        io::{self, ErrorKind, Read, Write},
+        process::{Child, ChildStdin, ChildStdout},
+    },
+};
+
+/// Size of the pieces written to the writer of a pipe connection
+#[cfg(unix)]
+const CHUNK: usize = 8 * 1024;
+
+/// A stream that talks over `reader` and `writer` instead of a socket.
+///
+/// Connections need a socket, so they get one end of a socket pair and two threads pass the
+/// bytes on between the other end and the pipes. The writer is flushed after every write, so
+/// buffered writers like stdout don't hold frames back. Once the connection is dropped the writer
+/// is dropped too, which closes the pipe.
+#[cfg(unix)]
+pub fn wrap<Rd, Wr>(mut reader: Rd, mut writer: Wr) -> Result<Stream, io::Error>
+where
+    Rd: Read + Send + 'static,
+    Wr: Write + Send + 'static,
+{
+    let (inner, relay) = UnixStream::pair()?;
+    let mut incoming = relay.try_clone()?;
+    thread::Builder::new()
+        .name("easy_ipc pipe reader".to_string())
+        .spawn(move || {
+            io::copy(&mut reader, &mut incoming).ok();
+            // Let the connection see the pipe close
+            incoming.shutdown(Shutdown::Write).ok();
+        })?;
+    let mut outgoing = relay;
+    thread::Builder::new()
+        .name("easy_ipc pipe writer".to_string())
+        .spawn(move || {
+            let mut buffer = vec![0; CHUNK];
+            loop {
+                let len = match outgoing.read(&mut buffer) {
+                    Ok(0) => return,
+                    Ok(len) => len,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(_) => return,
+                };
+                let written = writer
+                    .write_all(&buffer[..len])
+                    .and_then(|()| writer.flush());
+                if written.is_err() {
+                    // Nobody reads the pipe any more, writing on the connection fails from now on
+                    outgoing.shutdown(Shutdown::Both).ok();
+                    return;
+                }
+            }
+        })?;
+    Ok(Stream::UdSocket(inner.into()))
+}
+
+#[cfg(not(unix))]
+pub fn wrap<Rd, Wr>(_reader: Rd, _writer: Wr) -> Result<Stream, io::Error>
+where
+    Rd: Read + Send + 'static,
+    Wr: Write + Send + 'static,
+{
+    Err(io::Error::new(
+        ErrorKind::Unsupported,
+        "pipe connections are only supported on unix",
+    ))
+}
+
+/// The output of a child process, waits for the child once it is dropped so it doesn't linger
+/// as a zombie
+#[derive(Debug)]
+pub struct ChildOutput {
+    stdout: ChildStdout,
+    child: Child,
+}
+
+impl Read for ChildOutput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stdout.read(buf)
+    }
+}
+
+impl Drop for ChildOutput {
+    fn drop(&mut self) {
+        self.child.wait().ok();
+    }
+}
+
+/// The pipes to the standard input and output of `child`, which have to be piped
+pub fn child(mut child: Child) -> Result<(ChildOutput, ChildStdin), io::Error> {
+    match (child.stdout.take(), child.stdin.take()) {
+        (Some(stdout), Some(stdin)) => Ok((ChildOutput { stdout, child }, stdin)),
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "the standard input and output of the child have to be piped",
+        )),
+    }
+}
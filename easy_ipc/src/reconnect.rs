@@ -0,0 +1,323 @@
+use {
+    crate::{
+        client::Client,
+        error::{ConnectionError, InitError},
+        model::IpcModel,
+    },
+    std::{
+        fmt,
+        marker::PhantomData,
+        thread,
+        time::{Duration, Instant},
+    },
+};
+
+/// Something that happened to the connection of a [`ReconnectingClient`], passed to the callback
+/// set with [`ReconnectingClient::on_event`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ReconnectEvent<'a> {
+    /// The connection to the server broke
+    Disconnected(&'a ConnectionError),
+    /// Connecting to the server failed, another attempt is made after `delay`
+    AttemptFailed {
+        /// Number of attempts made so far
+        attempt: u32,
+        /// Why the attempt failed
+        error: &'a InitError,
+        /// How long we wait before the next attempt
+        delay: Duration,
+    },
+    /// Connected to the server again after the connection broke
+    Reconnected {
+        /// Number of attempts it took
+        attempts: u32,
+    },
+    /// Connecting failed too many times, see [`ReconnectingClient::max_attempts`]
+    GaveUp {
+        /// Number of attempts made
+        attempts: u32,
+    },
+}
+
+type EventCallback = Box<dyn FnMut(ReconnectEvent<'_>) + Send>;
+
+/// A client of the model `M` that connects again when the connection to the server breaks, for
+/// instance because the server restarted.
+///
+/// The connection is made on the first [`ReconnectingClient::send`], or eagerly with
+/// [`ReconnectingClient::connect`]. While connecting, failed attempts are retried with an
+/// exponential backoff, see [`ReconnectingClient::backoff`]. A connection that breaks before it
+/// was healthy counts as a failed attempt, so a server that accepts and hangs up right away isn't
+/// hammered either. It is healthy once a message arrived on it, or once it lasted as long as the
+/// longest delay.
+///
+/// A message that couldn't be sent because the connection broke is sent again once connected. A
+/// response that was lost because the connection broke can't be recovered, in that case
+/// [`ReconnectingClient::receive`] returns the error and the next send reconnects.
+///
+/// # Example
+///
+/// ```no_run
+/// use easy_ipc::{prelude::*, reconnect::ReconnectingClient};
+///
+/// # fn run<M: IpcModel<ClientMsg = String, ServerMsg = String>>() {
+/// let mut client = ReconnectingClient::<M>::new()
+///     .session_init("hello, I'm the agent".to_string())
+///     .on_event(|event| eprintln!("{event:?}"));
+/// loop {
+///     client.send("status".to_string()).unwrap();
+///     match client.receive() {
+///         Ok(status) => println!("{status}"),
+///         // The server went away before answering, ask again
+///         Err(e) => eprintln!("lost the answer, {e:?}"),
+///     }
+/// }
+/// # }
+/// ```
+pub struct ReconnectingClient<M: IpcModel> {
+    client: Option<Client<M::ClientMsg, M::ServerMsg>>,
+    /// Set once a connection was made, later connects are reconnects
+    connected_before: bool,
+    /// When the current connection was made
+    connected_at: Option<Instant>,
+    /// How long to wait before the next attempt to connect, `None` to try right away
+    delay: Option<Duration>,
+    initial_delay: Duration,
+    max_delay: Duration,
+    max_attempts: Option<u32>,
+    session_init: Option<M::ClientMsg>,
+    on_event: Option<EventCallback>,
+    _model: PhantomData<M>,
+}
+
+impl<M: IpcModel> ReconnectingClient<M> {
+    /// Create a client that connects on first use.
+    ///
+    /// By default the backoff starts at 50 milliseconds, doubles on every failed attempt up to 5
+    /// seconds and we never give up.
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            client: None,
+            connected_before: false,
+            connected_at: None,
+            delay: None,
+            initial_delay: Duration::from_millis(50),
+            max_delay: Duration::from_secs(5),
+            max_attempts: None,
+            session_init: None,
+            on_event: None,
+            _model: PhantomData,
+        }
+    }
+
+    /// Wait `initial` after the first failed attempt to connect, doubling the delay on every
+    /// following failed attempt up to `max`. Connections that break before they were healthy
+    /// count as failed attempts.
+    #[must_use]
+    pub const fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_delay = initial;
+        self.max_delay = max;
+        self
+    }
+
+    /// Give up after `attempts` failed attempts to connect, which makes the call that tried to
+    /// connect fail with [`ConnectionError::ReconnectFailed`]. The next call starts over.
+    #[must_use]
+    pub const fn max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = Some(attempts);
+        self
+    }
+
+    /// Send `message` every time a connection is made, before anything else.
+    ///
+    /// Use this to set up the state a server keeps for a client, like a subscription. The server
+    /// shouldn't answer it, an answer would be received by the next
+    /// [`ReconnectingClient::receive`].
+    #[must_use]
+    pub fn session_init(mut self, message: M::ClientMsg) -> Self {
+        self.session_init = Some(message);
+        self
+    }
+
+    /// Call `callback` whenever the connection breaks or we try to connect again, see
+    /// [`ReconnectEvent`].
+    #[must_use]
+    pub fn on_event<F>(mut self, callback: F) -> Self
+    where
+        F: FnMut(ReconnectEvent<'_>) + Send + 'static,
+    {
+        self.on_event = Some(Box::new(callback));
+        self
+    }
+
+    /// Returns `true` if the client currently has a connection to the server.
+    pub const fn is_connected(&self) -> bool {
+        self.client.is_some()
+    }
+
+    /// Connect to the server if not connected already.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`ConnectionError::ReconnectFailed`] once out of attempts, see
+    /// [`ReconnectingClient::max_attempts`].
+    pub fn connect(&mut self) -> Result<(), ConnectionError> {
+        self.client().map(|_| ())
+    }
+
+    /// Send a message to the server, connecting first if needed.
+    ///
+    /// If the connection turns out to be broken, we reconnect and send the message again.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`ReconnectingClient::connect`], or if the message can't be serialized.
+    // Taken by value to match `Client::send`
+    #[allow(clippy::needless_pass_by_value)]
+    pub fn send(&mut self, message: M::ClientMsg) -> Result<(), ConnectionError> {
+        let bytes = bitcode::serialize(&message).map_err(ConnectionError::SerilizationFailed)?;
+        loop {
+            let result = self.client()?.send_encoded(&bytes);
+            match result {
+                Err(e) if is_disconnect(&e) => self.disconnected(&e),
+                result => return result,
+            }
+        }
+    }
+
+    /// Receive a message from the server.
+    ///
+    /// If the connection broke, the error is returned and the next send reconnects.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`Client::receive`], and with [`ConnectionError::UnexepctedEof`] if not
+    /// connected.
+    pub fn receive(&mut self) -> Result<M::ServerMsg, ConnectionError> {
+        let Some(client) = self.client.as_mut() else {
+            return Err(ConnectionError::UnexepctedEof);
+        };
+        let result = client.receive();
+        match &result {
+            Ok(_) => self.delay = None,
+            Err(e) if is_disconnect(e) => self.disconnected(e),
+            Err(_) => (),
+        }
+        result
+    }
+
+    fn emit(&mut self, event: ReconnectEvent<'_>) {
+        if let Some(callback) = &mut self.on_event {
+            callback(event);
+        }
+    }
+
+    fn disconnected(&mut self, error: &ConnectionError) {
+        self.client = None;
+        if self
+            .connected_at
+            .take()
+            .is_some_and(|at| at.elapsed() >= self.max_delay)
+        {
+            self.delay = None;
+        }
+        self.emit(ReconnectEvent::Disconnected(error));
+    }
+
+    /// Get the current client, connecting with backoff if there is none
+    fn client(&mut self) -> Result<&mut Client<M::ClientMsg, M::ServerMsg>, ConnectionError> {
+        let client = match self.client.take() {
+            Some(client) => client,
+            None => self.connect_with_backoff()?,
+        };
+        Ok(self.client.insert(client))
+    }
+
+    fn connect_with_backoff(
+        &mut self,
+    ) -> Result<Client<M::ClientMsg, M::ServerMsg>, ConnectionError> {
+        let init = self
+            .session_init
+            .as_ref()
+            .map(bitcode::serialize)
+            .transpose()
+            .map_err(ConnectionError::SerilizationFailed)?;
+        let mut attempt = 0;
+        loop {
+            if let Some(delay) = self.delay {
+                thread::sleep(delay);
+            }
+            attempt += 1;
+            let next = self.delay.map_or(self.initial_delay, |delay| {
+                delay.saturating_mul(2).min(self.max_delay)
+            });
+            let error = match Self::try_connect(init.as_deref()) {
+                Ok(client) => {
+                    if self.connected_before {
+                        self.emit(ReconnectEvent::Reconnected { attempts: attempt });
+                    }
+                    self.connected_before = true;
+                    self.connected_at = Some(Instant::now());
+                    // Kept until the connection turns out to be healthy
+                    self.delay = Some(next);
+                    return Ok(client);
+                }
+                Err(e) => e,
+            };
+            if self.max_attempts.is_some_and(|max| attempt >= max) {
+                self.delay = None;
+                self.emit(ReconnectEvent::GaveUp { attempts: attempt });
+                return Err(ConnectionError::ReconnectFailed(error));
+            }
+            self.delay = Some(next);
+            self.emit(ReconnectEvent::AttemptFailed {
+                attempt,
+                error: &error,
+                delay: next,
+            });
+        }
+    }
+
+    /// Connect and send the session init message
+    fn try_connect(init: Option<&[u8]>) -> Result<Client<M::ClientMsg, M::ServerMsg>, InitError> {
+        let client = M::client()?;
+        if let Some(init) = init {
+            // The server went away again right after accepting us, treat it like it wasn't there
+            client.send_encoded(init).map_err(|e| match e {
+                ConnectionError::WriteFailed(e) => InitError::FailedConnectingToSocket(e),
+                _ => InitError::FailedConnectingToSocket(std::io::ErrorKind::BrokenPipe.into()),
+            })?;
+        }
+        Ok(client)
+    }
+}
+
+impl<M: IpcModel> Default for ReconnectingClient<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: IpcModel> fmt::Debug for ReconnectingClient<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReconnectingClient")
+            .field("connected", &self.is_connected())
+            .field("initial_delay", &self.initial_delay)
+            .field("max_delay", &self.max_delay)
+            .field("max_attempts", &self.max_attempts)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Errors that mean the other end of the connection is gone
+const fn is_disconnect(error: &ConnectionError) -> bool {
+    matches!(
+        error,
+        ConnectionError::WriteFailed(_)
+            | ConnectionError::ReadFailed(_)
+            | ConnectionError::UnexepctedEof
+            | ConnectionError::PeerUnresponsive
+    )
+}
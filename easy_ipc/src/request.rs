@@ -0,0 +1,88 @@
+use {
+    crate::{
+        connection::{FrameKind, RawConnection},
+        error::ConnectionError,
+    },
+    std::{
+        sync::{
+            Arc,
+            atomic::{AtomicBool, Ordering},
+        },
+        time::SystemTime,
+    },
+};
+
+/// Information about a request received with
+/// [`Connection::receive_request`](crate::connection::Connection::receive_request).
+///
+/// Long running handlers should check [`RequestContext::is_cancelled`] every so often and stop
+/// working on the request once it returns `true`, nobody is waiting for the answer any more.
+#[derive(Debug)]
+pub struct RequestContext {
+    raw: Arc<RawConnection>,
+    deadline: Option<SystemTime>,
+    cancelled: AtomicBool,
+}
+
+impl RequestContext {
+    pub(crate) const fn new(raw: Arc<RawConnection>, deadline: Option<SystemTime>) -> Self {
+        Self {
+            raw,
+            deadline,
+            cancelled: AtomicBool::new(false),
+        }
+    }
+
+    /// The deadline the other end sent with the request, if any.
+    pub const fn deadline(&self) -> Option<SystemTime> {
+        self.deadline
+    }
+
+    /// Returns `true` if the other end cancelled the request, went away or if the deadline
+    /// passed.
+    ///
+    /// This never blocks. If another thread is currently reading from the connection, only
+    /// cancellations that were already seen are reported, and the other end going away isn't
+    /// noticed until that thread is done reading.
+    pub fn is_cancelled(&self) -> bool {
+        if self.cancelled.load(Ordering::Relaxed) {
+            return true;
+        }
+        let expired = self
+            .deadline
+            .is_some_and(|deadline| deadline <= SystemTime::now());
+        // An error means the other end is gone, which is as good as cancelled
+        let cancelled = expired || self.raw.cancel_received(false).unwrap_or(true);
+        if cancelled {
+            self.cancelled.store(true, Ordering::Relaxed);
+        }
+        cancelled
+    }
+}
+
+/// Handle that can cancel the current request of a connection from another thread.
+///
+/// Created with [`Connection::cancel_handle`](crate::connection::Connection::cancel_handle) or
+/// [`Client::cancel_handle`](crate::client::Client::cancel_handle). The other end sees the
+/// cancellation through [`RequestContext::is_cancelled`].
+#[derive(Debug, Clone)]
+pub struct CancelHandle {
+    raw: Arc<RawConnection>,
+}
+
+impl CancelHandle {
+    pub(crate) const fn new(raw: Arc<RawConnection>) -> Self {
+        Self { raw }
+    }
+
+    /// Tell the other end to stop working on the current request.
+    ///
+    /// The other end still sends a response, which should be received as usual.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the cancel can't be written, because the connection broke.
+    pub fn cancel(&self) -> Result<(), ConnectionError> {
+        self.raw.write_frame(FrameKind::Cancel, &[])
+    }
+}
@@ -0,0 +1,400 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    fmt::Write,
+    path::PathBuf,
+    time::Duration,
+};
+
+/// Types that can describe their own layout.
+///
+/// This is usually derived with `#[derive(Schema)]` on your client and server messages. The
+/// description is used to check that a client and a server agree on the messages they are sending,
+/// see [`ModelSchema`] and [`crate::model::ClientServerOptions::schema_fingerprint`].
+///
+/// Recursive types are not supported, describing them will never terminate.
+pub trait Schema {
+    /// Get the structural description of this type
+    fn shape() -> Shape;
+}
+
+/// Structural description of a type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Shape {
+    /// A type with no further structure, like `u32` or `String`
+    Primitive(&'static str),
+    /// An optional value
+    Option(Box<Self>),
+    /// A variable length sequence, like a `Vec` or a `HashSet`
+    Seq(Box<Self>),
+    /// A fixed length array
+    Array(Box<Self>, usize),
+    /// A key value map
+    Map(Box<Self>, Box<Self>),
+    /// A tuple
+    Tuple(Vec<Self>),
+    /// A struct with a name and fields
+    Struct {
+        /// Name of the struct
+        name: &'static str,
+        /// Fields of the struct
+        fields: Fields,
+    },
+    /// An enum with a name and variants
+    Enum {
+        /// Name of the enum
+        name: &'static str,
+        /// Variants of the enum, in declaration order
+        variants: Vec<Variant>,
+    },
+}
+
+/// Fields of a struct or enum variant
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fields {
+    /// No fields, like `struct Foo;`
+    Unit,
+    /// Tuple fields, like `struct Foo(u32, String);`
+    Unnamed(Vec<Shape>),
+    /// Named fields, like `struct Foo { a: u32 }`
+    Named(Vec<(&'static str, Shape)>),
+}
+
+/// A single variant of an enum
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Variant {
+    /// Name of the variant
+    pub name: &'static str,
+    /// Fields of the variant
+    pub fields: Fields,
+}
+
+/// Description of both sides of a client/server model.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModelSchema {
+    /// Messages the client sends
+    pub client: Shape,
+    /// Messages the server sends
+    pub server: Shape,
+}
+
+impl ModelSchema {
+    /// Describe the model with client messages `C` and server messages `S`.
+    #[must_use]
+    pub fn new<C, S>() -> Self
+    where
+        C: Schema,
+        S: Schema,
+    {
+        Self {
+            client: C::shape(),
+            server: S::shape(),
+        }
+    }
+
+    /// A stable hash of the schema.
+    ///
+    /// This only depends on the structure of the messages, so it is the same across builds,
+    /// platforms and compiler versions.
+    #[must_use]
+    pub fn hash(&self) -> u64 {
+        // FNV-1a over the compact JSON description
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for byte in self.json(false).bytes() {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+        hash
+    }
+
+    /// Pretty printed JSON description of the schema, useful for documentation and diffing.
+    #[must_use]
+    pub fn to_json(&self) -> String {
+        self.json(true)
+    }
+
+    fn json(&self, pretty: bool) -> String {
+        let mut writer = JsonWriter::new(pretty);
+        writer.open('{');
+        writer.key("client");
+        writer.shape(&self.client);
+        writer.key("server");
+        writer.shape(&self.server);
+        writer.close('}');
+        writer.out
+    }
+}
+
+/// Minimal JSON writer for [`Shape`]s, handles commas and optional indentation.
+struct JsonWriter {
+    out: String,
+    pretty: bool,
+    depth: usize,
+    /// If the current container already has an element in it
+    needs_comma: bool,
+}
+
+impl JsonWriter {
+    const fn new(pretty: bool) -> Self {
+        Self {
+            out: String::new(),
+            pretty,
+            depth: 0,
+            needs_comma: false,
+        }
+    }
+
+    fn newline(&mut self) {
+        if self.pretty {
+            self.out.push('\n');
+            self.out.push_str(&"  ".repeat(self.depth));
+        }
+    }
+
+    /// Start a new element in the current container
+    fn element(&mut self) {
+        if self.needs_comma {
+            self.out.push(',');
+        }
+        self.newline();
+        self.needs_comma = true;
+    }
+
+    fn open(&mut self, bracket: char) {
+        self.out.push(bracket);
+        self.depth += 1;
+        self.needs_comma = false;
+    }
+
+    fn close(&mut self, bracket: char) {
+        self.depth -= 1;
+        if self.needs_comma {
+            self.newline();
+        }
+        self.out.push(bracket);
+        self.needs_comma = true;
+    }
+
+    fn key(&mut self, key: &str) {
+        self.element();
+        self.string(key);
+        self.out.push(':');
+        if self.pretty {
+            self.out.push(' ');
+        }
+        // The value belongs to this key, it shouldn't start a new element
+        self.needs_comma = false;
+    }
+
+    fn string(&mut self, value: &str) {
+        self.out.push('"');
+        for c in value.chars() {
+            match c {
+                '"' => self.out.push_str("\\\""),
+                '\\' => self.out.push_str("\\\\"),
+                c if c.is_control() => {
+                    let _ = write!(self.out, "\\u{:04x}", c as u32);
+                }
+                c => self.out.push(c),
+            }
+        }
+        self.out.push('"');
+        self.needs_comma = true;
+    }
+
+    fn shape(&mut self, shape: &Shape) {
+        match shape {
+            Shape::Primitive(name) => self.string(name),
+            Shape::Option(inner) => self.wrapped("option", inner),
+            Shape::Seq(inner) => self.wrapped("seq", inner),
+            Shape::Array(inner, len) => {
+                self.open('{');
+                self.key("array");
+                self.shape(inner);
+                self.key("len");
+                let _ = write!(self.out, "{len}");
+                self.needs_comma = true;
+                self.close('}');
+            }
+            Shape::Map(key, value) => {
+                self.open('{');
+                self.key("map");
+                self.list(&[key.as_ref(), value.as_ref()]);
+                self.close('}');
+            }
+            Shape::Tuple(items) => {
+                self.open('{');
+                self.key("tuple");
+                self.list(&items.iter().collect::<Vec<_>>());
+                self.close('}');
+            }
+            Shape::Struct { name, fields } => {
+                self.open('{');
+                self.key("struct");
+                self.string(name);
+                self.key("fields");
+                self.fields(fields);
+                self.close('}');
+            }
+            Shape::Enum { name, variants } => {
+                self.open('{');
+                self.key("enum");
+                self.string(name);
+                self.key("variants");
+                self.open('[');
+                for variant in variants {
+                    self.element();
+                    self.open('{');
+                    self.key("name");
+                    self.string(variant.name);
+                    self.key("fields");
+                    self.fields(&variant.fields);
+                    self.close('}');
+                }
+                self.close(']');
+                self.close('}');
+            }
+        }
+    }
+
+    fn wrapped(&mut self, kind: &str, inner: &Shape) {
+        self.open('{');
+        self.key(kind);
+        self.shape(inner);
+        self.close('}');
+    }
+
+    fn list(&mut self, shapes: &[&Shape]) {
+        self.open('[');
+        for shape in shapes {
+            self.element();
+            self.shape(shape);
+        }
+        self.close(']');
+    }
+
+    fn fields(&mut self, fields: &Fields) {
+        match fields {
+            Fields::Unit => {
+                self.out.push_str("null");
+                self.needs_comma = true;
+            }
+            Fields::Unnamed(shapes) => self.list(&shapes.iter().collect::<Vec<_>>()),
+            Fields::Named(named) => {
+                self.open('{');
+                for (name, shape) in named {
+                    self.key(name);
+                    self.shape(shape);
+                }
+                self.close('}');
+            }
+        }
+    }
+}
+
+/// Implement [`Schema`] for types that are described by their name
+macro_rules! impl_primitive {
+    ($($ty:ty),* $(,)?) => {
+        $(
+            impl Schema for $ty {
+                fn shape() -> Shape {
+                    Shape::Primitive(stringify!($ty))
+                }
+            }
+        )*
+    };
+}
+
+impl_primitive!(
+    bool, char, u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64, String,
+    PathBuf, Duration,
+);
+
+impl Schema for () {
+    fn shape() -> Shape {
+        Shape::Tuple(Vec::new())
+    }
+}
+
+impl<T: Schema> Schema for Option<T> {
+    fn shape() -> Shape {
+        Shape::Option(Box::new(T::shape()))
+    }
+}
+
+impl<T: Schema> Schema for Box<T> {
+    fn shape() -> Shape {
+        T::shape()
+    }
+}
+
+impl<T: Schema, const N: usize> Schema for [T; N] {
+    fn shape() -> Shape {
+        Shape::Array(Box::new(T::shape()), N)
+    }
+}
+
+/// Implement [`Schema`] for sequence types
+macro_rules! impl_seq {
+    ($($ty:ident),* $(,)?) => {
+        $(
+            impl<T: Schema> Schema for $ty<T> {
+                fn shape() -> Shape {
+                    Shape::Seq(Box::new(T::shape()))
+                }
+            }
+        )*
+    };
+}
+
+impl_seq!(Vec, VecDeque, BTreeSet);
+
+impl<T: Schema, H> Schema for HashSet<T, H> {
+    fn shape() -> Shape {
+        Shape::Seq(Box::new(T::shape()))
+    }
+}
+
+/// Implement [`Schema`] for map types
+macro_rules! impl_map {
+    ($($ty:ident),* $(,)?) => {
+        $(
+            impl<K: Schema, V: Schema> Schema for $ty<K, V> {
+                fn shape() -> Shape {
+                    Shape::Map(Box::new(K::shape()), Box::new(V::shape()))
+                }
+            }
+        )*
+    };
+}
+
+impl_map!(BTreeMap);
+
+impl<K: Schema, V: Schema, H> Schema for HashMap<K, V, H> {
+    fn shape() -> Shape {
+        Shape::Map(Box::new(K::shape()), Box::new(V::shape()))
+    }
+}
+
+/// Implement [`Schema`] for tuples
+macro_rules! impl_tuple {
+    ($(($($name:ident),+)),* $(,)?) => {
+        $(
+            impl<$($name: Schema),+> Schema for ($($name,)+) {
+                fn shape() -> Shape {
+                    Shape::Tuple(vec![$($name::shape()),+])
+                }
+            }
+        )*
+    };
+}
+
+impl_tuple!(
+    (A),
+    (A, B),
+    (A, B, C),
+    (A, B, C, D),
+    (A, B, C, D, E),
+    (A, B, C, D, E, F),
+    (A, B, C, D, E, F, G),
+    (A, B, C, D, E, F, G, H),
+);
@@ -0,0 +1,241 @@
+use {
+    crate::{connection::RawConnection, error::ConnectionError},
+    serde::Serialize,
+    std::{
+        any::type_name,
+        collections::VecDeque,
+        io::ErrorKind,
+        marker::PhantomData,
+        sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError},
+        thread,
+    },
+};
+
+/// What a [`Sender`] does with a message when its queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum Overflow {
+    /// Wait until a queued message was written and there is room
+    #[default]
+    Block,
+    /// Throw the oldest queued message away to make room, for messages that replace the ones
+    /// before them like progress updates
+    DropOldest,
+    /// Throw the new message away
+    DropNewest,
+    /// Fail with [`ConnectionError::QueueFull`]
+    Error,
+}
+
+/// Sends messages from a bounded queue on a thread of its own, so that a slow reader on the other
+/// end doesn't hold up the sending thread.
+///
+/// Get one with [`Connection::buffered_sender`]. Messages are serialized right away and written
+/// in the order they were queued. What happens once the queue is full depends on the
+/// [`Overflow`] policy. Messages sent directly on the connection in the meantime can overtake
+/// queued ones.
+///
+/// A failed write fails the next call to [`Sender::send`] or [`Sender::flush`] with the error,
+/// everything still queued is thrown away. Dropping the sender still writes what is queued, call
+/// [`Sender::flush`] first to wait for it.
+///
+/// [`Connection::buffered_sender`]: crate::connection::Connection::buffered_sender
+#[derive(Debug)]
+pub struct Sender<T>
+where
+    T: Serialize,
+{
+    shared: Arc<Shared>,
+    _tx: PhantomData<T>,
+}
+
+/// The queue, shared with the thread writing it
+#[derive(Debug)]
+struct Shared {
+    state: Mutex<State>,
+    /// Notified whenever the queue or the state of the writer changes
+    changed: Condvar,
+    capacity: usize,
+    overflow: Overflow,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    queue: VecDeque<Vec<u8>>,
+    /// The writer took a message from the queue and didn't finish writing it yet
+    writing: bool,
+    /// Why writing failed, handed to the next caller
+    error: Option<ConnectionError>,
+    failed: bool,
+    /// The sender was dropped, the writer stops once the queue is empty
+    closed: bool,
+    dropped: u64,
+}
+
+impl<T> Sender<T>
+where
+    T: Serialize,
+{
+    pub(crate) fn new(
+        raw: Arc<RawConnection>,
+        capacity: usize,
+        overflow: Overflow,
+    ) -> Result<Self, std::io::Error> {
+        let shared = Arc::new(Shared {
+            state: Mutex::default(),
+            changed: Condvar::new(),
+            capacity: capacity.max(1),
+            overflow,
+        });
+        let writer = shared.clone();
+        let type_name = type_name::<T>();
+        thread::Builder::new()
+            .name("easy_ipc sender".to_string())
+            .spawn(move || writer.write_queued(&raw, type_name))?;
+        Ok(Self {
+            shared,
+            _tx: PhantomData,
+        })
+    }
+
+    /// Queue a message to be sent, what happens if the queue is full depends on the [`Overflow`]
+    /// policy.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the message can't be serialized, with [`ConnectionError::QueueFull`] if the queue
+    /// is full and the policy is [`Overflow::Error`], or if writing an earlier message failed.
+    /// The first call after that gets the error of the write, later ones a
+    /// [`ConnectionError::WriteFailed`].
+    pub fn send(&self, message: T) -> Result<(), ConnectionError> {
+        let bytes = bitcode::serialize(&message).map_err(ConnectionError::SerilizationFailed)?;
+        let shared = &self.shared;
+        let mut state = shared.lock();
+        state.check()?;
+        if state.queue.len() >= shared.capacity {
+            match shared.overflow {
+                Overflow::Block => {
+                    state = shared
+                        .changed
+                        .wait_while(state, |state| {
+                            state.queue.len() >= shared.capacity && !state.failed
+                        })
+                        .unwrap_or_else(PoisonError::into_inner);
+                    state.check()?;
+                }
+                Overflow::DropOldest => {
+                    state.queue.pop_front();
+                    state.dropped += 1;
+                }
+                Overflow::DropNewest => {
+                    state.dropped += 1;
+                    return Ok(());
+                }
+                Overflow::Error => return Err(ConnectionError::QueueFull),
+            }
+        }
+        state.queue.push_back(bytes);
+        drop(state);
+        shared.changed.notify_all();
+        Ok(())
+    }
+
+    /// Wait until every queued message was written
+    ///
+    /// # Errors
+    ///
+    /// Fails if writing a message failed, like [`Sender::send`].
+    pub fn flush(&self) -> Result<(), ConnectionError> {
+        let mut state = self
+            .shared
+            .changed
+            .wait_while(self.shared.lock(), |state| {
+                (!state.queue.is_empty() || state.writing) && !state.failed
+            })
+            .unwrap_or_else(PoisonError::into_inner);
+        state.check()
+    }
+
+    /// Number of messages waiting in the queue, not counting the one being written
+    #[must_use]
+    pub fn queued(&self) -> usize {
+        self.shared.lock().queue.len()
+    }
+
+    /// Number of messages thrown away because the queue was full
+    #[must_use]
+    pub fn dropped(&self) -> u64 {
+        self.shared.lock().dropped
+    }
+
+    /// Number of messages the queue holds
+    #[must_use]
+    pub fn capacity(&self) -> usize {
+        self.shared.capacity
+    }
+}
+
+impl<T> Drop for Sender<T>
+where
+    T: Serialize,
+{
+    fn drop(&mut self) {
+        self.shared.lock().closed = true;
+        self.shared.changed.notify_all();
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Write queued messages until the sender is dropped or writing fails
+    fn write_queued(&self, raw: &RawConnection, type_name: &'static str) {
+        loop {
+            let mut state = self.lock();
+            let bytes = loop {
+                if let Some(bytes) = state.queue.pop_front() {
+                    break bytes;
+                }
+                if state.closed {
+                    return;
+                }
+                state = self
+                    .changed
+                    .wait(state)
+                    .unwrap_or_else(PoisonError::into_inner);
+            };
+            state.writing = true;
+            drop(state);
+            // There is room in the queue again
+            self.changed.notify_all();
+
+            let written = raw.write_message(type_name, &bytes);
+            let mut state = self.lock();
+            state.writing = false;
+            if let Err(e) = written {
+                state.error = Some(e);
+                state.failed = true;
+                state.queue.clear();
+            }
+            let failed = state.failed;
+            drop(state);
+            self.changed.notify_all();
+            if failed {
+                return;
+            }
+        }
+    }
+}
+
+impl State {
+    /// Fail if writing failed, the first caller gets the error itself
+    fn check(&mut self) -> Result<(), ConnectionError> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None if self.failed => Err(ConnectionError::WriteFailed(ErrorKind::BrokenPipe.into())),
+            None => Ok(()),
+        }
+    }
+}
@@ -1,10 +1,25 @@
 use {
-    crate::{connection::Connection, error::ConnectionError, model::OptionsRaw},
-    interprocess::local_socket::prelude::*,
+    crate::{
+        connection::{Connection, Frame, FrameKind, MAIN_CHANNEL, Side},
+        error::ConnectionError,
+        memory::MemoryListener,
+        metrics::{ServerCounters, ServerMetrics},
+        model::OptionsRaw,
+        token,
+        trace::{self, Span},
+    },
+    interprocess::local_socket::{ListenerNonblockingMode, Stream, prelude::*},
     serde::{Deserialize, Serialize},
-    std::{marker::PhantomData, sync::Arc},
+    std::{
+        io,
+        marker::PhantomData,
+        sync::{Arc, Mutex, PoisonError},
+    },
 };
 
+#[cfg(unix)]
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
+
 /// A instance of a server
 #[derive(Debug)]
 pub struct Server<T, R>
@@ -12,8 +27,13 @@ where
     T: Serialize,
     R: for<'de> Deserialize<'de>,
 {
-    listener: LocalSocketListener,
+    listener: Listener,
     opts: Arc<OptionsRaw>,
+    /// The token clients have to present, if token authentication is enabled
+    token: Option<Mutex<Vec<u8>>>,
+    /// Parent of the spans of the connections
+    span: Span,
+    metrics: Arc<ServerCounters>,
     _tx: PhantomData<T>,
     _rx: PhantomData<R>,
 }
@@ -24,20 +44,236 @@ where
     R: for<'de> Deserialize<'de>,
 {
     /// Get a new Server listening on a socket
-    pub(crate) fn new(listener: LocalSocketListener, opts: OptionsRaw) -> Self {
+    pub(crate) fn new(
+        listener: Listener,
+        opts: OptionsRaw,
+        token: Option<Vec<u8>>,
+        span: Span,
+    ) -> Self {
         let opts = Arc::new(opts);
         Self {
             listener,
             opts,
+            token: token.map(Mutex::new),
+            span,
+            metrics: Arc::default(),
             _tx: PhantomData,
             _rx: PhantomData,
         }
     }
-    /// Create an iterator over all connections
+    /// Create an iterator over all connections.
+    ///
+    /// Clients that fail to set up show up as errors, as do errors of the listening socket. Use
+    /// [`Server::try_accept`] to tell them apart.
     pub fn connections(&self) -> impl Iterator<Item = Result<Connection<T, R>, ConnectionError>> {
-        self.listener.incoming().map(|conn| {
-            conn.map(|c| Connection::new(c, self.opts.clone()))
+        std::iter::repeat_with(|| {
+            self.listener
+                .accept()
+                .map_err(ConnectionError::InitError)
+                .and_then(|stream| self.set_up(stream))
+        })
+    }
+
+    /// Accept a client if one is waiting, without waiting for one.
+    ///
+    /// Clients that fail to set up are counted as rejected and skipped, the next waiting client
+    /// is tried instead.
+    ///
+    /// Meant for event loops that wait for the server to become readable, see the [`AsFd`]
+    /// implementation. Setting the connection up still talks to the client, like checking its
+    /// token, which waits for the client up to [`ClientServerOptions::auth_timeout`]. Don't call
+    /// this while another thread iterates [`Server::connections`], which could find the server in
+    /// non-blocking mode.
+    ///
+    /// [`ClientServerOptions::auth_timeout`]: crate::model::ClientServerOptions::auth_timeout
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`ConnectionError::InitError`] if accepting on the listening socket fails,
+    /// which usually happens again on the next call.
+    pub fn try_accept(&self) -> Result<Option<Connection<T, R>>, ConnectionError> {
+        while let Some(stream) = self
+            .listener
+            .try_accept()
+            .map_err(ConnectionError::InitError)?
+        {
+            if let Ok(connection) = self.set_up(stream) {
+                return Ok(Some(connection));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Wait for a client and set it up, `None` if setting it up failed. Only fails if accepting
+    /// on the listening socket does.
+    pub(crate) fn accept(&self) -> Result<Option<Connection<T, R>>, io::Error> {
+        let stream = self.listener.accept()?;
+        Ok(self.set_up(stream).ok())
+    }
+
+    /// Accept a stream if one is waiting, see [`Server::try_accept`]
+    #[cfg(all(unix, feature = "event-loop"))]
+    pub(crate) fn try_accept_stream(&self) -> Result<Option<Stream>, io::Error> {
+        self.listener.try_accept()
+    }
+
+    /// Turn an accepted stream into a connection
+    fn set_up(&self, stream: Stream) -> Result<Connection<T, R>, ConnectionError> {
+        trace::in_scope(&self.span, || {
+            self.open(stream)
+                .and_then(|conn| self.authenticate(conn))
+                .inspect(|_| self.accepted())
+                .inspect_err(|e| self.rejected(e))
+        })
+    }
+
+    /// Make a connection out of an accepted stream, without authenticating the client yet
+    pub(crate) fn open(&self, stream: Stream) -> Result<Connection<T, R>, ConnectionError> {
+        trace::in_scope(&self.span, || {
+            let metrics = Some(self.metrics.clone());
+            Connection::new(stream, self.opts.clone(), Side::Server, metrics, None)
                 .map_err(ConnectionError::InitError)
         })
     }
+
+    /// Count a client that was set up
+    pub(crate) fn accepted(&self) {
+        self.metrics.accepted();
+    }
+
+    /// Count a client that failed to set up
+    pub(crate) fn rejected(&self, error: &ConnectionError) {
+        self.metrics.rejected();
+        trace::error(&self.span, "failed accepting a connection", error);
+    }
+
+    /// Counters of the connections and messages of this server so far.
+    ///
+    /// Counting is always on and only costs a few atomic additions per message. With the
+    /// `metrics` feature the same numbers are reported through the `metrics` crate facade, so
+    /// that any recorder installed with `metrics::set_global_recorder` picks them up.
+    #[must_use]
+    pub fn metrics(&self) -> ServerMetrics {
+        self.metrics.snapshot()
+    }
+
+    /// Write a new token to the token file, see
+    /// [`ClientServerOptions::token_auth`](crate::model::ClientServerOptions::token_auth).
+    ///
+    /// Clients that are already connected stay connected, new clients need the new token. Does
+    /// nothing if token authentication isn't enabled.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the new token can't be written, the old one stays valid then.
+    pub fn rotate_token(&self) -> Result<(), std::io::Error> {
+        let (Some(current), Some(path)) = (&self.token, &self.opts.token_file) else {
+            return Ok(());
+        };
+        let mut current = current.lock().unwrap_or_else(PoisonError::into_inner);
+        *current = token::generate(path)?;
+        drop(current);
+        Ok(())
+    }
+
+    /// Check the token the client sends first, if token authentication is enabled.
+    ///
+    /// The connection stops waiting for the token after [`ClientServerOptions::auth_timeout`].
+    ///
+    /// [`ClientServerOptions::auth_timeout`]: crate::model::ClientServerOptions::auth_timeout
+    fn authenticate(&self, conn: Connection<T, R>) -> Result<Connection<T, R>, ConnectionError> {
+        if !self.token_auth() {
+            return Ok(conn);
+        }
+        let frame = conn.read_frame()?;
+        if !self.authorizes(&frame) {
+            return Err(ConnectionError::Unauthorized);
+        }
+        conn.authorize()?;
+        Ok(conn)
+    }
+
+    /// Whether clients have to present a token before anything else
+    pub(crate) const fn token_auth(&self) -> bool {
+        self.token.is_some()
+    }
+
+    /// How long clients have to present their token
+    #[cfg(all(unix, feature = "event-loop"))]
+    pub(crate) fn auth_timeout(&self) -> std::time::Duration {
+        self.opts.auth_timeout
+    }
+
+    /// Whether the first frame of a client carries the right token
+    pub(crate) fn authorizes(&self, frame: &Frame) -> bool {
+        let Some(expected) = &self.token else {
+            return true;
+        };
+        let expected = expected.lock().unwrap_or_else(PoisonError::into_inner);
+        let valid = frame.kind == FrameKind::Token
+            && frame.channel == MAIN_CHANNEL
+            && token::matches(&expected, &frame.data);
+        drop(expected);
+        valid
+    }
+}
+
+/// The listening socket, polled for readiness. Readable while clients are waiting to be
+/// accepted with [`Server::try_accept`].
+#[cfg(unix)]
+impl<T, R> AsFd for Server<T, R>
+where
+    T: Serialize,
+    R: for<'de> Deserialize<'de>,
+{
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match &self.listener {
+            Listener::Socket(LocalSocketListener::UdSocket(listener)) => listener.as_fd(),
+            Listener::Memory(listener) => listener.as_fd(),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl<T, R> AsRawFd for Server<T, R>
+where
+    T: Serialize,
+    R: for<'de> Deserialize<'de>,
+{
+    fn as_raw_fd(&self) -> RawFd {
+        self.as_fd().as_raw_fd()
+    }
+}
+
+/// Where a server gets its connections from
+#[derive(Debug)]
+pub(crate) enum Listener {
+    Socket(LocalSocketListener),
+    /// See [`ClientServerOptions::in_memory`](crate::model::ClientServerOptions::in_memory)
+    Memory(MemoryListener),
+}
+
+impl Listener {
+    fn accept(&self) -> Result<Stream, io::Error> {
+        match self {
+            Self::Socket(listener) => listener.accept(),
+            Self::Memory(listener) => listener.accept(),
+        }
+    }
+
+    fn try_accept(&self) -> Result<Option<Stream>, io::Error> {
+        match self {
+            Self::Socket(listener) => {
+                listener.set_nonblocking(ListenerNonblockingMode::Accept)?;
+                let accepted = listener.accept();
+                listener.set_nonblocking(ListenerNonblockingMode::Neither)?;
+                match accepted {
+                    Ok(stream) => Ok(Some(stream)),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+                    Err(e) => Err(e),
+                }
+            }
+            Self::Memory(listener) => listener.try_accept(),
+        }
+    }
 }
@@ -0,0 +1,120 @@
+use {
+    crate::{
+        connection::{Connection, FrameKind},
+        error::ConnectionError,
+    },
+    serde::{Deserialize, Serialize},
+};
+
+/// How sending a stream with [`Connection::send_stream`] ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamStatus {
+    /// Every item was sent
+    Completed,
+    /// The other end cancelled the stream before all items were sent
+    Cancelled,
+    /// The producer returned an error, which was forwarded to the other end
+    Failed,
+}
+
+/// Iterator over a streamed response, created with [`Connection::receive_stream`].
+///
+/// Yields items until the other end finishes the stream. If the other end reports an error, it is
+/// yielded as [`ConnectionError::StreamFailed`] and the stream ends.
+///
+/// Dropping the stream before it is finished cancels it: the other end is told to stop producing
+/// items and anything already in flight is read and thrown away, so that the connection can be
+/// used for the next message. Use [`ResponseStream::cancel`] to do the same thing while seeing
+/// errors.
+#[derive(Debug)]
+pub struct ResponseStream<'a, T, R>
+where
+    T: Serialize,
+    R: for<'de> Deserialize<'de>,
+{
+    connection: &'a mut Connection<T, R>,
+    finished: bool,
+}
+
+impl<'a, T, R> ResponseStream<'a, T, R>
+where
+    T: Serialize,
+    R: for<'de> Deserialize<'de>,
+{
+    pub(crate) const fn new(connection: &'a mut Connection<T, R>) -> Self {
+        Self {
+            connection,
+            finished: false,
+        }
+    }
+
+    /// Tell the other end to stop producing items and wait for the stream to end.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the connection breaks, or the other end sends something that doesn't belong to
+    /// the stream.
+    pub fn cancel(mut self) -> Result<(), ConnectionError> {
+        self.cancel_inner()
+    }
+
+    fn cancel_inner(&mut self) -> Result<(), ConnectionError> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        self.connection.write_frame(FrameKind::Cancel, &[])?;
+        // Throw away whatever was sent before the other end saw the cancel frame. The other end
+        // always ends the stream with an end or an error frame, even when cancelled.
+        loop {
+            match self.connection.read_frame()?.kind {
+                FrameKind::StreamItem => (),
+                FrameKind::StreamEnd | FrameKind::StreamError => return Ok(()),
+                _ => return Err(ConnectionError::UnexpectedFrame),
+            }
+        }
+    }
+}
+
+impl<T, R> Iterator for ResponseStream<'_, T, R>
+where
+    T: Serialize,
+    R: for<'de> Deserialize<'de>,
+{
+    type Item = Result<R, ConnectionError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        let frame = self.connection.read_frame();
+        let item = match frame.map(|frame| (frame.kind, frame.data)) {
+            Ok((FrameKind::StreamItem, data)) => self.connection.decode(&data),
+            Ok((FrameKind::StreamEnd, _)) => {
+                self.finished = true;
+                return None;
+            }
+            Ok((FrameKind::StreamError, data)) => Err(ConnectionError::StreamFailed(
+                String::from_utf8_lossy(&data).into_owned(),
+            )),
+            Ok(_) => Err(ConnectionError::UnexpectedFrame),
+            Err(e) => Err(e),
+        };
+        // Any error other than a bad item means the stream can't continue
+        if !matches!(item, Ok(_) | Err(ConnectionError::DeserilizationFailed(_))) {
+            self.finished = true;
+        }
+        Some(item)
+    }
+}
+
+impl<T, R> Drop for ResponseStream<'_, T, R>
+where
+    T: Serialize,
+    R: for<'de> Deserialize<'de>,
+{
+    fn drop(&mut self) {
+        // Nowhere to report errors here, the next send or receive on the connection will fail.
+        let _ = self.cancel_inner();
+    }
+}
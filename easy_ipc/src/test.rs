@@ -4,7 +4,12 @@ use std::time::Duration;
 use interprocess::local_socket::{GenericNamespaced, NameType};
 use serde::{Deserialize, Serialize};
 
+use crate::broker::{Broker, BrokerClient, BrokerEvent, BrokerRequest};
+use crate::capture::Direction;
+use crate::checksum::Checksum;
+use crate::connection::Connection;
 use crate::error::{ConnectionError, InitError};
+use crate::fault::{Fault, FaultyTransport};
 use crate::handlers::clean;
 use crate::prelude::*;
 
@@ -160,3 +165,2433 @@ fn basic_send_receive() {
         ConnectionError::UnexepctedEof
     ));
 }
+
+#[test]
+fn schema_fingerprint_mismatch() {
+    const SOCKET_NAME: &str = "schema_fingerprint_mismatch.socket";
+
+    struct ServerModel;
+    impl IpcModel for ServerModel {
+        type ServerMsg = String;
+        type ClientMsg = u32;
+
+        fn model() -> Result<ClientServerModel<Self::ClientMsg, Self::ServerMsg>, InitError> {
+            Ok(
+                ClientServerOptions::new(crate::namespace::namespace(SOCKET_NAME)?)
+                    .disable_single_server_check()
+                    .handlers(|_model| {})
+                    .schema_fingerprint()
+                    .create(),
+            )
+        }
+    }
+
+    // Client thinks it is sending a different type
+    struct ClientModel;
+    impl IpcModel for ClientModel {
+        type ServerMsg = String;
+        type ClientMsg = u64;
+
+        fn model() -> Result<ClientServerModel<Self::ClientMsg, Self::ServerMsg>, InitError> {
+            Ok(
+                ClientServerOptions::new(crate::namespace::namespace(SOCKET_NAME)?)
+                    .schema_fingerprint()
+                    .create(),
+            )
+        }
+    }
+
+    let server_model = ServerModel::model().unwrap();
+    let client_model = ClientModel::model().unwrap();
+    assert_ne!(server_model.schema().hash(), client_model.schema().hash());
+    clean(&server_model.options().socket_name);
+
+    let server = ServerModel::server().unwrap();
+    let handle = spawn(move || {
+        let mut conn = server.connections().next().unwrap().unwrap();
+        assert!(matches!(
+            conn.receive().unwrap_err(),
+            ConnectionError::HeaderMismatch
+        ));
+    });
+
+    let mut client = ClientModel::client().unwrap();
+    client.send(42).unwrap();
+    handle.join().unwrap();
+}
+
+/// Model where the client asks for a number of items and the server streams them back
+struct CountModel;
+impl IpcModel for CountModel {
+    type ServerMsg = u64;
+    type ClientMsg = u64;
+
+    fn model() -> Result<ClientServerModel<Self::ClientMsg, Self::ServerMsg>, InitError> {
+        Ok(
+            ClientServerOptions::new(crate::namespace::namespace("stream_count.socket")?)
+                .disable_single_server_check()
+                .handlers(|_model| {})
+                .create(),
+        )
+    }
+}
+
+#[test]
+fn stream_complete_cancel_and_fail() {
+    use crate::stream::StreamStatus;
+
+    clean(&CountModel::model().unwrap().options().socket_name);
+    let server = CountModel::server().unwrap();
+
+    let handle = spawn(move || {
+        let mut conn = server.connections().next().unwrap().unwrap();
+        // Finite stream
+        let count = conn.receive().unwrap();
+        assert_eq!(conn.send_stream(0..count).unwrap(), StreamStatus::Completed);
+        // Never ending stream that the client cancels
+        conn.receive().unwrap();
+        assert_eq!(conn.send_stream(0..).unwrap(), StreamStatus::Cancelled);
+        // Stream that fails part of the way through
+        conn.receive().unwrap();
+        let items = [Ok(1), Err("out of numbers")];
+        assert_eq!(
+            conn.send_stream_fallible(items).unwrap(),
+            StreamStatus::Failed
+        );
+        // Regular messages still work afterwards
+        let msg = conn.receive().unwrap();
+        conn.send(msg + 1).unwrap();
+    });
+
+    let mut client = CountModel::client().unwrap();
+    client.send(5).unwrap();
+    let items: Vec<u64> = client.receive_stream().map(|i| i.unwrap()).collect();
+    assert_eq!(items, vec![0, 1, 2, 3, 4]);
+
+    client.send(0).unwrap();
+    let first: Vec<u64> = client
+        .receive_stream()
+        .take(3)
+        .map(|i| i.unwrap())
+        .collect();
+    assert_eq!(first, vec![0, 1, 2]);
+
+    client.send(0).unwrap();
+    let mut stream = client.receive_stream();
+    assert_eq!(stream.next().unwrap().unwrap(), 1);
+    assert!(matches!(
+        stream.next().unwrap().unwrap_err(),
+        ConnectionError::StreamFailed(e) if e == "out of numbers"
+    ));
+    assert!(stream.next().is_none());
+    drop(stream);
+
+    client.send(41).unwrap();
+    assert_eq!(client.receive().unwrap(), 42);
+    handle.join().unwrap();
+}
+
+/// Model where the client sends a number and the server answers with a number
+struct RequestModel;
+impl IpcModel for RequestModel {
+    type ServerMsg = u64;
+    type ClientMsg = u64;
+
+    fn model() -> Result<ClientServerModel<Self::ClientMsg, Self::ServerMsg>, InitError> {
+        Ok(
+            ClientServerOptions::new(crate::namespace::namespace("request_cancel.socket")?)
+                .disable_single_server_check()
+                .handlers(|_model| {})
+                .create(),
+        )
+    }
+}
+
+#[test]
+fn request_deadline_and_cancel() {
+    use std::time::SystemTime;
+
+    let deadline = SystemTime::UNIX_EPOCH + Duration::from_secs(1);
+
+    clean(&RequestModel::model().unwrap().options().socket_name);
+    let server = RequestModel::server().unwrap();
+    let handle = spawn(move || {
+        let mut conn = server.connections().next().unwrap().unwrap();
+        // Deadline long gone
+        let (msg, context) = conn.receive_request().unwrap();
+        assert_eq!(msg, 1);
+        assert_eq!(context.deadline(), Some(deadline));
+        assert!(context.is_cancelled());
+        conn.send(0).unwrap();
+
+        // No deadline, work until the client cancels
+        let (msg, context) = conn.receive_request().unwrap();
+        assert_eq!(msg, 2);
+        assert_eq!(context.deadline(), None);
+        while !context.is_cancelled() {
+            sleep(Duration::from_millis(1));
+        }
+        conn.send(3).unwrap();
+
+        // Plain messages still work after a cancel
+        let msg = conn.receive().unwrap();
+        conn.send(msg).unwrap();
+    });
+
+    let mut client = RequestModel::client().unwrap();
+    client.send_with_deadline(1, deadline).unwrap();
+    assert_eq!(client.receive().unwrap(), 0);
+
+    client.send(2).unwrap();
+    let cancel = client.cancel_handle();
+    spawn(move || {
+        sleep(Duration::from_millis(20));
+        cancel.cancel().unwrap();
+    });
+    assert_eq!(client.receive().unwrap(), 3);
+
+    client.send(4).unwrap();
+    assert_eq!(client.receive().unwrap(), 4);
+    handle.join().unwrap();
+}
+
+/// Model that only provides the socket for the multiplexed models below
+struct HostModel;
+impl IpcModel for HostModel {
+    type ServerMsg = ();
+    type ClientMsg = ();
+
+    fn model() -> Result<ClientServerModel<Self::ClientMsg, Self::ServerMsg>, InitError> {
+        Ok(
+            ClientServerOptions::new(crate::namespace::namespace("mux_host.socket")?)
+                .disable_single_server_check()
+                .handlers(|_model| {})
+                .channel_window(2)
+                .create(),
+        )
+    }
+}
+
+/// The client sends a word and the server answers with its length
+struct WordModel;
+impl IpcModel for WordModel {
+    type ServerMsg = u64;
+    type ClientMsg = String;
+
+    fn model() -> Result<ClientServerModel<Self::ClientMsg, Self::ServerMsg>, InitError> {
+        Ok(ClientServerOptions::new(crate::namespace::namespace("mux_word.socket")?).create())
+    }
+}
+
+#[test]
+fn multiplexed_channels() {
+    use crate::mux::Multiplexer;
+
+    clean(&HostModel::model().unwrap().options().socket_name);
+    let server = HostModel::server().unwrap();
+    let handle = spawn(move || {
+        let conn = server.connections().next().unwrap().unwrap();
+        let mux = Multiplexer::server(conn);
+        let mut numbers = mux.open_channel::<u64, u64>("numbers").unwrap();
+        let mut words = mux.open_model::<WordModel>("words").unwrap();
+
+        // The numbers are already waiting, but the words channel isn't held up by them
+        let word = words.receive().unwrap();
+        words.send(word.len() as u64).unwrap();
+        for _ in 0..5 {
+            let number = numbers.receive().unwrap();
+            numbers.send(number * 2).unwrap();
+        }
+        assert!(matches!(
+            numbers.receive(),
+            Err(ConnectionError::ChannelClosed)
+        ));
+
+        // Closed on both ends, the channel can be opened again
+        drop(numbers);
+        let mut numbers = mux.open_channel::<u64, u64>("numbers").unwrap();
+        numbers.send(42).unwrap();
+        words.send(0).unwrap();
+        assert!(matches!(
+            numbers.receive(),
+            Err(ConnectionError::ChannelClosed)
+        ));
+    });
+
+    let mux = HostModel::client().unwrap().into_multiplexer();
+    let mut numbers = mux.open_channel::<u64, u64>("numbers").unwrap();
+    let mut words = mux.open_model::<WordModel>("words").unwrap();
+    assert!(matches!(
+        mux.open_channel::<u64, u64>("numbers"),
+        Err(ConnectionError::ChannelInUse(_))
+    ));
+
+    // More messages than the window allows, this waits for the server to catch up
+    let sender = spawn(move || {
+        for number in 0..5 {
+            numbers.send(number).unwrap();
+        }
+        numbers
+    });
+    words.send("hello".to_string()).unwrap();
+    assert_eq!(words.receive().unwrap(), 5);
+
+    let mut numbers = sender.join().unwrap();
+    for number in 0..5 {
+        assert_eq!(numbers.receive().unwrap(), number * 2);
+    }
+    drop(numbers);
+    // The server didn't close its end yet as far as we know
+    assert!(matches!(
+        mux.open_channel::<u64, u64>("numbers"),
+        Err(ConnectionError::ChannelInUse(_))
+    ));
+    assert_eq!(words.receive().unwrap(), 0);
+    let mut numbers = mux.open_channel::<u64, u64>("numbers").unwrap();
+    assert_eq!(numbers.receive().unwrap(), 42);
+    drop(numbers);
+    handle.join().unwrap();
+}
+
+/// Model that only provides the socket for the multiplexer of the test below
+struct OneSidedModel;
+impl IpcModel for OneSidedModel {
+    type ServerMsg = ();
+    type ClientMsg = ();
+
+    fn model() -> Result<ClientServerModel<Self::ClientMsg, Self::ServerMsg>, InitError> {
+        Ok(
+            ClientServerOptions::new(crate::namespace::namespace("mux_one_sided.socket")?)
+                .disable_single_server_check()
+                .handlers(|_model| {})
+                .create(),
+        )
+    }
+}
+
+#[test]
+fn multiplexed_channels_opened_by_one_end() {
+    use crate::mux::Multiplexer;
+
+    clean(&OneSidedModel::model().unwrap().options().socket_name);
+    let server = OneSidedModel::server().unwrap();
+    let client = OneSidedModel::client().unwrap();
+    let mux = Multiplexer::server(server.connections().next().unwrap().unwrap());
+    let mut last = mux.open_channel::<u64, u64>("last").unwrap();
+
+    // The client can send to a bounded number of channels the server never opened
+    let client = client.into_multiplexer();
+    for i in 0..64 {
+        let mut channel = client.open_channel::<u64, u64>(&i.to_string()).unwrap();
+        channel.send(i).unwrap();
+    }
+    let mut channel = client.open_channel::<u64, u64>("last").unwrap();
+    channel.send(64).unwrap();
+    assert_eq!(last.receive().unwrap(), 64);
+
+    let mut channel = client.open_channel::<u64, u64>("one too many").unwrap();
+    channel.send(65).unwrap();
+    assert!(matches!(
+        last.receive(),
+        Err(ConnectionError::UnexpectedFrame)
+    ));
+}
+
+/// Model that only provides the socket for the multiplexer of the test below
+struct CollisionModel;
+impl IpcModel for CollisionModel {
+    type ServerMsg = ();
+    type ClientMsg = ();
+
+    fn model() -> Result<ClientServerModel<Self::ClientMsg, Self::ServerMsg>, InitError> {
+        Ok(
+            ClientServerOptions::new(crate::namespace::namespace("mux_collision.socket")?)
+                .disable_single_server_check()
+                .handlers(|_model| {})
+                .create(),
+        )
+    }
+}
+
+#[test]
+fn multiplexed_channel_name_collisions() {
+    use crate::mux::Multiplexer;
+
+    clean(&CollisionModel::model().unwrap().options().socket_name);
+    let server = CollisionModel::server().unwrap();
+    let client = CollisionModel::client().unwrap().into_multiplexer();
+    let server = Multiplexer::server(server.connections().next().unwrap().unwrap());
+    // Both names map to the same channel id
+    let (ours, theirs) = ("channel 2179599", "channel 2362382");
+    let names = |e: ConnectionError| match e {
+        ConnectionError::ChannelNameCollision(a, b) => Some((a, b)),
+        _ => None,
+    };
+
+    let mut channel = client.open_channel::<u64, u64>(ours).unwrap();
+    let collided = client.open_channel::<u64, u64>(theirs);
+    assert_eq!(
+        collided.err().and_then(names),
+        Some((theirs.to_string(), ours.to_string()))
+    );
+
+    // The other end can't know about it before our name arrives, then both channels fail
+    let mut other = server.open_channel::<u64, u64>(theirs).unwrap();
+    let received = other.receive();
+    assert_eq!(
+        received.err().and_then(names),
+        Some((theirs.to_string(), ours.to_string()))
+    );
+    let received = channel.receive();
+    assert_eq!(
+        received.err().and_then(names),
+        Some((ours.to_string(), theirs.to_string()))
+    );
+}
+
+/// Server side of the heartbeat test, it never sends heartbeats
+struct SilentModel;
+impl IpcModel for SilentModel {
+    type ServerMsg = u64;
+    type ClientMsg = u64;
+
+    fn model() -> Result<ClientServerModel<Self::ClientMsg, Self::ServerMsg>, InitError> {
+        Ok(
+            ClientServerOptions::new(crate::namespace::namespace("heartbeat.socket")?)
+                .disable_single_server_check()
+                .handlers(|_model| {})
+                .create(),
+        )
+    }
+}
+
+/// Same socket as [`SilentModel`] but with heartbeats
+struct HeartbeatModel;
+impl IpcModel for HeartbeatModel {
+    type ServerMsg = u64;
+    type ClientMsg = u64;
+
+    fn model() -> Result<ClientServerModel<Self::ClientMsg, Self::ServerMsg>, InitError> {
+        Ok(
+            ClientServerOptions::new(crate::namespace::namespace("heartbeat.socket")?)
+                .disable_single_server_check()
+                .handlers(|_model| {})
+                .heartbeat(Duration::from_millis(20), 10)
+                .create(),
+        )
+    }
+}
+
+#[test]
+fn heartbeat_detects_unresponsive_peer() {
+    clean(&HeartbeatModel::model().unwrap().options().socket_name);
+
+    // A slow server that keeps sending heartbeats is fine
+    let server = HeartbeatModel::server().unwrap();
+    let handle = spawn(move || {
+        let mut conn = server.connections().next().unwrap().unwrap();
+        let msg = conn.receive().unwrap();
+        sleep(Duration::from_millis(200));
+        conn.send(msg + 1).unwrap();
+    });
+    let mut client = HeartbeatModel::client().unwrap();
+    // Idle time doesn't count against the server
+    sleep(Duration::from_millis(100));
+    client.send(1).unwrap();
+    assert_eq!(client.receive().unwrap(), 2);
+    handle.join().unwrap();
+
+    // A server that doesn't answer and doesn't send heartbeats is given up on
+    let server = SilentModel::server().unwrap();
+    let handle = spawn(move || {
+        let mut conn = server.connections().next().unwrap().unwrap();
+        conn.receive().unwrap();
+        // Keep the connection open without answering
+        sleep(Duration::from_millis(1000));
+    });
+    let mut client = HeartbeatModel::client().unwrap();
+    client.send(1).unwrap();
+    assert!(matches!(
+        client.receive(),
+        Err(ConnectionError::PeerUnresponsive)
+    ));
+    assert!(matches!(
+        client.receive(),
+        Err(ConnectionError::PeerUnresponsive)
+    ));
+    handle.join().unwrap();
+}
+
+/// Model where the server multiplies by ten, after the client introduced itself with a zero
+struct ReconnectModel;
+impl IpcModel for ReconnectModel {
+    type ServerMsg = u64;
+    type ClientMsg = u64;
+
+    fn model() -> Result<ClientServerModel<Self::ClientMsg, Self::ServerMsg>, InitError> {
+        Ok(
+            ClientServerOptions::new(crate::namespace::namespace("reconnect.socket")?)
+                .disable_single_server_check()
+                .handlers(|_model| {})
+                .create(),
+        )
+    }
+}
+
+#[test]
+fn reconnecting_client() {
+    use crate::reconnect::{ReconnectEvent, ReconnectingClient};
+    use std::sync::{Arc, Mutex};
+
+    // Handle a single connection and then go away, like a server that restarts
+    let serve_once = || {
+        let server = ReconnectModel::server().unwrap();
+        spawn(move || {
+            let mut conn = server.connections().next().unwrap().unwrap();
+            assert_eq!(conn.receive().unwrap(), 0);
+            let msg = conn.receive().unwrap();
+            conn.send(msg * 10).unwrap();
+        })
+    };
+
+    clean(&ReconnectModel::model().unwrap().options().socket_name);
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events_clone = events.clone();
+    let mut client = ReconnectingClient::<ReconnectModel>::new()
+        .backoff(Duration::from_millis(1), Duration::from_millis(10))
+        .session_init(0)
+        .on_event(move |event| {
+            let name = match event {
+                ReconnectEvent::Disconnected(_) => "disconnected",
+                ReconnectEvent::AttemptFailed { .. } => "failed",
+                ReconnectEvent::Reconnected { .. } => "reconnected",
+                ReconnectEvent::GaveUp { .. } => "gave up",
+            };
+            events_clone.lock().unwrap().push(name);
+        });
+
+    let handle = serve_once();
+    client.send(1).unwrap();
+    assert_eq!(client.receive().unwrap(), 10);
+    handle.join().unwrap();
+
+    // The old connection is gone, sending notices and goes to the new server
+    let handle = serve_once();
+    client.send(2).unwrap();
+    assert_eq!(client.receive().unwrap(), 20);
+    handle.join().unwrap();
+    assert_eq!(*events.lock().unwrap(), ["disconnected", "reconnected"]);
+
+    // A server that hangs up right away makes us back off, as if connecting failed
+    let server = ReconnectModel::server().unwrap();
+    let handle = spawn(move || {
+        for conn in server.connections().take(3) {
+            conn.unwrap().receive().unwrap();
+        }
+    });
+    let mut client = ReconnectingClient::<ReconnectModel>::new()
+        .backoff(Duration::from_millis(20), Duration::from_secs(1));
+    let start = std::time::Instant::now();
+    for i in 0..3 {
+        client.send(i).unwrap();
+        assert!(client.receive().is_err());
+    }
+    assert!(start.elapsed() >= Duration::from_millis(60));
+    handle.join().unwrap();
+
+    // Nothing to reconnect to
+    let mut client = ReconnectingClient::<ReconnectModel>::new()
+        .backoff(Duration::from_millis(1), Duration::from_millis(1))
+        .max_attempts(3);
+    assert!(matches!(
+        client.send(1),
+        Err(ConnectionError::ReconnectFailed(_))
+    ));
+}
+
+/// Model of the server started by [`client_or_spawn`], which adds one to every number
+struct SpawnModel;
+impl IpcModel for SpawnModel {
+    type ServerMsg = u64;
+    type ClientMsg = u64;
+
+    fn model() -> Result<ClientServerModel<Self::ClientMsg, Self::ServerMsg>, InitError> {
+        Ok(
+            ClientServerOptions::new(crate::namespace::namespace("spawn.socket")?)
+                .disable_single_server_check()
+                .handlers(|_model| {})
+                .create(),
+        )
+    }
+}
+
+/// Environment variable telling [`spawned_server`] where to log that it started
+const SPAWN_LOG: &str = "EASY_IPC_SPAWN_LOG";
+const SPAWN_CLIENTS: usize = 4;
+
+/// Not a real test, this is the server started by [`client_or_spawn`]
+#[test]
+#[ignore = "run by client_or_spawn"]
+fn spawned_server() {
+    use std::io::Write;
+
+    let Some(log) = std::env::var_os(SPAWN_LOG) else {
+        return;
+    };
+    let mut log = std::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(log)
+        .unwrap();
+    writeln!(log, "{}", std::process::id()).unwrap();
+
+    let server = SpawnModel::server().unwrap();
+    for conn in server.connections().take(SPAWN_CLIENTS) {
+        let mut conn = conn.unwrap();
+        let msg = conn.receive().unwrap();
+        conn.send(msg + 1).unwrap();
+    }
+}
+
+#[test]
+fn client_or_spawn() {
+    use std::process::Command;
+
+    clean(&SpawnModel::model().unwrap().options().socket_name);
+    let log = std::env::temp_dir().join("easy_ipc_spawn_test.log");
+    let _ = std::fs::remove_file(&log);
+
+    let handles: Vec<_> = (0..SPAWN_CLIENTS as u64)
+        .map(|i| {
+            let log = log.clone();
+            spawn(move || {
+                let mut command = Command::new(std::env::current_exe().unwrap());
+                command
+                    .args(["test::spawned_server", "--exact", "--ignored"])
+                    .env(SPAWN_LOG, log);
+                let mut client = SpawnModel::client_or_spawn(command).unwrap();
+                client.send(i).unwrap();
+                assert_eq!(client.receive().unwrap(), i + 1);
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    // Only one server was started
+    let started = std::fs::read_to_string(&log).unwrap();
+    assert_eq!(started.lines().count(), 1);
+}
+
+/// Generates models on the compression test socket with the given compression
+#[cfg(all(feature = "zstd", feature = "lz4"))]
+macro_rules! compression_model {
+    ($name:ident, $compression:expr) => {
+        struct $name;
+        impl IpcModel for $name {
+            type ServerMsg = String;
+            type ClientMsg = String;
+
+            fn model() -> Result<ClientServerModel<Self::ClientMsg, Self::ServerMsg>, InitError> {
+                Ok(
+                    ClientServerOptions::new(crate::namespace::namespace("compression.socket")?)
+                        .disable_single_server_check()
+                        .handlers(|_model| {})
+                        .compression($compression)
+                        .compression_threshold(64)
+                        .create(),
+                )
+            }
+        }
+    };
+}
+
+#[cfg(all(feature = "zstd", feature = "lz4"))]
+#[test]
+fn compressed_messages() {
+    use crate::compression::Compression;
+
+    compression_model!(ZstdModel, Compression::Zstd { level: 3 });
+    compression_model!(Lz4Model, Compression::Lz4);
+    compression_model!(PlainModel, Compression::None);
+
+    clean(&ZstdModel::model().unwrap().options().socket_name);
+    let server = ZstdModel::server().unwrap();
+    let config = "key = value\n".repeat(100);
+    let short = "hi".to_string();
+    // Bytes the server received of the messages, headers left out
+    let received = |conn: &Connection<String, String>| {
+        let header = crate::connection::header_length(ZstdModel::model().unwrap().options());
+        let metrics = conn.metrics();
+        metrics.bytes_received - metrics.messages_received * header as u64
+    };
+
+    std::thread::scope(|s| {
+        let handle = s.spawn(|| {
+            server
+                .connections()
+                .take(2)
+                .map(|conn| {
+                    let mut conn = conn.unwrap();
+                    while let Ok(msg) = conn.receive() {
+                        conn.send(msg.repeat(2)).unwrap();
+                    }
+                    received(&conn)
+                })
+                .collect::<Vec<_>>()
+        });
+
+        // Different algorithms on each end, both can decompress either
+        let mut client = Lz4Model::client().unwrap();
+        for msg in [&config, &short, &config] {
+            client.send(msg.clone()).unwrap();
+            assert_eq!(client.receive().unwrap(), msg.repeat(2));
+        }
+        drop(client);
+
+        // Falls back to no compression for a peer that doesn't advertise it
+        let mut client = PlainModel::client().unwrap();
+        client.send(config.clone()).unwrap();
+        assert_eq!(client.receive().unwrap(), config.repeat(2));
+        drop(client);
+
+        let received = handle.join().unwrap();
+        // The first config may go out before the capabilities of the server arrived, the second
+        // doesn't
+        let config_len = config.len() as u64;
+        assert!(received[0] < 2 * config_len, "{received:?}");
+        assert!(received[1] > config_len, "{received:?}");
+    });
+
+    // A client that only sends picks up what the server supports without reading
+    let mut client = Lz4Model::client().unwrap();
+    let mut conn = server.connections().next().unwrap().unwrap();
+    client.send(config.clone()).unwrap();
+    assert_eq!(conn.receive().unwrap(), config);
+    assert!(received(&conn) < config.len() as u64);
+
+    // Messages that decompress to more than the maximum are refused
+    struct LimitModel;
+    impl IpcModel for LimitModel {
+        type ServerMsg = String;
+        type ClientMsg = String;
+
+        fn model() -> Result<ClientServerModel<String, String>, InitError> {
+            Ok(
+                ClientServerOptions::new(crate::namespace::namespace("compression_limit.socket")?)
+                    .disable_single_server_check()
+                    .handlers(|_model| {})
+                    .compression(Compression::Zstd { level: 3 })
+                    .compression_threshold(64)
+                    .max_message_size(1000)
+                    .create(),
+            )
+        }
+    }
+    clean(&LimitModel::model().unwrap().options().socket_name);
+    let server = LimitModel::server().unwrap();
+    let mut client = LimitModel::client().unwrap();
+    let mut conn = server.connections().next().unwrap().unwrap();
+    client.send(config).unwrap();
+    let result = conn.receive();
+    assert!(
+        matches!(result, Err(ConnectionError::CompressionFailed(_))),
+        "{result:?}"
+    );
+}
+
+/// Generates models for the checksum test with the given checksum
+#[cfg(all(feature = "crc32c", feature = "xxhash"))]
+macro_rules! checksum_model {
+    ($name:ident : $socket_name:literal, $checksum:expr) => {
+        struct $name;
+        impl IpcModel for $name {
+            type ServerMsg = String;
+            type ClientMsg = String;
+
+            fn model() -> Result<ClientServerModel<Self::ClientMsg, Self::ServerMsg>, InitError> {
+                Ok(
+                    ClientServerOptions::new(crate::namespace::namespace($socket_name)?)
+                        .disable_single_server_check()
+                        .handlers(|_model| {})
+                        .checksum($checksum)
+                        .create(),
+                )
+            }
+        }
+    };
+}
+
+/// Talks to an echo server of `S` through a relay listening on the socket of `C` that flips a
+/// single bit somewhere in what the server sends, making sure the corruption is always caught.
+#[cfg(all(feature = "crc32c", feature = "xxhash"))]
+fn corrupted_stream<S, C>()
+where
+    S: IpcModel<ClientMsg = String, ServerMsg = String>,
+    C: IpcModel<ClientMsg = String, ServerMsg = String>,
+{
+    use crate::model::pathbuf_to_interprocess_name;
+    use interprocess::{
+        TryClone,
+        local_socket::{ListenerOptions, Stream, prelude::*},
+    };
+    use std::io::{Read, Write};
+
+    const ROUNDS: usize = 20;
+    const MESSAGES: usize = 60;
+
+    let server_path = S::model().unwrap().options().socket_name.clone();
+    let relay_path = C::model().unwrap().options().socket_name.clone();
+    clean(&server_path);
+    clean(&relay_path);
+
+    let server = S::server().unwrap();
+    spawn(move || {
+        for conn in server.connections().take(ROUNDS) {
+            let mut conn = conn.unwrap();
+            spawn(move || {
+                while let Ok(msg) = conn.receive() {
+                    if conn.send(msg).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+
+    let relay = ListenerOptions::new()
+        .name(pathbuf_to_interprocess_name(&relay_path).unwrap())
+        .create_sync()
+        .unwrap();
+    spawn(move || {
+        // Deterministic xorshift so that failures can be reproduced
+        let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+        for client in relay.incoming().take(ROUNDS) {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            // Somewhere in the middle of the stream, past the first few frames
+            let flip_at = 100 + usize::try_from(seed % 1000).unwrap();
+            let bit = 1 << (seed >> 32) % 8;
+
+            let mut client = client.unwrap();
+            let mut upstream =
+                Stream::connect(pathbuf_to_interprocess_name(&server_path).unwrap()).unwrap();
+            let mut client_reader = client.try_clone().unwrap();
+            let mut upstream_writer = upstream.try_clone().unwrap();
+            spawn(move || std::io::copy(&mut client_reader, &mut upstream_writer));
+            spawn(move || {
+                let mut offset = 0;
+                let mut buf = [0; 256];
+                while let Ok(n @ 1..) = upstream.read(&mut buf) {
+                    if (offset..offset + n).contains(&flip_at) {
+                        buf[flip_at - offset] ^= bit;
+                    }
+                    offset += n;
+                    if client.write_all(&buf[..n]).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+
+    for _ in 0..ROUNDS {
+        let mut client = C::client().unwrap();
+        let mut error = None;
+        for i in 0..MESSAGES {
+            let msg = format!("message number {i}");
+            client.send(msg.clone()).unwrap();
+            match client.receive() {
+                // Whatever gets through has to be intact
+                Ok(received) => assert_eq!(received, msg),
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            }
+        }
+        // A flipped magic byte is caught before the checksum
+        assert!(
+            matches!(
+                error,
+                Some(ConnectionError::ChecksumMismatch | ConnectionError::HeaderMismatch)
+            ),
+            "{error:?}"
+        );
+    }
+}
+
+#[cfg(all(feature = "crc32c", feature = "xxhash"))]
+#[test]
+fn checksum_detects_corruption() {
+    use crate::checksum::Checksum;
+
+    checksum_model!(CrcServer: "checksum_crc.socket", Checksum::Crc32c);
+    checksum_model!(CrcRelay: "checksum_crc_relay.socket", Checksum::Crc32c);
+    checksum_model!(XxServer: "checksum_xx.socket", Checksum::XxHash);
+    checksum_model!(XxRelay: "checksum_xx_relay.socket", Checksum::XxHash);
+
+    corrupted_stream::<CrcServer, CrcRelay>();
+    corrupted_stream::<XxServer, XxRelay>();
+}
+
+#[cfg(feature = "noise")]
+#[test]
+fn noise_encrypted_connections() {
+    use crate::noise::Keypair;
+    use std::sync::OnceLock;
+
+    /// Keys of the server, an allowed client and a client that isn't allowed
+    fn keys() -> &'static [Keypair; 3] {
+        static KEYS: OnceLock<[Keypair; 3]> = OnceLock::new();
+        KEYS.get_or_init(|| {
+            [
+                Keypair::generate().unwrap(),
+                Keypair::generate().unwrap(),
+                Keypair::generate().unwrap(),
+            ]
+        })
+    }
+
+    macro_rules! noise_model {
+        ($name:ident, |$opts:ident| $configure:expr) => {
+            struct $name;
+            impl IpcModel for $name {
+                type ServerMsg = String;
+                type ClientMsg = String;
+
+                fn model() -> Result<ClientServerModel<Self::ClientMsg, Self::ServerMsg>, InitError>
+                {
+                    let $opts =
+                        ClientServerOptions::new(crate::namespace::namespace("noise.socket")?)
+                            .disable_single_server_check()
+                            .handlers(|_model| {});
+                    Ok($configure.create())
+                }
+            }
+        };
+    }
+
+    noise_model!(NoiseServer, |opts| opts
+        .noise(keys()[0].clone())
+        .noise_allowed_clients([keys()[1].public()]));
+    // Learns the key of the server during the handshake
+    noise_model!(XxClient, |opts| opts.noise(keys()[1].clone()));
+    // Knows the key of the server ahead of time
+    noise_model!(IkClient, |opts| opts
+        .noise(keys()[1].clone())
+        .noise_server_key(keys()[0].public()));
+    noise_model!(RogueClient, |opts| opts.noise(keys()[2].clone()));
+    noise_model!(WrongServerKeyClient, |opts| opts
+        .noise(keys()[1].clone())
+        .noise_server_key(keys()[2].public()));
+
+    clean(&NoiseServer::model().unwrap().options().socket_name);
+    let server = NoiseServer::server().unwrap();
+    let handle = spawn(move || {
+        let mut rejected = 0;
+        for conn in server.connections().take(4) {
+            let Ok(mut conn) = conn else {
+                rejected += 1;
+                continue;
+            };
+            while let Ok(msg) = conn.receive() {
+                conn.send(msg.repeat(2)).unwrap();
+            }
+        }
+        rejected
+    });
+
+    // Longer than a single Noise message
+    let long = "secret".repeat(20_000);
+    // The server handles one connection at a time, and connecting waits for the handshake
+    let clients: [fn() -> _; 2] = [XxClient::client, IkClient::client];
+    for client in clients {
+        let mut client = client().unwrap();
+        for msg in ["hello", long.as_str()] {
+            client.send(msg.to_string()).unwrap();
+            assert_eq!(client.receive().unwrap(), msg.repeat(2));
+        }
+        // Frames held back are sealed in the order they are written, empty ones included
+        client.cork();
+        client
+            .send_batch(&["corked".to_string(), String::new()])
+            .unwrap();
+        client.uncork().unwrap();
+        assert_eq!(client.receive().unwrap(), "corkedcorked");
+        assert_eq!(client.receive().unwrap(), "");
+    }
+
+    // The server hangs up on a client it doesn't know
+    let mut rogue = RogueClient::client().unwrap();
+    rogue.send("let me in".to_string()).ok();
+    assert!(rogue.receive().is_err());
+
+    // And a client refuses to talk to a server with another key
+    assert!(WrongServerKeyClient::client().is_err());
+    assert_eq!(handle.join().unwrap(), 2);
+}
+
+#[cfg(feature = "noise")]
+#[test]
+fn noise_authenticated_headers() {
+    use crate::noise::Keypair;
+    use std::sync::OnceLock;
+
+    /// Keys of the server and the client
+    fn keys() -> &'static [Keypair; 2] {
+        static KEYS: OnceLock<[Keypair; 2]> = OnceLock::new();
+        KEYS.get_or_init(|| [Keypair::generate().unwrap(), Keypair::generate().unwrap()])
+    }
+
+    fn options() -> ClientServerOptions<String, String> {
+        ClientServerOptions::in_memory("noise headers")
+    }
+
+    struct Server;
+    impl IpcModel for Server {
+        type ServerMsg = String;
+        type ClientMsg = String;
+
+        fn model() -> Result<ClientServerModel<String, String>, InitError> {
+            Ok(options().noise(keys()[0].clone()).create())
+        }
+    }
+
+    struct TamperedClient;
+    impl IpcModel for TamperedClient {
+        type ServerMsg = String;
+        type ClientMsg = String;
+
+        fn model() -> Result<ClientServerModel<String, String>, InitError> {
+            let channel = options().create().options().magic_bytes.len() + 1;
+            // The handshake and the first message pass, the channel of the second is changed
+            let faults = [Fault::Pass, Fault::Pass, Fault::Corrupt(channel)];
+            Ok(options()
+                .noise(keys()[1].clone())
+                .noise_server_key(keys()[0].public())
+                .faults(FaultyTransport::script(faults))
+                .create())
+        }
+    }
+
+    let server = Server::server().unwrap();
+    // Connecting waits for the handshake, which needs the server
+    let handle = spawn(move || {
+        let mut conn = server.connections().next().unwrap().unwrap();
+        assert_eq!(conn.receive().unwrap(), "hello");
+        conn.receive()
+    });
+    let mut client = TamperedClient::client().unwrap();
+    client.send("hello".to_string()).unwrap();
+    client.send("hello again".to_string()).unwrap();
+    let result = handle.join().unwrap();
+    assert!(
+        matches!(result, Err(ConnectionError::DecryptionFailed)),
+        "{result:?}"
+    );
+}
+
+#[cfg(feature = "noise")]
+#[test]
+fn noise_heartbeats_while_corked() {
+    use crate::noise::Keypair;
+
+    struct CorkedModel;
+    impl IpcModel for CorkedModel {
+        type ServerMsg = String;
+        type ClientMsg = String;
+
+        fn model() -> Result<ClientServerModel<String, String>, InitError> {
+            static KEY: std::sync::OnceLock<Keypair> = std::sync::OnceLock::new();
+            Ok(ClientServerOptions::in_memory("noise cork")
+                .noise(KEY.get_or_init(|| Keypair::generate().unwrap()).clone())
+                .heartbeat(Duration::from_millis(5), 100)
+                .create())
+        }
+    }
+
+    let server = CorkedModel::server().unwrap();
+    let handle = spawn(move || {
+        let mut conn = server.connections().next().unwrap().unwrap();
+        while let Ok(msg) = conn.receive() {
+            conn.send(msg).unwrap();
+        }
+    });
+    let mut client = CorkedModel::client().unwrap();
+    // Heartbeats would be sealed after the held frames but written before them
+    client.cork();
+    client.send("held".to_string()).unwrap();
+    sleep(Duration::from_millis(50));
+    client.send("back".to_string()).unwrap();
+    client.uncork().unwrap();
+    assert_eq!(client.receive().unwrap(), "held");
+    assert_eq!(client.receive().unwrap(), "back");
+    // And they go on once uncorked
+    sleep(Duration::from_millis(50));
+    client.send("after".to_string()).unwrap();
+    assert_eq!(client.receive().unwrap(), "after");
+    drop(client);
+    handle.join().unwrap();
+}
+
+fn token_path() -> std::path::PathBuf {
+    std::env::temp_dir().join("easy_ipc_test.token")
+}
+
+define_model!(TokenModel: "token.socket", TokenServer {Echo,}, TokenClient {Ping,},);
+
+struct TokenAuthModel;
+impl IpcModel for TokenAuthModel {
+    type ServerMsg = TokenServer;
+    type ClientMsg = TokenClient;
+
+    fn model() -> Result<ClientServerModel<Self::ClientMsg, Self::ServerMsg>, InitError> {
+        Ok(
+            ClientServerOptions::new(crate::namespace::namespace("token.socket")?)
+                .disable_single_server_check()
+                .handlers(|_model| {})
+                .token_auth(token_path())
+                .create(),
+        )
+    }
+}
+
+#[test]
+fn token_authentication() {
+    clean(&TokenAuthModel::model().unwrap().options().socket_name);
+    let server = TokenAuthModel::server().unwrap();
+    let token = std::fs::read_to_string(token_path()).unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(token_path())
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    std::thread::scope(|s| {
+        let handle = s.spawn(|| {
+            let mut rejected = 0;
+            for conn in server.connections().take(4) {
+                match conn {
+                    Ok(mut conn) => {
+                        while let Ok(TokenClient::Ping) = conn.receive() {
+                            conn.send(TokenServer::Echo).unwrap();
+                        }
+                    }
+                    Err(ConnectionError::Unauthorized) => rejected += 1,
+                    Err(e) => panic!("{e:?}"),
+                }
+            }
+            rejected
+        });
+
+        let mut client = TokenAuthModel::client().unwrap();
+        client.send(TokenClient::Ping).unwrap();
+        assert_eq!(client.receive().unwrap(), TokenServer::Echo);
+        drop(client);
+
+        // Clients without the token never get an answer
+        let mut client = TokenModel::client().unwrap();
+        client.send(TokenClient::Ping).unwrap();
+        assert!(client.receive().is_err());
+        std::fs::write(token_path(), "guessed").unwrap();
+        let mut client = TokenAuthModel::client().unwrap();
+        // The server may have hung up already
+        client.send(TokenClient::Ping).ok();
+        assert!(client.receive().is_err());
+
+        // Rotating gives new clients a new token
+        server.rotate_token().unwrap();
+        let rotated = std::fs::read_to_string(token_path()).unwrap();
+        assert_ne!(rotated, token);
+        let mut client = TokenAuthModel::client().unwrap();
+        client.send(TokenClient::Ping).unwrap();
+        assert_eq!(client.receive().unwrap(), TokenServer::Echo);
+        drop(client);
+
+        assert_eq!(handle.join().unwrap(), 2);
+    });
+}
+
+#[cfg(unix)]
+#[test]
+fn token_auth_before_anything_else() {
+    /// Options of both models, only the server and the clients with the token use the token file
+    fn options() -> Result<ClientServerOptions<String, String>, InitError> {
+        Ok(
+            ClientServerOptions::new(crate::namespace::namespace("auth_timeout.socket")?)
+                .disable_single_server_check()
+                .handlers(|_model| {})
+                .auth_timeout(Duration::from_millis(100)),
+        )
+    }
+
+    struct AuthModel;
+    impl IpcModel for AuthModel {
+        type ServerMsg = String;
+        type ClientMsg = String;
+
+        fn model() -> Result<ClientServerModel<String, String>, InitError> {
+            let token = std::env::temp_dir().join("easy_ipc_auth_timeout.token");
+            Ok(options()?.token_auth(token).create())
+        }
+    }
+    struct NoTokenModel;
+    impl IpcModel for NoTokenModel {
+        type ServerMsg = String;
+        type ClientMsg = String;
+
+        fn model() -> Result<ClientServerModel<String, String>, InitError> {
+            Ok(options()?.create())
+        }
+    }
+
+    clean(&AuthModel::model().unwrap().options().socket_name);
+    let server = AuthModel::server().unwrap();
+    // A client that doesn't send its token is given up on
+    let silent = NoTokenModel::client().unwrap();
+    let result = server.connections().next().unwrap();
+    assert!(
+        matches!(&result, Err(ConnectionError::ReadFailed(_))),
+        "{result:?}"
+    );
+    assert!(result.unwrap_err().to_string().starts_with("read failed, "));
+    drop(silent);
+
+    // Anything before the token is refused, long frames before their payload is read
+    for msg in ["hi".to_string(), "x".repeat(10_000)] {
+        let mut client = NoTokenModel::client().unwrap();
+        client.send(msg).unwrap();
+        let result = server.connections().next().unwrap();
+        assert!(
+            matches!(&result, Err(ConnectionError::Unauthorized)),
+            "{result:?}"
+        );
+    }
+
+    // Pings aren't answered before the token, they are refused like any other frame
+    let mut pinging = NoTokenModel::client().unwrap();
+    let handle = spawn(move || pinging.ping());
+    let result = server.connections().next().unwrap();
+    assert!(
+        matches!(&result, Err(ConnectionError::Unauthorized)),
+        "{result:?}"
+    );
+    assert!(handle.join().unwrap().is_err());
+
+    // Clients with the token are let in
+    let mut client = AuthModel::client().unwrap();
+    client.send("hi".to_string()).unwrap();
+    let mut conn = server.connections().next().unwrap().unwrap();
+    assert_eq!(conn.receive().unwrap(), "hi");
+    assert_eq!(server.metrics().connections_rejected, 4);
+}
+
+/// Collects the spans created anywhere in the process as `name field=value...`
+#[cfg(feature = "tracing")]
+#[derive(Default)]
+struct SpanCollector {
+    spans: std::sync::Mutex<Vec<String>>,
+}
+
+#[cfg(feature = "tracing")]
+struct FieldWriter<'a>(&'a mut String);
+
+#[cfg(feature = "tracing")]
+impl tracing::field::Visit for FieldWriter<'_> {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        use std::fmt::Write;
+        write!(self.0, " {field}={value:?}").unwrap();
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl tracing::Subscriber for SpanCollector {
+    fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+        let mut description = span.metadata().name().to_string();
+        span.record(&mut FieldWriter(&mut description));
+        let mut spans = self.spans.lock().unwrap();
+        spans.push(description);
+        tracing::span::Id::from_u64(spans.len() as u64)
+    }
+
+    fn record(&self, span: &tracing::span::Id, values: &tracing::span::Record<'_>) {
+        let index = usize::try_from(span.into_u64()).unwrap() - 1;
+        values.record(&mut FieldWriter(&mut self.spans.lock().unwrap()[index]));
+    }
+
+    fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+
+    fn event(&self, _event: &tracing::Event<'_>) {}
+
+    fn enter(&self, _span: &tracing::span::Id) {}
+
+    fn exit(&self, _span: &tracing::span::Id) {}
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn tracing_spans() {
+    define_model!(
+        TracingModel: "tracing.socket",
+        TracingServer {
+            Pong,
+        },
+        TracingClient {
+            Ping,
+        },
+    );
+
+    let collector = std::sync::Arc::new(SpanCollector::default());
+    tracing::subscriber::set_global_default(collector.clone()).unwrap();
+    clean(&TracingModel::model().unwrap().options().socket_name);
+    let server = TracingModel::server().unwrap();
+    let handle = spawn(move || {
+        let mut conn = server.connections().next().unwrap().unwrap();
+        assert_eq!(conn.receive().unwrap(), TracingClient::Ping);
+        conn.send(TracingServer::Pong).unwrap();
+    });
+    let mut client = TracingModel::client().unwrap();
+    client.send(TracingClient::Ping).unwrap();
+    assert_eq!(client.receive().unwrap(), TracingServer::Pong);
+    handle.join().unwrap();
+
+    let spans = collector.spans.lock().unwrap();
+    assert!(spans.iter().any(|s| s.starts_with("server socket=")));
+    let connections = spans.iter().filter(|s| s.starts_with("connection")).count();
+    assert_eq!(connections, 2);
+    #[cfg(target_os = "linux")]
+    assert!(
+        spans
+            .iter()
+            .any(|s| s.contains(&format!("peer_pid={}", std::process::id())))
+    );
+    for operation in ["send", "receive"] {
+        let ping = spans
+            .iter()
+            .find(|s| {
+                s.contains(&format!("operation={operation:?}")) && s.contains("TracingClient")
+            })
+            .unwrap();
+        assert!(ping.contains(" bytes="), "{ping}");
+        assert!(ping.contains(" duration="), "{ping}");
+    }
+}
+
+#[test]
+fn server_metrics() {
+    define_model!(
+        MetricsModel: "metrics.socket",
+        MetricsServer {
+            Pong,
+        },
+        MetricsClient {
+            Ping,
+        },
+    );
+
+    clean(&MetricsModel::model().unwrap().options().socket_name);
+    let server = std::sync::Arc::new(MetricsModel::server().unwrap());
+    let server_thread = server.clone();
+    let handle = spawn(move || {
+        let mut conn = server_thread.connections().next().unwrap().unwrap();
+        assert_eq!(conn.receive().unwrap(), MetricsClient::Ping);
+        conn.send(MetricsServer::Pong).unwrap();
+        assert!(matches!(
+            conn.receive(),
+            Err(ConnectionError::DeserilizationFailed(_))
+        ));
+        assert_eq!(server_thread.metrics().connections_active, 1);
+        conn.metrics()
+    });
+
+    let mut client = MetricsModel::client().unwrap();
+    client.send(MetricsClient::Ping).unwrap();
+    assert_eq!(client.receive().unwrap(), MetricsServer::Pong);
+    client.send_encoded(&[0xff; 4]).unwrap();
+    // The connection is closed once the server thread is done with it
+    let connection = handle.join().unwrap();
+
+    let metrics = server.metrics();
+    assert_eq!(metrics.connections_accepted, 1);
+    assert_eq!(metrics.connections_active, 0);
+    assert_eq!(metrics.connections_rejected, 0);
+    assert_eq!(metrics.messages, connection);
+    assert_eq!(connection.messages_received, 2);
+    assert_eq!(connection.messages_sent, 1);
+    assert_eq!(connection.decode_errors, 1);
+    assert!(connection.bytes_received > connection.bytes_sent);
+    assert_eq!(connection.send_latency.count(), 1);
+    assert_eq!(connection.receive_latency.count(), 2);
+    let p100 = connection.receive_latency.quantile(1.0).unwrap();
+    assert!(p100 >= connection.receive_latency.mean().unwrap());
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+enum CaptureClient {
+    Add(u32, u32),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+enum CaptureServer {
+    Sum(u32),
+}
+
+fn capture_path() -> std::path::PathBuf {
+    std::env::temp_dir().join("easy_ipc_test.capture")
+}
+
+struct CaptureModel;
+impl IpcModel for CaptureModel {
+    type ServerMsg = CaptureServer;
+    type ClientMsg = CaptureClient;
+
+    fn model() -> Result<ClientServerModel<Self::ClientMsg, Self::ServerMsg>, InitError> {
+        Ok(
+            ClientServerOptions::new(crate::namespace::namespace("capture.socket")?)
+                .disable_single_server_check()
+                .handlers(|_model| {})
+                .capture(capture_path())
+                .create(),
+        )
+    }
+}
+
+/// Same socket as [`CaptureModel`], without capturing
+struct ReplayModel;
+impl IpcModel for ReplayModel {
+    type ServerMsg = CaptureServer;
+    type ClientMsg = CaptureClient;
+
+    fn model() -> Result<ClientServerModel<Self::ClientMsg, Self::ServerMsg>, InitError> {
+        Ok(
+            ClientServerOptions::new(crate::namespace::namespace("capture.socket")?)
+                .disable_single_server_check()
+                .handlers(|_model| {})
+                .create(),
+        )
+    }
+}
+
+/// Answer additions, off by `error`
+fn serve_sums(mut conn: crate::connection::Connection<CaptureServer, CaptureClient>, error: u32) {
+    while let Ok(CaptureClient::Add(a, b)) = conn.receive() {
+        conn.send(CaptureServer::Sum(a + b + error)).unwrap();
+    }
+}
+
+/// Ask for two additions
+fn ask_sums(client: &mut Client<CaptureClient, CaptureServer>) -> Vec<CaptureServer> {
+    [(1, 2), (3, 4)]
+        .into_iter()
+        .map(|(a, b)| {
+            client.send(CaptureClient::Add(a, b)).unwrap();
+            client.receive().unwrap()
+        })
+        .collect()
+}
+
+#[test]
+fn capture_and_replay() {
+    use crate::capture::{Direction, Recording};
+
+    std::fs::remove_file(capture_path()).ok();
+    clean(&CaptureModel::model().unwrap().options().socket_name);
+    let server = CaptureModel::server().unwrap();
+    let handle = spawn(move || serve_sums(server.connections().next().unwrap().unwrap(), 0));
+    let mut client = ReplayModel::client().unwrap();
+    assert_eq!(
+        ask_sums(&mut client),
+        [CaptureServer::Sum(3), CaptureServer::Sum(7)]
+    );
+    drop(client);
+    handle.join().unwrap();
+
+    let recording = Recording::read(capture_path()).unwrap();
+    let connections = recording.connections();
+    assert_eq!(connections.len(), 1);
+    // Frames of encrypted connections are captured in plain
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(capture_path())
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+    let directions: Vec<_> = recording.frames().iter().map(|f| f.direction).collect();
+    assert_eq!(
+        directions,
+        [
+            Direction::ToServer,
+            Direction::ToClient,
+            Direction::ToServer,
+            Direction::ToClient
+        ]
+    );
+    let message = recording.frames()[3].message::<CaptureServer>();
+    assert_eq!(message.unwrap().unwrap(), CaptureServer::Sum(7));
+
+    // Replaying the client finds the server that answers differently
+    clean(&ReplayModel::model().unwrap().options().socket_name);
+    let server = ReplayModel::server().unwrap();
+    let handle = spawn(move || {
+        for (error, conn) in server.connections().take(2).enumerate() {
+            serve_sums(conn.unwrap(), u32::try_from(error).unwrap());
+        }
+    });
+    let replay = |client| recording.replay_client(connections[0], client).unwrap();
+    assert!(replay(ReplayModel::client().unwrap()).is_empty());
+    let mismatches = replay(ReplayModel::client().unwrap());
+    let indices: Vec<_> = mismatches.iter().map(|m| m.index).collect();
+    assert_eq!(indices, [1, 3]);
+    let actual = mismatches[0].actual.message::<CaptureServer>();
+    assert_eq!(actual.unwrap().unwrap(), CaptureServer::Sum(4));
+    handle.join().unwrap();
+
+    // Replaying the server gives a client the captured answers
+    clean(&ReplayModel::model().unwrap().options().socket_name);
+    let server = ReplayModel::server().unwrap();
+    let handle = spawn(|| ask_sums(&mut ReplayModel::client().unwrap()));
+    let conn = server.connections().next().unwrap().unwrap();
+    assert!(
+        recording
+            .replay_server(connections[0], conn)
+            .unwrap()
+            .is_empty()
+    );
+    assert_eq!(
+        handle.join().unwrap(),
+        [CaptureServer::Sum(3), CaptureServer::Sum(7)]
+    );
+}
+
+#[test]
+fn ping_describes_peer() {
+    define_model!(
+        PingModel: "ping.socket",
+        PingServer {
+            Pong,
+        },
+        PingClient {
+            Ping,
+        },
+    );
+
+    clean(&PingModel::model().unwrap().options().socket_name);
+    let server = PingModel::server().unwrap();
+    let handle = spawn(move || {
+        let mut conn = server.connections().next().unwrap().unwrap();
+        // Pings are answered while waiting for a message
+        assert_eq!(conn.receive().unwrap(), PingClient::Ping);
+        conn.send(PingServer::Pong).unwrap();
+    });
+
+    let mut client = PingModel::client().unwrap();
+    let info = client.ping().unwrap();
+    assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(info.checksum, "None");
+    assert!(!info.encrypted && !info.token_auth);
+    assert_eq!(info.heartbeat, None);
+    assert!(info.round_trip > Duration::ZERO);
+    client.send(PingClient::Ping).unwrap();
+    assert_eq!(client.receive().unwrap(), PingServer::Pong);
+    handle.join().unwrap();
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+enum MemoryMessage {
+    Hello(String),
+}
+
+macro_rules! memory_model {
+    ($model_name:ident : $name:literal) => {
+        struct $model_name;
+        impl IpcModel for $model_name {
+            type ServerMsg = MemoryMessage;
+            type ClientMsg = MemoryMessage;
+
+            fn model() -> Result<ClientServerModel<MemoryMessage, MemoryMessage>, InitError> {
+                Ok(ClientServerOptions::in_memory($name).create())
+            }
+        }
+    };
+}
+
+#[test]
+fn in_memory_transport() {
+    memory_model!(FirstModel: "first");
+    memory_model!(SecondModel: "second");
+
+    // No cleaning, and servers run side by side
+    let first = FirstModel::server().unwrap();
+    let second = SecondModel::server().unwrap();
+    assert!(matches!(
+        FirstModel::server(),
+        Err(InitError::SocketAlreadyExists)
+    ));
+    assert!(!std::path::Path::new("first").exists());
+
+    let echo = |server: Server<MemoryMessage, MemoryMessage>| {
+        spawn(move || {
+            for conn in server.connections().take(2) {
+                let mut conn = conn.unwrap();
+                let MemoryMessage::Hello(name) = conn.receive().unwrap();
+                conn.send(MemoryMessage::Hello(name + " back")).unwrap();
+            }
+        })
+    };
+    let handles = [echo(first), echo(second)];
+    let hello = |mut client: Client<MemoryMessage, MemoryMessage>, name: &str| {
+        client.send(MemoryMessage::Hello(name.to_string())).unwrap();
+        client.receive().unwrap()
+    };
+    for _ in 0..2 {
+        let reply = hello(FirstModel::client().unwrap(), "first");
+        assert_eq!(reply, MemoryMessage::Hello("first back".to_string()));
+        let reply = hello(SecondModel::client().unwrap(), "second");
+        assert_eq!(reply, MemoryMessage::Hello("second back".to_string()));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    // The name is free once the server is gone
+    assert!(matches!(
+        FirstModel::client(),
+        Err(InitError::FailedConnectingToSocket(_))
+    ));
+    drop(FirstModel::server().unwrap());
+}
+
+thread_local! {
+    /// Name, transport and checksum of the model of the running fault case
+    static FAULT_CASE: std::cell::RefCell<(String, Box<dyn Fn() -> FaultyTransport>, Checksum)> =
+        std::cell::RefCell::new((String::new(), Box::new(|| FaultyTransport::script([])), Checksum::None));
+}
+
+struct FaultModel;
+
+impl IpcModel for FaultModel {
+    type ServerMsg = MemoryMessage;
+    type ClientMsg = MemoryMessage;
+
+    fn model() -> Result<ClientServerModel<MemoryMessage, MemoryMessage>, InitError> {
+        FAULT_CASE.with_borrow(|(name, transport, checksum)| {
+            Ok(ClientServerOptions::in_memory(name.clone())
+                .checksum(*checksum)
+                .faults(transport())
+                .create())
+        })
+    }
+}
+
+/// A connected client and server, frames going `direction` pass through `transport`
+fn faulty_pair(
+    name: &str,
+    direction: Direction,
+    checksum: Checksum,
+    transport: impl Fn() -> FaultyTransport + 'static,
+) -> (
+    Client<MemoryMessage, MemoryMessage>,
+    Connection<MemoryMessage, MemoryMessage>,
+) {
+    FAULT_CASE.set((
+        name.to_string(),
+        Box::new(move || transport().direction(direction)),
+        checksum,
+    ));
+    let server = FaultModel::server().unwrap();
+    let client = FaultModel::client().unwrap();
+    let conn = server.connections().next().unwrap().unwrap();
+    (client, conn)
+}
+
+/// Send a message through a single fault, returns what the receiving end got
+fn receive_through(
+    fault: Fault,
+    direction: Direction,
+    checksum: Checksum,
+) -> Result<MemoryMessage, ConnectionError> {
+    let name = format!("fault {fault:?} {direction:?} {checksum:?}");
+    let (mut client, mut conn) = faulty_pair(&name, direction, checksum, move || {
+        FaultyTransport::script([fault])
+    });
+    let message = MemoryMessage::Hello("through faults".to_string());
+    match direction {
+        Direction::ToServer => {
+            client.send(message).unwrap();
+            conn.receive()
+        }
+        Direction::ToClient => {
+            conn.send(message).unwrap();
+            client.receive()
+        }
+    }
+}
+
+#[test]
+fn faulty_transport() {
+    let message = MemoryMessage::Hello("through faults".to_string());
+    for direction in [Direction::ToServer, Direction::ToClient] {
+        let receive = |fault| receive_through(fault, direction, Checksum::None);
+        // Frames that arrive late or in pieces still arrive
+        assert_eq!(receive(Fault::Pass).unwrap(), message);
+        let delay = Fault::Delay(Duration::from_millis(20));
+        assert_eq!(receive(delay).unwrap(), message);
+        assert_eq!(receive(Fault::Split(3)).unwrap(), message);
+
+        // Cut off in the header or the payload
+        for len in [0, 5, 20, usize::MAX] {
+            let result = receive(Fault::Truncate(len));
+            assert!(
+                matches!(result, Err(ConnectionError::UnexepctedEof)),
+                "{result:?}"
+            );
+        }
+        let result = receive(Fault::Disconnect);
+        assert!(
+            matches!(result, Err(ConnectionError::UnexepctedEof)),
+            "{result:?}"
+        );
+        let result = receive(Fault::Corrupt(0));
+        assert!(
+            matches!(result, Err(ConnectionError::HeaderMismatch)),
+            "{result:?}"
+        );
+    }
+
+    // The sender finds out once the connection is cut
+    let (mut client, mut conn) = faulty_pair(
+        "fault disconnect sender",
+        Direction::ToServer,
+        Checksum::None,
+        || FaultyTransport::script([Fault::Disconnect]),
+    );
+    client.send(message.clone()).unwrap();
+    assert!(matches!(
+        conn.receive(),
+        Err(ConnectionError::UnexepctedEof)
+    ));
+    let result = client.send(message.clone());
+    assert!(
+        matches!(result, Err(ConnectionError::WriteFailed(_))),
+        "{result:?}"
+    );
+    assert!(matches!(
+        client.receive(),
+        Err(ConnectionError::UnexepctedEof)
+    ));
+
+    // Every frame is damaged when the probability is 1, none when it is 0
+    let (mut client, mut conn) =
+        faulty_pair("fault random", Direction::ToServer, Checksum::None, || {
+            FaultyTransport::random(7, [(Fault::Disconnect, 0.0), (Fault::Split(1), 1.0)])
+        });
+    for _ in 0..3 {
+        client.send(message.clone()).unwrap();
+        assert_eq!(conn.receive().unwrap(), message);
+    }
+}
+
+#[cfg(feature = "crc32c")]
+#[test]
+fn faulty_transport_checksum() {
+    let model = ClientServerOptions::<MemoryMessage, MemoryMessage>::in_memory("checksum layout")
+        .checksum(Checksum::Crc32c)
+        .create();
+    let header = crate::connection::header_length(model.options());
+    let length = model.options().magic_bytes.len() + 6;
+    // The header checksum catches a damaged length, the payload checksum a damaged payload
+    for offset in [length, header - 1, header, header + 3] {
+        let result = receive_through(
+            Fault::Corrupt(offset),
+            Direction::ToServer,
+            Checksum::Crc32c,
+        );
+        assert!(
+            matches!(result, Err(ConnectionError::ChecksumMismatch)),
+            "{offset}: {result:?}"
+        );
+    }
+}
+
+struct NonblockingModel;
+
+impl IpcModel for NonblockingModel {
+    type ServerMsg = MemoryMessage;
+    type ClientMsg = MemoryMessage;
+
+    fn model() -> Result<ClientServerModel<MemoryMessage, MemoryMessage>, InitError> {
+        Ok(
+            ClientServerOptions::new(crate::namespace::namespace("nonblocking.socket")?)
+                .disable_single_server_check()
+                .handlers(|_model| {})
+                .create(),
+        )
+    }
+}
+
+/// Wait up to `timeout_ms` for `fd` to become readable
+#[cfg(unix)]
+fn readable(fd: &impl std::os::fd::AsRawFd, timeout_ms: i32) -> bool {
+    let mut poll = libc::pollfd {
+        fd: fd.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // SAFETY: A single valid pollfd
+    unsafe { libc::poll(&raw mut poll, 1, timeout_ms) == 1 }
+}
+
+#[cfg(unix)]
+#[test]
+fn nonblocking_receive() {
+    clean(&NonblockingModel::model().unwrap().options().socket_name);
+    let server = NonblockingModel::server().unwrap();
+    assert!(server.try_accept().unwrap().is_none());
+    assert!(!readable(&server, 0));
+
+    let big = MemoryMessage::Hello("x".repeat(1 << 20));
+    let sent = big.clone();
+    let handle = spawn(move || {
+        let mut client = NonblockingModel::client().unwrap();
+        client.send(sent.clone()).unwrap();
+        client
+            .send(MemoryMessage::Hello("small".to_string()))
+            .unwrap();
+        client.send(sent).unwrap();
+        // Wait until the server is done before closing
+        client.receive().unwrap();
+    });
+
+    assert!(readable(&server, 5000));
+    let mut conn = server.try_accept().unwrap().unwrap();
+    assert!(server.try_accept().unwrap().is_none());
+
+    // The big message usually arrives in several pieces, each poll adds to it
+    let first = loop {
+        if let Some(message) = conn.try_receive().unwrap() {
+            break message;
+        }
+        assert!(readable(&conn, 5000));
+    };
+    assert_eq!(first, big);
+
+    // Part of the last message may already be read ahead, blocking reads continue from it
+    assert_eq!(
+        conn.receive().unwrap(),
+        MemoryMessage::Hello("small".to_string())
+    );
+    let last = match conn.try_receive().unwrap() {
+        Some(message) => message,
+        None => conn.receive().unwrap(),
+    };
+    assert_eq!(last, big);
+    assert!(conn.try_receive().unwrap().is_none());
+
+    conn.send(MemoryMessage::Hello("done".to_string())).unwrap();
+    handle.join().unwrap();
+    assert!(readable(&conn, 5000));
+    assert!(matches!(
+        conn.try_receive(),
+        Err(ConnectionError::UnexepctedEof)
+    ));
+
+    // Servers in memory can be polled the same way
+    memory_model!(PolledModel: "polled");
+    let memory = PolledModel::server().unwrap();
+    assert!(memory.try_accept().unwrap().is_none());
+    let _client = PolledModel::client().unwrap();
+    assert!(readable(&memory, 5000));
+    assert!(memory.try_accept().unwrap().is_some());
+    assert!(!readable(&memory, 0));
+}
+
+#[cfg(all(unix, feature = "event-loop"))]
+#[test]
+fn event_loop_server() {
+    use crate::event_loop::{ConnectionId, Connections, EventHandler, EventLoopServer};
+
+    #[derive(Default)]
+    struct Echo {
+        connected: usize,
+        messages: usize,
+        /// Clients that went away and connections closed by us
+        gone: usize,
+        closed: usize,
+    }
+
+    impl EventHandler<MemoryMessage, MemoryMessage> for Echo {
+        fn on_connect(
+            &mut self,
+            _id: ConnectionId,
+            _: &mut Connections<MemoryMessage, MemoryMessage>,
+        ) {
+            self.connected += 1;
+        }
+
+        fn on_message(
+            &mut self,
+            id: ConnectionId,
+            message: MemoryMessage,
+            connections: &mut Connections<MemoryMessage, MemoryMessage>,
+        ) {
+            self.messages += 1;
+            let MemoryMessage::Hello(text) = message;
+            if text == "bye" {
+                connections.close(id);
+            } else {
+                connections
+                    .send(id, MemoryMessage::Hello(text + " back"))
+                    .unwrap();
+            }
+        }
+
+        fn on_disconnect(
+            &mut self,
+            _id: ConnectionId,
+            error: Option<ConnectionError>,
+            _: &mut Connections<MemoryMessage, MemoryMessage>,
+        ) {
+            match error {
+                None => self.closed += 1,
+                Some(ConnectionError::UnexepctedEof) => self.gone += 1,
+                Some(e) => panic!("unexpected error {e:?}"),
+            }
+        }
+    }
+
+    memory_model!(EventLoopModel: "event loop");
+    const CLIENTS: usize = 20;
+    let mut server =
+        EventLoopServer::new(EventLoopModel::server().unwrap(), Echo::default()).unwrap();
+    let clients: Vec<_> = (0..CLIENTS)
+        .map(|i| {
+            spawn(move || {
+                let mut client = EventLoopModel::client().unwrap();
+                for j in 0..3 {
+                    let text = format!("{i} {j}");
+                    client.send(MemoryMessage::Hello(text.clone())).unwrap();
+                    let reply = client.receive().unwrap();
+                    assert_eq!(reply, MemoryMessage::Hello(text + " back"));
+                }
+                // Half of the clients ask to be disconnected, the others just leave
+                if i % 2 == 0 {
+                    client
+                        .send(MemoryMessage::Hello("bye".to_string()))
+                        .unwrap();
+                    assert!(matches!(
+                        client.receive(),
+                        Err(ConnectionError::UnexepctedEof)
+                    ));
+                }
+            })
+        })
+        .collect();
+
+    while server.handler().gone + server.handler().closed < CLIENTS {
+        server.poll(Some(Duration::from_secs(5))).unwrap();
+    }
+    for client in clients {
+        client.join().unwrap();
+    }
+    let handler = server.handler();
+    assert_eq!(handler.connected, CLIENTS);
+    assert_eq!(handler.messages, CLIENTS * 3 + CLIENTS / 2);
+    assert_eq!(handler.closed, CLIENTS / 2);
+    assert_eq!(handler.gone, CLIENTS / 2);
+    assert!(server.connections().is_empty());
+    assert_eq!(
+        server.server().metrics().connections_accepted,
+        CLIENTS as u64
+    );
+}
+
+#[cfg(all(unix, feature = "event-loop"))]
+#[test]
+fn event_loop_token_auth() {
+    use crate::event_loop::{ConnectionId, Connections, EventHandler, EventLoopServer};
+
+    struct Echo;
+
+    impl EventHandler<MemoryMessage, MemoryMessage> for Echo {
+        fn on_message(
+            &mut self,
+            id: ConnectionId,
+            message: MemoryMessage,
+            connections: &mut Connections<MemoryMessage, MemoryMessage>,
+        ) {
+            connections.send(id, message).unwrap();
+        }
+    }
+
+    struct AuthModel;
+    impl IpcModel for AuthModel {
+        type ServerMsg = MemoryMessage;
+        type ClientMsg = MemoryMessage;
+
+        fn model() -> Result<ClientServerModel<MemoryMessage, MemoryMessage>, InitError> {
+            let token = std::env::temp_dir().join("easy_ipc_event_loop.token");
+            Ok(ClientServerOptions::in_memory("event loop auth")
+                .token_auth(token)
+                .auth_timeout(Duration::from_millis(200))
+                .create())
+        }
+    }
+    memory_model!(NoTokenModel: "event loop auth");
+
+    let mut server = EventLoopServer::new(AuthModel::server().unwrap(), Echo).unwrap();
+    // A client that doesn't send a token holds up nobody
+    let mut silent = NoTokenModel::client().unwrap();
+    let hello = MemoryMessage::Hello("hi".to_string());
+    let client = spawn({
+        let hello = hello.clone();
+        move || {
+            let mut client = AuthModel::client().unwrap();
+            client.send(hello).unwrap();
+            client.receive().unwrap()
+        }
+    });
+    while !client.is_finished() {
+        server.poll(Some(Duration::from_millis(50))).unwrap();
+    }
+    assert_eq!(client.join().unwrap(), hello);
+
+    // It is turned away once it sends anything else
+    silent.send(hello).unwrap();
+    while server.server().metrics().connections_rejected == 0 {
+        server.poll(Some(Duration::from_secs(5))).unwrap();
+    }
+    assert!(silent.receive().is_err());
+    assert_eq!(server.server().metrics().connections_accepted, 1);
+
+    // Clients that never send anything are given up on, even when polling without a timeout
+    let silent = NoTokenModel::client().unwrap();
+    while server.server().metrics().connections_rejected < 2 {
+        server.poll(None).unwrap();
+    }
+    drop(silent);
+}
+
+#[test]
+fn buffered_sender() {
+    use crate::sender::Overflow;
+
+    memory_model!(SenderModel: "buffered sender");
+    let server = SenderModel::server().unwrap();
+    let client = SenderModel::client().unwrap();
+    let mut conn = server.connections().next().unwrap().unwrap();
+    let hello = |text: &str| MemoryMessage::Hello(text.to_string());
+    // Too big for the socket, the writer is stuck on it until we read
+    let big = MemoryMessage::Hello("x".repeat(1 << 22));
+    let stuck = |sender: &crate::sender::Sender<MemoryMessage>| {
+        sender.send(big.clone()).unwrap();
+        while sender.queued() > 0 {
+            sleep(Duration::from_millis(1));
+        }
+    };
+
+    let sender = client.buffered_sender(2, Overflow::Error).unwrap();
+    stuck(&sender);
+    sender.send(hello("a")).unwrap();
+    sender.send(hello("b")).unwrap();
+    assert!(matches!(
+        sender.send(hello("c")),
+        Err(ConnectionError::QueueFull)
+    ));
+    assert_eq!((sender.queued(), sender.capacity()), (2, 2));
+    assert_eq!(conn.receive().unwrap(), big);
+    assert_eq!(conn.receive().unwrap(), hello("a"));
+    assert_eq!(conn.receive().unwrap(), hello("b"));
+    sender.flush().unwrap();
+    drop(sender);
+
+    let sender = client.buffered_sender(2, Overflow::DropOldest).unwrap();
+    stuck(&sender);
+    for text in ["a", "b", "c"] {
+        sender.send(hello(text)).unwrap();
+    }
+    assert_eq!(sender.dropped(), 1);
+    assert_eq!(conn.receive().unwrap(), big);
+    assert_eq!(conn.receive().unwrap(), hello("b"));
+    assert_eq!(conn.receive().unwrap(), hello("c"));
+    sender.flush().unwrap();
+    drop(sender);
+
+    let sender = client.buffered_sender(1, Overflow::DropNewest).unwrap();
+    stuck(&sender);
+    sender.send(hello("a")).unwrap();
+    sender.send(hello("b")).unwrap();
+    assert_eq!(sender.dropped(), 1);
+    assert_eq!(conn.receive().unwrap(), big);
+    assert_eq!(conn.receive().unwrap(), hello("a"));
+    sender.flush().unwrap();
+    drop(sender);
+
+    // Blocking waits for the reader, nothing is lost
+    let sender = client.buffered_sender(1, Overflow::Block).unwrap();
+    let reader = spawn(move || {
+        for i in 0..20 {
+            assert_eq!(conn.receive().unwrap(), hello(&i.to_string()));
+        }
+        conn
+    });
+    for i in 0..20 {
+        sender.send(hello(&i.to_string())).unwrap();
+    }
+    sender.flush().unwrap();
+    assert_eq!((sender.queued(), sender.dropped()), (0, 0));
+
+    // Writing fails once the other end is gone
+    drop(reader.join().unwrap());
+    let result = sender.send(big.clone()).and_then(|()| sender.flush());
+    assert!(
+        matches!(result, Err(ConnectionError::WriteFailed(_))),
+        "{result:?}"
+    );
+    assert!(matches!(
+        sender.send(hello("late")),
+        Err(ConnectionError::WriteFailed(_))
+    ));
+}
+
+#[test]
+fn batched_and_corked_sends() {
+    memory_model!(BatchModel: "batch");
+    let server = BatchModel::server().unwrap();
+    let mut client = BatchModel::client().unwrap();
+    let mut conn = server.connections().next().unwrap().unwrap();
+    let hello = |i: usize| MemoryMessage::Hello(i.to_string());
+
+    let batch: Vec<_> = (0..100).map(hello).collect();
+    client.send_batch(&batch).unwrap();
+    client.send_batch(&[]).unwrap();
+    for message in &batch {
+        assert_eq!(&conn.receive().unwrap(), message);
+    }
+
+    // Nothing arrives until uncorked, batches included
+    client.cork();
+    client.send(hello(0)).unwrap();
+    client.send_batch(&batch[1..3]).unwrap();
+    client.send(hello(3)).unwrap();
+    sleep(Duration::from_millis(20));
+    assert!(conn.try_receive().unwrap().is_none());
+    client.uncork().unwrap();
+    for i in 0..4 {
+        assert_eq!(conn.receive().unwrap(), hello(i));
+    }
+    client.send(hello(4)).unwrap();
+    assert_eq!(conn.receive().unwrap(), hello(4));
+    assert_eq!(conn.metrics().messages_received, 105);
+
+    // Held back messages only count as sent once they are written
+    conn.cork();
+    conn.send_batch(&batch[..2]).unwrap();
+    assert_eq!(conn.metrics().messages_sent, 0);
+    conn.uncork().unwrap();
+    assert_eq!(conn.metrics().messages_sent, 2);
+    assert_eq!(client.receive().unwrap(), hello(0));
+    assert_eq!(client.receive().unwrap(), hello(1));
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+struct Document {
+    title: String,
+    body: String,
+    pages: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct DocumentView<'a> {
+    title: &'a str,
+    body: &'a str,
+    pages: u32,
+}
+
+struct DocumentModel;
+
+impl IpcModel for DocumentModel {
+    type ServerMsg = Document;
+    type ClientMsg = Document;
+
+    fn model() -> Result<ClientServerModel<Document, Document>, InitError> {
+        Ok(ClientServerOptions::in_memory("documents").create())
+    }
+}
+
+#[test]
+fn borrowed_receive() {
+    let server = DocumentModel::server().unwrap();
+    let mut client = DocumentModel::client().unwrap();
+    let mut conn = server.connections().next().unwrap().unwrap();
+    let document = Document {
+        title: "report".to_string(),
+        body: "lorem ipsum ".repeat(500),
+        pages: 7,
+    };
+
+    client.send(document.clone()).unwrap();
+    let received = conn.receive_borrowed().unwrap();
+    let view: DocumentView = received.message().unwrap();
+    assert_eq!((view.title, view.pages), ("report", 7));
+    assert_eq!(view.body, document.body);
+    // The strings point into the receive buffer instead of being copied
+    assert!(
+        received
+            .bytes()
+            .as_ptr_range()
+            .contains(&view.body.as_ptr())
+    );
+    assert!(received.context().deadline().is_none());
+    assert_eq!(received.into_owned().unwrap(), document);
+
+    // Deadlines are kept, borrowed messages arrive in order with owned ones
+    let deadline = std::time::SystemTime::now() + Duration::from_secs(60);
+    client
+        .send_with_deadline(document.clone(), deadline)
+        .unwrap();
+    client.send(document.clone()).unwrap();
+    let received = conn.receive_borrowed().unwrap();
+    assert!(received.context().deadline().is_some());
+    // The message starts after the deadline
+    let buffer = received.bytes().as_ptr().wrapping_sub(8);
+    assert_eq!(received.message::<DocumentView>().unwrap().title, "report");
+    assert_eq!(conn.receive().unwrap(), document);
+
+    // Borrowing needs the same layout, a mismatch fails like a wrong message type
+    client.send(document).unwrap();
+    let received = conn.receive_borrowed().unwrap();
+    // The buffer is reused once it is large enough
+    assert_eq!(received.bytes().as_ptr(), buffer);
+    assert!(matches!(
+        received.message::<(&str, u32)>(),
+        Err(ConnectionError::DeserilizationFailed(_))
+    ));
+    assert_eq!(conn.metrics().decode_errors, 1);
+}
+
+#[cfg(feature = "rkyv")]
+#[derive(rkyv::Archive, rkyv::Serialize)]
+struct Samples {
+    sensor: String,
+    values: Vec<u64>,
+}
+
+#[cfg(feature = "rkyv")]
+struct ArchiveModel;
+
+#[cfg(feature = "rkyv")]
+impl IpcModel for ArchiveModel {
+    type ServerMsg = Document;
+    type ClientMsg = Document;
+
+    fn model() -> Result<ClientServerModel<Document, Document>, InitError> {
+        Ok(ClientServerOptions::in_memory("archives").create())
+    }
+}
+
+#[cfg(feature = "rkyv")]
+#[test]
+fn archived_messages() {
+    let server = ArchiveModel::server().unwrap();
+    let mut client = ArchiveModel::client().unwrap();
+    let mut conn = server.connections().next().unwrap().unwrap();
+    let samples = Samples {
+        sensor: "thermometer".to_string(),
+        values: (0..1000).collect(),
+    };
+
+    client.send_archived(&samples).unwrap();
+    let received = conn.receive_borrowed().unwrap();
+    let archived = received.archived::<Samples>().unwrap();
+    assert_eq!(archived.sensor.as_str(), "thermometer");
+    assert!(archived.values.iter().map(|v| v.to_native()).eq(0..1000));
+    // Read in place unless the allocator handed out a buffer that isn't aligned enough
+    if received.bytes().as_ptr().align_offset(16) == 0 {
+        let buffer = received.bytes().as_ptr_range();
+        assert!(buffer.contains(&archived.values.as_ptr().cast()));
+    }
+    assert!(matches!(
+        received.message::<Document>(),
+        Err(ConnectionError::UnexpectedFrame)
+    ));
+
+    // Archives and bitcode messages don't mix
+    client.send_archived(&samples).unwrap();
+    assert!(matches!(
+        conn.receive(),
+        Err(ConnectionError::UnexpectedFrame)
+    ));
+    client
+        .send(Document {
+            title: "report".to_string(),
+            body: String::new(),
+            pages: 1,
+        })
+        .unwrap();
+    let received = conn.receive_borrowed().unwrap();
+    assert!(matches!(
+        received.archived::<Samples>(),
+        Err(ConnectionError::UnexpectedFrame)
+    ));
+
+    // Archives are checked before they are read
+    client.send_archived(&7_u8).unwrap();
+    let received = conn.receive_borrowed().unwrap();
+    assert!(matches!(
+        received.archived::<Samples>(),
+        Err(ConnectionError::ArchiveFailed(_))
+    ));
+    assert_eq!(conn.metrics().decode_errors, 1);
+}
+
+struct BrokerModel;
+
+impl IpcModel for BrokerModel {
+    type ServerMsg = BrokerEvent<String>;
+    type ClientMsg = BrokerRequest<String>;
+
+    fn model() -> Result<ClientServerModel<Self::ClientMsg, Self::ServerMsg>, InitError> {
+        Ok(ClientServerOptions::in_memory("broker").create())
+    }
+}
+
+#[test]
+fn broker_routes_between_peers() {
+    let broker = Broker::new(BrokerModel::server().unwrap())
+        .offline_limit(2)
+        .offline_peers(1);
+    let broker = std::sync::Arc::new(broker);
+    let running = broker.clone();
+    spawn(move || running.run());
+    let register = |name: &str| BrokerClient::register(BrokerModel::client().unwrap(), name);
+    let message = |from: &str, message: &str| BrokerEvent::Message {
+        from: from.to_string(),
+        message: message.to_string(),
+    };
+
+    let mut alice = register("alice").unwrap();
+    assert!(alice.peers().is_empty());
+
+    // Kept for bob until he registers, up to the limit
+    for text in ["one", "two", "three"] {
+        alice.send("bob", text.to_string()).unwrap();
+    }
+    assert_eq!(
+        alice.receive().unwrap(),
+        BrokerEvent::Undeliverable {
+            to: "bob".to_string()
+        }
+    );
+    let mut bob = register("bob").unwrap();
+    assert_eq!(bob.peers(), ["alice"]);
+    assert_eq!(bob.receive().unwrap(), message("alice", "one"));
+    assert_eq!(bob.receive().unwrap(), message("alice", "two"));
+    assert_eq!(
+        alice.receive().unwrap(),
+        BrokerEvent::Joined("bob".to_string())
+    );
+    assert_eq!(alice.peers(), ["bob"]);
+    assert!(matches!(
+        register("bob"),
+        Err(ConnectionError::NameTaken(name)) if name == "bob"
+    ));
+
+    bob.send("alice", "hi".to_string()).unwrap();
+    assert_eq!(alice.receive().unwrap(), message("bob", "hi"));
+    assert_eq!(broker.peers(), ["alice", "bob"]);
+
+    drop(bob);
+    assert_eq!(
+        alice.receive().unwrap(),
+        BrokerEvent::Left("bob".to_string())
+    );
+    assert!(alice.peers().is_empty());
+    assert_eq!(broker.peers(), ["alice"]);
+
+    // Messages are kept for as many offline peers as allowed
+    alice.send("bob", "back soon?".to_string()).unwrap();
+    alice.send("carol", "hello".to_string()).unwrap();
+    assert_eq!(
+        alice.receive().unwrap(),
+        BrokerEvent::Undeliverable {
+            to: "carol".to_string()
+        }
+    );
+    let mut bob = register("bob").unwrap();
+    assert_eq!(bob.receive().unwrap(), message("alice", "back soon?"));
+}
+
+struct PipeModel;
+
+impl IpcModel for PipeModel {
+    type ServerMsg = MemoryMessage;
+    type ClientMsg = MemoryMessage;
+
+    fn model() -> Result<ClientServerModel<MemoryMessage, MemoryMessage>, InitError> {
+        Ok(ClientServerOptions::new("pipe.sock")
+            .magic_bytes(*b"pipe")
+            .create())
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn pipe_connections() {
+    use std::process::{Command, Stdio};
+    let hello = |name: &str| MemoryMessage::Hello(name.to_string());
+
+    let (down_reader, down_writer) = std::io::pipe().unwrap();
+    let (up_reader, up_writer) = std::io::pipe().unwrap();
+    let handle = spawn(move || {
+        let mut conn =
+            Connection::<MemoryMessage, MemoryMessage>::from_rw(down_reader, up_writer).unwrap();
+        let MemoryMessage::Hello(name) = conn.receive().unwrap();
+        conn.send(MemoryMessage::Hello(name + " back")).unwrap();
+        // The pipe closes once the other end is dropped
+        assert!(matches!(
+            conn.receive(),
+            Err(ConnectionError::UnexepctedEof)
+        ));
+    });
+    let mut conn =
+        Connection::<MemoryMessage, MemoryMessage>::from_rw(up_reader, down_writer).unwrap();
+    conn.send(hello("pipe")).unwrap();
+    assert_eq!(conn.receive().unwrap(), hello("pipe back"));
+    drop(conn);
+    handle.join().unwrap();
+
+    // `cat` sends every frame back as it is
+    let mut client = PipeModel::spawn_child(Command::new("cat")).unwrap();
+    assert!(!std::path::Path::new("pipe.sock").exists());
+    for name in ["one", "two"] {
+        client.send(hello(name)).unwrap();
+        assert_eq!(client.receive().unwrap(), hello(name));
+    }
+
+    let child = Command::new("cat")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut conn = Connection::<MemoryMessage, MemoryMessage>::from_child(child).unwrap();
+    conn.send(hello("child")).unwrap();
+    assert_eq!(conn.receive().unwrap(), hello("child"));
+
+    let child = Command::new("cat").stdin(Stdio::piped()).spawn().unwrap();
+    let error = Connection::<MemoryMessage, MemoryMessage>::from_child(child).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+}
@@ -0,0 +1,73 @@
+use std::{
+    fmt::Write as _,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::Path,
+};
+
+/// Random bytes in a token, written to the file as hex
+const TOKEN_LEN: usize = 32;
+
+/// Longest payload a client may send before it presented its token, tokens and handshake
+/// messages are far shorter. Keeps clients that aren't authorized from making the server allocate.
+pub const MAX_FRAME_LEN: usize = 1024;
+
+/// Generate a new token and write it to `path`, replacing the previous one.
+///
+/// The token is written to a temporary file that is then renamed over `path`, so clients never
+/// read a partially written token. On unix only the owning user can read the file.
+///
+/// The temporary file has a random name and must not exist yet, so that in a shared directory
+/// another user can't have it written to a file or a link of their own.
+pub fn generate(path: &Path) -> Result<Vec<u8>, io::Error> {
+    let token = random_hex(TOKEN_LEN)?;
+
+    let mut tmp_name = path.as_os_str().to_owned();
+    tmp_name.push(format!(".{}.tmp", random_hex(8)?));
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&tmp_name)?;
+    let written = file
+        .write_all(token.as_bytes())
+        .and_then(|()| file.sync_all())
+        .and_then(|()| fs::rename(&tmp_name, path));
+    if written.is_err() {
+        fs::remove_file(&tmp_name).ok();
+    }
+    written?;
+    Ok(token.into_bytes())
+}
+
+/// `len` random bytes written as hex
+fn random_hex(len: usize) -> Result<String, io::Error> {
+    let mut bytes = vec![0; len];
+    getrandom::getrandom(&mut bytes)?;
+    Ok(bytes.iter().fold(String::new(), |mut hex, byte| {
+        // Writing to a string can't fail
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    }))
+}
+
+/// Read the token the server wrote to `path`.
+///
+/// Reads no more than a token and a line ending, whatever the size of the file.
+pub fn read(path: &Path) -> Result<Vec<u8>, io::Error> {
+    let mut token = Vec::new();
+    File::open(path)?
+        .take(2 * TOKEN_LEN as u64 + 2)
+        .read_to_end(&mut token)?;
+    Ok(token.trim_ascii().to_vec())
+}
+
+/// Compare tokens in constant time, so the time it takes doesn't tell how much of it was right
+pub fn matches(expected: &[u8], given: &[u8]) -> bool {
+    expected.len() == given.len()
+        && expected
+            .iter()
+            .zip(given)
+            .fold(0, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
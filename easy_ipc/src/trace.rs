@@ -0,0 +1,172 @@
+#[cfg(not(feature = "tracing"))]
+pub use disabled::*;
+#[cfg(feature = "tracing")]
+pub use enabled::*;
+
+#[cfg(feature = "tracing")]
+mod enabled {
+    use {
+        crate::{connection::Side, error::ConnectionError},
+        interprocess::local_socket::Stream,
+        std::{fmt::Debug, path::Path, time::Instant},
+        tracing::field,
+    };
+
+    pub use tracing::Span;
+
+    /// Span of a server, bound to `socket`
+    pub fn server(socket: &Path) -> Span {
+        tracing::info_span!("server", socket = %socket.display())
+    }
+
+    /// Span of a connection, a child of the current span
+    pub fn connection(side: Side, stream: &Stream) -> Span {
+        let span = tracing::info_span!("connection", side = ?side, peer_pid = peer_pid(stream));
+        tracing::debug!(parent: &span, "connected");
+        span
+    }
+
+    /// Run `f` with `span` as the current span
+    pub fn in_scope<T>(span: &Span, f: impl FnOnce() -> T) -> T {
+        span.in_scope(f)
+    }
+
+    /// Something happened that is worth knowing about when things go wrong
+    pub fn info(span: &Span, message: &str) {
+        tracing::info!(parent: span, "{message}");
+    }
+
+    /// Something went wrong
+    pub fn error(span: &Span, message: &str, error: &dyn Debug) {
+        tracing::error!(parent: span, error = ?error, "{message}");
+    }
+
+    /// Something went wrong outside of any server or connection
+    pub fn warn(message: &str) {
+        tracing::warn!("{message}");
+    }
+
+    /// A single send or receive on a connection
+    pub struct Operation {
+        span: Span,
+        start: Instant,
+    }
+
+    impl Operation {
+        /// Start sending or receiving a message of type `message_type` on `connection`
+        pub fn start(connection: &Span, name: &'static str, message_type: &str) -> Self {
+            let span = tracing::debug_span!(
+                parent: connection,
+                "message",
+                operation = name,
+                message_type,
+                bytes = field::Empty,
+                duration = field::Empty,
+            );
+            Self {
+                span,
+                start: Instant::now(),
+            }
+        }
+
+        /// The size of the message once known
+        pub fn bytes(&self, bytes: usize) {
+            self.span.record("bytes", bytes);
+        }
+
+        /// Record how it went, hands the result back
+        pub fn finish<T>(self, result: Result<T, ConnectionError>) -> Result<T, ConnectionError> {
+            self.span
+                .record("duration", field::debug(self.start.elapsed()));
+            match &result {
+                Ok(_) => tracing::trace!(parent: &self.span, "done"),
+                // The usual way for a connection to end
+                Err(ConnectionError::UnexepctedEof) => {
+                    tracing::debug!(parent: &self.span, "connection closed by the other end");
+                }
+                Err(e) => tracing::warn!(parent: &self.span, error = ?e, "failed"),
+            }
+            result
+        }
+    }
+
+    /// Process id of the other end, only known on linux
+    #[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+    fn peer_pid(stream: &Stream) -> Option<u32> {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::fd::{AsFd, AsRawFd};
+
+            let Stream::UdSocket(socket) = stream;
+            let mut credentials = libc::ucred {
+                pid: 0,
+                uid: 0,
+                gid: 0,
+            };
+            let mut len = libc::socklen_t::try_from(size_of::<libc::ucred>()).ok()?;
+            // SAFETY: The socket is open for as long as we borrow it and the buffer is as large
+            // as we say.
+            let result = unsafe {
+                libc::getsockopt(
+                    socket.as_fd().as_raw_fd(),
+                    libc::SOL_SOCKET,
+                    libc::SO_PEERCRED,
+                    (&raw mut credentials).cast(),
+                    &raw mut len,
+                )
+            };
+            if result == 0 {
+                return u32::try_from(credentials.pid).ok();
+            }
+        }
+        None
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+mod disabled {
+    use {
+        crate::{connection::Side, error::ConnectionError},
+        interprocess::local_socket::Stream,
+        std::{fmt::Debug, path::Path},
+    };
+
+    #[derive(Debug, Clone)]
+    pub struct Span;
+
+    pub const fn server(_socket: &Path) -> Span {
+        Span
+    }
+
+    pub const fn connection(_side: Side, _stream: &Stream) -> Span {
+        Span
+    }
+
+    pub fn in_scope<T>(_span: &Span, f: impl FnOnce() -> T) -> T {
+        f()
+    }
+
+    pub const fn info(_span: &Span, _message: &str) {}
+
+    pub fn error(_span: &Span, _message: &str, _error: &dyn Debug) {}
+
+    pub fn warn(message: &str) {
+        eprintln!("{message}");
+    }
+
+    pub struct Operation;
+
+    // Same signatures as with tracing enabled, so callers don't need to care
+    #[allow(clippy::unused_self, clippy::missing_const_for_fn)]
+    impl Operation {
+        pub const fn start(_connection: &Span, _name: &'static str, _type: &str) -> Self {
+            Self
+        }
+
+        pub const fn bytes(&self, _bytes: usize) {}
+
+        pub fn finish<T>(self, result: Result<T, ConnectionError>) -> Result<T, ConnectionError> {
+            result
+        }
+    }
+}
@@ -0,0 +1,296 @@
+use std::{
+    path::PathBuf,
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use easy_ipc::{
+    capture::{CapturedFrame, Direction, Recording},
+    checksum::Checksum,
+    connection::PeerInfo,
+    error::ConnectionError,
+    namespace::namespace,
+    prelude::*,
+};
+
+/// Inspect easy_ipc sockets and captured traffic
+#[derive(Parser)]
+#[command(name = "easy-ipc")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List sockets that could belong to easy_ipc servers
+    List {
+        /// Ping every socket and only show the ones that answer
+        #[arg(long)]
+        probe: bool,
+        #[command(flatten)]
+        connect: ConnectArgs,
+    },
+    /// Measure the round trip time to a server
+    Ping {
+        #[command(flatten)]
+        target: TargetArgs,
+        /// Number of pings to send
+        #[arg(short, long, default_value_t = 4)]
+        count: u32,
+    },
+    /// Show the version and handshake settings of a server
+    Info {
+        #[command(flatten)]
+        target: TargetArgs,
+    },
+    /// Print the frames of a file written by `ClientServerOptions::capture`
+    Tail {
+        /// The capture file
+        path: PathBuf,
+        /// Keep printing frames as they are captured
+        #[arg(short, long)]
+        follow: bool,
+        /// Only print frames of this connection, in hex as printed
+        #[arg(short, long, value_parser = parse_hex)]
+        connection: Option<u64>,
+    },
+}
+
+/// The server to talk to
+#[derive(Args)]
+struct TargetArgs {
+    /// Socket name as given to `easy_ipc::namespace::namespace`, or an absolute path
+    socket: String,
+    #[command(flatten)]
+    connect: ConnectArgs,
+}
+
+/// How to talk to a server, has to match the options of its model
+#[derive(Args, Clone)]
+struct ConnectArgs {
+    /// Magic bytes of the model
+    #[arg(long, default_value = "4242")]
+    magic: String,
+    /// Schema hash in hex, for models using `ClientServerOptions::schema_fingerprint`
+    #[arg(long, value_parser = parse_hex)]
+    schema_hash: Option<u64>,
+    /// Checksum of the model
+    #[arg(long, value_enum, default_value_t = ChecksumArg::None)]
+    checksum: ChecksumArg,
+    /// Token file of a model using `ClientServerOptions::token_auth`
+    #[arg(long)]
+    token_file: Option<PathBuf>,
+    /// Seconds to wait for the server to answer
+    #[arg(long, default_value_t = 2.0)]
+    timeout: f64,
+}
+
+#[derive(ValueEnum, Clone, Copy)]
+enum ChecksumArg {
+    None,
+    Crc32c,
+    Xxhash,
+}
+
+impl From<ChecksumArg> for Checksum {
+    fn from(value: ChecksumArg) -> Self {
+        match value {
+            ChecksumArg::None => Checksum::None,
+            ChecksumArg::Crc32c => Checksum::Crc32c,
+            ChecksumArg::Xxhash => Checksum::XxHash,
+        }
+    }
+}
+
+/// A model that can talk to any server at `socket`, as long as it only pings it
+fn probe(socket: PathBuf, args: &ConnectArgs) -> ClientServerModel<(), ()> {
+    let mut magic = args.magic.clone().into_bytes();
+    if let Some(hash) = args.schema_hash {
+        magic.extend_from_slice(&hash.to_le_bytes());
+    }
+    let mut options = ClientServerOptions::new(socket)
+        .magic_bytes(magic)
+        .checksum(args.checksum.into())
+        .disable_single_server_check()
+        .handlers(|_model| {});
+    if let Some(token_file) = &args.token_file {
+        options = options.token_auth(token_file);
+    }
+    options.create()
+}
+
+/// Connect to `socket` and ping it `count` times, giving up after the timeout
+fn ping(socket: PathBuf, args: &ConnectArgs, count: u32) -> Result<Vec<PeerInfo>, String> {
+    let mut client = probe(socket, args)
+        .client()
+        .map_err(|e| format!("couldn't connect: {e:?}"))?;
+    client
+        .set_read_timeout(Some(Duration::from_secs_f64(args.timeout)))
+        .map_err(|e| format!("couldn't set the timeout: {e}"))?;
+    (0..count)
+        .map(|i| {
+            if i > 0 {
+                thread::sleep(Duration::from_secs(1));
+            }
+            client.ping().map_err(|e| match e {
+                ConnectionError::ReadFailed(e)
+                    if matches!(
+                        e.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    "no answer, the server may not be reading".to_string()
+                }
+                e => format!("ping failed: {e:?}"),
+            })
+        })
+        .collect()
+}
+
+/// Resolve a socket argument like the models of the server would
+fn socket_path(socket: &str) -> PathBuf {
+    let path = PathBuf::from(socket);
+    if path.is_absolute() {
+        return path;
+    }
+    namespace(socket).unwrap_or(path)
+}
+
+/// Sockets in the directories [`easy_ipc::namespace::filesystem_path`] uses, and on linux the
+/// listening sockets in the abstract namespace
+fn list_sockets() -> Vec<String> {
+    let mut sockets = Vec::new();
+    let directories = dirs::data_dir()
+        .and_then(|dir| dir.read_dir().ok())
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| entry.path().read_dir().ok());
+    for entry in directories.flatten().flatten() {
+        let path = entry.path();
+        let is_socket = entry.file_type().is_ok_and(|file_type| {
+            #[cfg(unix)]
+            return std::os::unix::fs::FileTypeExt::is_socket(&file_type);
+            #[cfg(not(unix))]
+            return !file_type.is_dir();
+        });
+        if is_socket && path.extension().is_some_and(|ext| ext == "sock") {
+            sockets.push(path.display().to_string());
+        }
+    }
+    #[cfg(target_os = "linux")]
+    if let Ok(table) = std::fs::read_to_string("/proc/net/unix") {
+        // Num RefCount Protocol Flags Type St Inode Path, listening sockets have the accept flag
+        for line in table.lines().skip(1) {
+            let columns: Vec<_> = line.split_whitespace().collect();
+            if let [_, _, _, "00010000", _, _, _, path] = columns[..]
+                && let Some(name) = path.strip_prefix('@')
+                && !sockets.iter().any(|socket| socket == name)
+            {
+                sockets.push(name.to_string());
+            }
+        }
+    }
+    sockets.sort();
+    sockets
+}
+
+fn print_frame(frame: &CapturedFrame) {
+    let time = frame
+        .time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let direction = match frame.direction {
+        Direction::ToServer => "client -> server",
+        Direction::ToClient => "server -> client",
+    };
+    let data = frame.data();
+    let preview: Vec<_> = data.iter().take(16).map(|b| format!("{b:02x}")).collect();
+    println!(
+        "{}.{:06} {:016x} {direction} {:<16} ch {:<3} {:>6} B  {}{}",
+        time.as_secs(),
+        time.subsec_micros(),
+        frame.connection,
+        frame.kind(),
+        frame.channel(),
+        data.len(),
+        preview.join(" "),
+        if data.len() > 16 { " ..." } else { "" },
+    );
+}
+
+fn tail(path: &PathBuf, follow: bool, connection: Option<u64>) -> Result<(), String> {
+    let mut printed = 0;
+    loop {
+        match Recording::read(path) {
+            Ok(recording) => {
+                for frame in &recording.frames()[printed..] {
+                    if connection.is_none_or(|connection| frame.connection == connection) {
+                        print_frame(frame);
+                    }
+                }
+                printed = recording.frames().len();
+            }
+            // The last record may still be being written
+            Err(e) if follow && e.kind() == std::io::ErrorKind::InvalidData && printed > 0 => (),
+            Err(e) => return Err(format!("couldn't read {}: {e}", path.display())),
+        }
+        if !follow {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+}
+
+fn parse_hex(value: &str) -> Result<u64, std::num::ParseIntError> {
+    u64::from_str_radix(value.trim_start_matches("0x"), 16)
+}
+
+fn run(command: Command) -> Result<(), String> {
+    match command {
+        Command::List { probe, connect } => {
+            for socket in list_sockets() {
+                if !probe {
+                    println!("{socket}");
+                    continue;
+                }
+                if let Ok(infos) = ping(socket_path(&socket), &connect, 1) {
+                    println!("{socket}  easy_ipc {}", infos[0].version);
+                }
+            }
+        }
+        Command::Ping { target, count } => {
+            for info in ping(socket_path(&target.socket), &target.connect, count)? {
+                println!("{}: time={:?}", target.socket, info.round_trip);
+            }
+        }
+        Command::Info { target } => {
+            let info = ping(socket_path(&target.socket), &target.connect, 1)?.remove(0);
+            println!("version:     {}", info.version);
+            println!("compression: {}", info.compression);
+            println!("checksum:    {}", info.checksum);
+            println!("encrypted:   {}", info.encrypted);
+            println!("token auth:  {}", info.token_auth);
+            match info.heartbeat {
+                Some(interval) => println!("heartbeat:   every {interval:?}"),
+                None => println!("heartbeat:   off"),
+            }
+            println!("round trip:  {:?}", info.round_trip);
+        }
+        Command::Tail {
+            path,
+            follow,
+            connection,
+        } => tail(&path, follow, connection)?,
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run(Cli::parse().command) {
+        eprintln!("easy-ipc: {e}");
+        std::process::exit(1);
+    }
+}
@@ -2,7 +2,7 @@ use std::fmt::Display;
 
 use proc_macro::TokenStream;
 use quote::quote;
-use syn::{DeriveInput, Ident, Type, parse::Parse};
+use syn::{DeriveInput, Ident, Type, parse::Parse, punctuated::Punctuated};
 
 /// Crate name, used to namespace attributes and for diagnostic messages
 const CRATE_NAME: &str = "easy_ipc";
@@ -45,28 +45,174 @@ pub fn ipc_model_derive(input: TokenStream) -> TokenStream {
     TokenStream::from(model_impl)
 }
 
+/// Derive the easy_ipc::schema::Schema trait
+#[proc_macro_derive(Schema)]
+pub fn schema_derive(input: TokenStream) -> TokenStream {
+    let mut input = syn::parse_macro_input!(input as DeriveInput);
+    let name = &input.ident;
+
+    // Every type parameter needs to be describable too
+    for param in input.generics.type_params_mut() {
+        param
+            .bounds
+            .push(syn::parse_quote!(::easy_ipc::schema::Schema));
+    }
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let shape = match &input.data {
+        syn::Data::Struct(data) => {
+            let fields = fields_shape(&data.fields);
+            quote! {
+                ::easy_ipc::schema::Shape::Struct {
+                    name: ::std::stringify!(#name),
+                    fields: #fields,
+                }
+            }
+        }
+        syn::Data::Enum(data) => {
+            let variants = data.variants.iter().map(|variant| {
+                let variant_name = &variant.ident;
+                let fields = fields_shape(&variant.fields);
+                quote! {
+                    ::easy_ipc::schema::Variant {
+                        name: ::std::stringify!(#variant_name),
+                        fields: #fields,
+                    }
+                }
+            });
+            quote! {
+                ::easy_ipc::schema::Shape::Enum {
+                    name: ::std::stringify!(#name),
+                    variants: ::std::vec![#(#variants),*],
+                }
+            }
+        }
+        syn::Data::Union(data) => {
+            return syn::Error::new_spanned(
+                data.union_token,
+                "`#[derive(Schema)]` is not supported for unions",
+            )
+            .into_compile_error()
+            .into();
+        }
+    };
+
+    let schema_impl = quote! {
+        impl #impl_generics ::easy_ipc::schema::Schema for #name #ty_generics #where_clause {
+            fn shape() -> ::easy_ipc::schema::Shape {
+                #shape
+            }
+        }
+    };
+
+    TokenStream::from(schema_impl)
+}
+
+/// Generates the `easy_ipc::schema::Fields` expression for a struct or variant
+fn fields_shape(fields: &syn::Fields) -> proc_macro2::TokenStream {
+    match fields {
+        syn::Fields::Unit => quote! { ::easy_ipc::schema::Fields::Unit },
+        syn::Fields::Unnamed(fields) => {
+            let types = fields.unnamed.iter().map(|field| &field.ty);
+            quote! {
+                ::easy_ipc::schema::Fields::Unnamed(::std::vec![
+                    #(<#types as ::easy_ipc::schema::Schema>::shape()),*
+                ])
+            }
+        }
+        syn::Fields::Named(fields) => {
+            let names = fields.named.iter().map(|field| &field.ident);
+            let types = fields.named.iter().map(|field| &field.ty);
+            quote! {
+                ::easy_ipc::schema::Fields::Named(::std::vec![
+                    #((
+                        ::std::stringify!(#names),
+                        <#types as ::easy_ipc::schema::Schema>::shape(),
+                    )),*
+                ])
+            }
+        }
+    }
+}
+
 /// Parses the attributes from the appropriate information, returns an error if parsing fails
 fn parse_message_type(input: &DeriveInput) -> Result<MessageAttributes, syn::Error> {
+    let mut found: Option<(&syn::Attribute, AttributeKeys)> = None;
     for attr in &input.attrs {
         let segments = &attr.path().segments;
         // Should only be one segment, and name should match namespace
-        if segments.len() == 1 && segments[0].ident == CRATE_NAME {
-            let attr: MessageAttributes = attr.parse_args().map_err(|mut e| {
-                e.combine(syn::Error::new(e.span(), DeriveError::GenericError));
-                e
-            })?;
-            return Ok(attr);
+        if segments.len() != 1 || segments[0].ident != CRATE_NAME {
+            continue;
+        }
+        if found.is_some() {
+            return Err(syn::Error::new_spanned(
+                attr,
+                DeriveError::DuplicateAttribute,
+            ));
+        }
+        let syn::Meta::List(list) = &attr.meta else {
+            return Err(syn::Error::new_spanned(attr, DeriveError::GenericError));
+        };
+        found = Some((attr, list.parse_args()?));
+    }
+
+    let Some((attr, keys)) = found else {
+        // Didn't find required attributes
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            DeriveError::GenericError,
+        ));
+    };
+
+    // Report every missing key at once instead of making the user fix them one at a time.
+    match keys {
+        AttributeKeys {
+            server_message: Some(server_message),
+            client_message: Some(client_message),
+        } => Ok(MessageAttributes {
+            server_message,
+            client_message,
+        }),
+        AttributeKeys {
+            server_message,
+            client_message,
+        } => {
+            let mut error = None;
+            if client_message.is_none() {
+                push_error(
+                    &mut error,
+                    syn::Error::new_spanned(attr, DeriveError::MissingClientMessage),
+                );
+            }
+            if server_message.is_none() {
+                push_error(
+                    &mut error,
+                    syn::Error::new_spanned(attr, DeriveError::MissingServerMessage),
+                );
+            }
+            Err(error.unwrap_or_else(|| syn::Error::new_spanned(attr, DeriveError::GenericError)))
         }
     }
+}
 
-    // Didn't find required attributes
-    Err(syn::Error::new_spanned(input, DeriveError::GenericError))
+/// Adds an error to a possibly empty set of errors so they can all be reported together
+fn push_error(errors: &mut Option<syn::Error>, error: syn::Error) {
+    match errors {
+        Some(errors) => errors.combine(error),
+        None => *errors = Some(error),
+    }
 }
 
 /// Errors that can happen
 enum DeriveError {
     MissingServerMessage,
     MissingClientMessage,
+    /// The key was given more than once
+    DuplicateKey(String),
+    /// The key is not one we know about, with the closest valid key if there is one
+    UnknownKey(String, Option<&'static str>),
+    /// More than one `#[easy_ipc(...)]` attribute
+    DuplicateAttribute,
     GenericError,
 }
 
@@ -93,15 +239,30 @@ impl Display for DeriveError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             DeriveError::MissingServerMessage => {
-                write!(f, "missing {}", DeriveError::server_usage())
+                write!(f, "missing `{}`", DeriveError::server_usage())
             }
             DeriveError::MissingClientMessage => {
-                write!(f, "missing {}", DeriveError::client_usage())
+                write!(f, "missing `{}`", DeriveError::client_usage())
+            }
+            DeriveError::DuplicateKey(key) => {
+                write!(f, "`{key}` is specified more than once")
+            }
+            DeriveError::UnknownKey(key, Some(suggestion)) => {
+                write!(f, "unknown key `{key}`, did you mean `{suggestion}`?")
+            }
+            DeriveError::UnknownKey(key, None) => {
+                write!(
+                    f,
+                    "unknown key `{key}`, expected `{CLIENT_MESSAGE}` or `{SERVER_MESSAGE}`"
+                )
+            }
+            DeriveError::DuplicateAttribute => {
+                write!(f, "`#[{CRATE_NAME}(...)]` can only be used once")
             }
             DeriveError::GenericError => {
                 write!(
                     f,
-                    "invalid or missing attributes for `#[derive(Model)]` from {CRATE_NAME}\nusage: {}",
+                    "invalid or missing attributes for `#[derive(IpcModel)]` from {CRATE_NAME}\nusage: {}",
                     DeriveError::default_useage()
                 )
             }
@@ -109,53 +270,102 @@ impl Display for DeriveError {
     }
 }
 
+/// Finds the valid key closest to what the user typed, if any is close enough to be a typo.
+fn suggest_key(key: &str) -> Option<&'static str> {
+    [CLIENT_MESSAGE, SERVER_MESSAGE]
+        .into_iter()
+        .map(|valid| (valid, edit_distance(key, valid)))
+        .filter(|(valid, distance)| *distance <= valid.len() / 3)
+        .min_by_key(|(_, distance)| *distance)
+        .map(|(valid, _)| valid)
+}
+
+/// Levenshtein distance between two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// A single `key = Type` pair in the attribute
+struct KeyValue {
+    key: Ident,
+    ty: Type,
+}
+
+impl Parse for KeyValue {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let key: Ident = input.parse().map_err(|e| {
+            syn::Error::new(
+                e.span(),
+                format!("expected `{CLIENT_MESSAGE}` or `{SERVER_MESSAGE}`"),
+            )
+        })?;
+        input.parse::<syn::Token![=]>()?;
+        let ty: Type = input.parse().map_err(|e| {
+            syn::Error::new(e.span(), format!("expected a message type for `{key}`"))
+        })?;
+        Ok(KeyValue { key, ty })
+    }
+}
+
 /// Helper struct to parse the attributes
 struct MessageAttributes {
     server_message: syn::Type,
     client_message: syn::Type,
 }
 
-impl Parse for MessageAttributes {
-    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
-        // Parse "first_ident = first_type"
-        let first_ident: Ident = input.parse()?;
-        input.parse::<syn::Token![=]>()?;
-        let first_type: Type = input.parse()?;
+/// The keys found in the attribute, which may not all be present
+struct AttributeKeys {
+    server_message: Option<syn::Type>,
+    client_message: Option<syn::Type>,
+}
 
-        // Comma separated
-        input.parse::<syn::Token![,]>()?;
+impl Parse for AttributeKeys {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        // Comma separated "key = Type" pairs, trailing comma allowed
+        let pairs = Punctuated::<KeyValue, syn::Token![,]>::parse_terminated(input)?;
 
-        // Parse "second_ident = second_type"
-        let second_ident: Ident = input.parse()?;
-        input.parse::<syn::Token![=]>()?;
-        let second_type: Type = input.parse()?;
-
-        // Split them into server/client messages depending on the identifiers
-        let (server_message, client_message) = match (
-            first_ident.to_string().as_str(),
-            second_ident.to_string().as_str(),
-        ) {
-            (CLIENT_MESSAGE, SERVER_MESSAGE) => (second_type, first_type),
-            (SERVER_MESSAGE, CLIENT_MESSAGE) => (first_type, second_type),
-            _ => {
-                let has_server_message =
-                    first_ident == SERVER_MESSAGE || second_ident == SERVER_MESSAGE;
-                let has_client_message =
-                    first_ident == CLIENT_MESSAGE || second_ident == CLIENT_MESSAGE;
-
-                if !has_server_message {
-                    return Err(input.error(DeriveError::MissingServerMessage));
-                }
-                if !has_client_message {
-                    return Err(input.error(DeriveError::MissingClientMessage));
+        let mut keys = AttributeKeys {
+            server_message: None,
+            client_message: None,
+        };
+        let mut error = None;
+        for KeyValue { key, ty } in pairs {
+            let name = key.to_string();
+            let slot = match name.as_str() {
+                SERVER_MESSAGE => &mut keys.server_message,
+                CLIENT_MESSAGE => &mut keys.client_message,
+                _ => {
+                    let suggestion = suggest_key(&name);
+                    push_error(
+                        &mut error,
+                        syn::Error::new_spanned(&key, DeriveError::UnknownKey(name, suggestion)),
+                    );
+                    continue;
                 }
-                unreachable!();
+            };
+            if slot.is_some() {
+                push_error(
+                    &mut error,
+                    syn::Error::new_spanned(&key, DeriveError::DuplicateKey(name)),
+                );
+                continue;
             }
-        };
+            *slot = Some(ty);
+        }
 
-        Ok(MessageAttributes {
-            server_message,
-            client_message,
-        })
+        match error {
+            Some(error) => Err(error),
+            None => Ok(keys),
+        }
     }
 }
@@ -0,0 +1,6 @@
+// Makes sure the derive gives helpful diagnostics for malformed attributes
+#[test]
+fn compile_fail() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/*.rs");
+}
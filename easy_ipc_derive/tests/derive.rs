@@ -1,4 +1,5 @@
-use easy_ipc_derive::IpcModel;
+use easy_ipc::model::IpcModel;
+use easy_ipc_derive::{IpcModel, Schema};
 use serde::{Deserialize, Serialize};
 
 // Enum model
@@ -31,3 +32,67 @@ struct ServerStructMessage {
 struct ClientStructMessage {
     send: u32,
 }
+
+// Trailing comma and reversed key order
+#[derive(IpcModel)]
+#[easy_ipc(client_message = ClientEnumMessage, server_message = ServerEnumMessage,)]
+struct MyTrailingCommaModel;
+
+// Schema model
+#[derive(Serialize, Deserialize, Schema)]
+enum SchemaMessage {
+    Unit,
+    Tuple(u8, Option<String>),
+    Named { values: Vec<u32> },
+}
+
+#[derive(Serialize, Deserialize, Schema)]
+struct GenericMessage<T> {
+    inner: T,
+}
+
+#[derive(IpcModel)]
+#[easy_ipc(client_message = SchemaMessage, server_message = GenericMessage<bool>)]
+struct MySchemaModel;
+
+#[test]
+fn schema_json() {
+    let model = MySchemaModel::model().unwrap();
+    let expected = r#"{
+  "client": {
+    "enum": "SchemaMessage",
+    "variants": [
+      {
+        "name": "Unit",
+        "fields": null
+      },
+      {
+        "name": "Tuple",
+        "fields": [
+          "u8",
+          {
+            "option": "String"
+          }
+        ]
+      },
+      {
+        "name": "Named",
+        "fields": {
+          "values": {
+            "seq": "u32"
+          }
+        }
+      }
+    ]
+  },
+  "server": {
+    "struct": "GenericMessage",
+    "fields": {
+      "inner": "bool"
+    }
+  }
+}"#;
+    assert_eq!(model.schema_json(), expected);
+    // The hash must never change for the same layout
+    assert_eq!(model.schema().hash(), 0xc38b_b13a_7d65_50f8);
+}
@@ -0,0 +1,19 @@
+use easy_ipc_derive::IpcModel;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+enum ServerMessage {
+    Ok,
+}
+
+#[derive(Serialize, Deserialize)]
+enum ClientMessage {
+    Go,
+}
+
+#[derive(IpcModel)]
+#[easy_ipc(client_message = ClientMessage, server_message = ServerMessage)]
+#[easy_ipc(client_message = ClientMessage, server_message = ServerMessage)]
+struct MyModel;
+
+fn main() {}
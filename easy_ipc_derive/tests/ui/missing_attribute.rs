@@ -0,0 +1,17 @@
+use easy_ipc_derive::IpcModel;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+enum ServerMessage {
+    Ok,
+}
+
+#[derive(Serialize, Deserialize)]
+enum ClientMessage {
+    Go,
+}
+
+#[derive(IpcModel)]
+struct MyModel;
+
+fn main() {}
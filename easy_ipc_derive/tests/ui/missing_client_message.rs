@@ -0,0 +1,18 @@
+use easy_ipc_derive::IpcModel;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+enum ServerMessage {
+    Ok,
+}
+
+#[derive(Serialize, Deserialize)]
+enum ClientMessage {
+    Go,
+}
+
+#[derive(IpcModel)]
+#[easy_ipc(server_message = ServerMessage)]
+struct MyModel;
+
+fn main() {}
@@ -41,6 +41,8 @@ fn main() {
     let msg: ClientMessage = args.op.into();
     // Make our client
     let mut client = MyModel::client().unwrap();
+    // Tell the server to stop working on our request if the user presses Ctrl-C
+    client.cancel_on_interrupt();
     // Send the message to the server
     client.send(msg.clone()).unwrap();
     // Get the response and print it out